@@ -0,0 +1,260 @@
+use crate::*;
+use std::io::{BufRead, BufReader};
+use std::net::TcpListener;
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+
+// gives clients a moment to receive the match ending before the server goes away
+const END_MATCH_DELAY: f32 = 1.0;
+const MIN_SPEED: f32 = 0.1;
+// any faster and the server can't keep up with the ticks
+const MAX_SPEED: f32 = 8.0;
+
+const ADMIN_USAGE: &str =
+    "commands: players, kick <connection>, pause, resume, speed <multiplier>, \
+     spawn <ron>, save <file>, end";
+
+/// A command typed into the server's console.
+#[derive(Debug)]
+pub enum AdminCommand {
+    Players,
+    Kick(ConnectionHandle),
+    Pause,
+    Resume,
+    Speed(f32),
+    /// A spawnable written out in RON, like in a map file.
+    Spawn(String),
+    Save(String),
+    End,
+}
+
+impl FromStr for AdminCommand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut split = s.trim().splitn(2, char::is_whitespace);
+        let command = split.next().unwrap_or("");
+        let argument = split
+            .next()
+            .map(str::trim)
+            .filter(|argument| !argument.is_empty());
+
+        match (command, argument) {
+            ("players", None) => Ok(AdminCommand::Players),
+            ("kick", Some(handle)) => handle
+                .parse()
+                .map(AdminCommand::Kick)
+                .map_err(|_| format!("'{}' is not a connection", handle)),
+            ("kick", None) => Err("usage: kick <connection>".to_string()),
+            ("pause", None) => Ok(AdminCommand::Pause),
+            ("resume", None) => Ok(AdminCommand::Resume),
+            ("speed", Some(speed)) => match speed.parse::<f32>() {
+                Ok(speed) if speed.is_finite() && speed > 0.0 => {
+                    Ok(AdminCommand::Speed(speed.max(MIN_SPEED).min(MAX_SPEED)))
+                }
+                _ => Err(format!("'{}' is not a valid speed", speed)),
+            },
+            ("speed", None) => Err("usage: speed <multiplier>".to_string()),
+            ("spawn", Some(ron)) => Ok(AdminCommand::Spawn(ron.to_string())),
+            ("spawn", None) => Err("usage: spawn <ron>".to_string()),
+            ("save", Some(path)) => Ok(AdminCommand::Save(path.to_string())),
+            ("save", None) => Err("usage: save <file>".to_string()),
+            ("end", None) => Ok(AdminCommand::End),
+            ("help", _) => Err(ADMIN_USAGE.to_string()),
+            ("", _) => Err("no command given".to_string()),
+            (command, _) => Err(format!("unknown command '{}', {}", command, ADMIN_USAGE)),
+        }
+    }
+}
+
+/// The lines read from stdin and the admin socket, on threads of their own so the server doesn't block.
+pub struct AdminConsole {
+    lines: Mutex<Receiver<String>>,
+}
+
+impl AdminConsole {
+    /// Reads stdin, and local TCP connections on the port if one is given.
+    pub fn new(port: Option<u16>) -> Self {
+        let (sender, receiver) = mpsc::channel();
+
+        let stdin_sender = sender.clone();
+        std::thread::spawn(move || read_lines(std::io::stdin().lock(), stdin_sender));
+
+        if let Some(port) = port {
+            // only local, anyone reaching it can run the server
+            match TcpListener::bind(("127.0.0.1", port)) {
+                Ok(listener) => {
+                    info!(
+                        "Admin console listening at: {}",
+                        listener.local_addr().unwrap()
+                    );
+                    std::thread::spawn(move || accept_connections(listener, sender));
+                }
+                Err(err) => error!("Failed to open the admin console on port {}: {}", port, err),
+            }
+        }
+
+        Self {
+            lines: Mutex::new(receiver),
+        }
+    }
+}
+
+fn accept_connections(listener: TcpListener, sender: Sender<String>) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                info!("Admin connected from {:?}", stream.peer_addr());
+
+                let sender = sender.clone();
+                std::thread::spawn(move || read_lines(BufReader::new(stream), sender));
+            }
+            Err(err) => warn!("Failed to accept admin connection: {}", err),
+        }
+    }
+}
+
+fn read_lines(reader: impl BufRead, sender: Sender<String>) {
+    for line in reader.lines() {
+        match line {
+            Ok(line) => {
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+            Err(_) => break,
+        }
+    }
+}
+
+fn log_players(players: &Players, lobby: &Lobby, disconnects: &Disconnects) {
+    let mut connected: Vec<(&ConnectionHandle, &PlayerId)> = players.player_ids.iter().collect();
+    connected.sort_by_key(|(_, player_id)| **player_id);
+
+    info!(
+        "{} players, {} spectators",
+        connected.len(),
+        players.spectators.len()
+    );
+
+    for (handle, player_id) in connected {
+        let name = lobby
+            .get(*player_id)
+            .map_or("", |player| player.name.as_str());
+
+        info!("connection {}: {:?} '{}'", handle, player_id, name);
+    }
+
+    for handle in &players.spectators {
+        info!("connection {}: spectator", handle);
+    }
+
+    let mut player_ids: Vec<&PlayerId> = lobby.player_ids().collect();
+    player_ids.sort();
+
+    for player_id in player_ids {
+        if disconnects.is_ai(*player_id) {
+            info!("{:?}: abandoned, played by the ai", player_id);
+        } else if disconnects.is_reserved(*player_id) {
+            info!("{:?}: disconnected, waiting for them to return", player_id);
+        }
+    }
+}
+
+fn admin_console_system(
+    mut end_timer: Local<Option<Timer>>,
+    time: Res<Time>,
+    console: Res<AdminConsole>,
+    mut net: ResMut<Network>,
+    mut disconnects: ResMut<Disconnects>,
+    mut fixed_tick: ResMut<FixedTick>,
+    mut save_events: ResMut<Events<SaveMatchEvent>>,
+    mut app_exit_events: ResMut<Events<bevy::app::AppExit>>,
+    players: Res<Players>,
+    lobby: Res<Lobby>,
+    spawn_resource: Res<SpawnResource>,
+) {
+    if let Some(timer) = &mut *end_timer {
+        timer.tick(time.delta_seconds());
+
+        if timer.just_finished() {
+            app_exit_events.send(bevy::app::AppExit);
+        }
+
+        return;
+    }
+
+    let lines: Vec<String> = console.lines.lock().unwrap().try_iter().collect();
+
+    for line in lines {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let command = match line.parse::<AdminCommand>() {
+            Ok(command) => command,
+            Err(err) => {
+                warn!("{}", err);
+                continue;
+            }
+        };
+
+        match command {
+            AdminCommand::Players => log_players(&players, &lobby, &disconnects),
+            AdminCommand::Kick(handle) => {
+                if !net.connections().any(|connection| *connection == handle) {
+                    warn!("There is no connection {}", handle);
+                    continue;
+                }
+
+                info!("kicking connection {}", handle);
+
+                // no grace period, the slot is given up
+                disconnects.kick(handle);
+                net.send_message(
+                    handle,
+                    ConnectionMessage::Rejected("kicked by the server".to_string()),
+                );
+                net.disconnect(handle);
+            }
+            AdminCommand::Pause => {
+                info!("pausing the match");
+                disconnects.set_admin_paused(true);
+            }
+            AdminCommand::Resume => {
+                info!("resuming the match");
+                disconnects.set_admin_paused(false);
+            }
+            AdminCommand::Speed(speed) => {
+                info!("game speed set to {}", speed);
+                fixed_tick.speed = speed;
+            }
+            AdminCommand::Spawn(ron) => match ron::de::from_str::<Box<dyn Spawnable>>(&ron) {
+                Ok(spawnable) => {
+                    info!("spawning {:?}", spawnable);
+                    spawn_resource.spawn(Spawner::new(spawnable));
+                }
+                Err(err) => warn!("Failed to parse spawnable: {}", err),
+            },
+            AdminCommand::Save(path) => save_events.send(SaveMatchEvent(path)),
+            AdminCommand::End => {
+                info!("ending the match");
+
+                net.broadcast_message(MatchEventMessage::Ended);
+                *end_timer = Some(Timer::from_seconds(END_MATCH_DELAY, false));
+                break;
+            }
+        }
+    }
+}
+
+/// Reads admin commands from the server's stdin, and from a local socket if given a port.
+pub struct AdminPlugin(pub Option<u16>);
+
+impl Plugin for AdminPlugin {
+    fn build(&self, app_builder: &mut AppBuilder) {
+        app_builder.add_resource(AdminConsole::new(self.0));
+        app_builder.add_system(admin_console_system.system());
+    }
+}
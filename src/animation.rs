@@ -60,6 +60,8 @@ pub enum AnimatorOperation {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct AnimatorMessage {
+    /// The tick the server had simulated up to.
+    tick: u64,
     operation: AnimatorOperation,
     network_entity: NetworkEntity,
 }
@@ -76,6 +78,9 @@ pub struct Animator {
     current_frame_changed: bool,
     #[reflect(ignore)]
     operations: Vec<AnimatorOperation>,
+    // the tick of the latest replicated operation, as they can arrive out of order
+    #[reflect(ignore)]
+    synced_tick: u64,
 }
 
 impl Animator {
@@ -88,6 +93,7 @@ impl Animator {
             current_frame_set: false,
             current_frame_changed: true,
             operations: Vec::new(),
+            synced_tick: 0,
         }
     }
 
@@ -147,12 +153,8 @@ impl Animator {
     }
 }
 
-pub fn animator_system(
-    time: Res<Time>,
-    animation_sets: Res<Assets<AnimationSet>>,
-    mut query: Query<&mut Animator>,
-) {
-    for mut animator in query.iter_mut() {
+impl Animator {
+    fn advance(&mut self, animation_sets: &Assets<AnimationSet>, delta_seconds: f32) {
         let Animator {
             animation_set,
             playing_animation,
@@ -161,7 +163,7 @@ pub fn animator_system(
             current_frame_set,
             current_frame_changed,
             ..
-        } = &mut *animator;
+        } = self;
 
         if let Some(animation_set) = animation_sets.get(animation_set.clone()) {
             if let Some(animation) = animation_set.get(&*playing_animation) {
@@ -173,7 +175,7 @@ pub fn animator_system(
                     *play_time = *current_frame as f32 * animation.frame_length;
                 }
 
-                *play_time += time.delta_seconds();
+                *play_time += delta_seconds;
 
                 let new_frame = (*play_time / animation.frame_length).floor() as u32
                     % (animation.end - animation.start + 1);
@@ -187,6 +189,38 @@ pub fn animator_system(
     }
 }
 
+pub fn animator_system(
+    time: Res<Time>,
+    simulation_mode: Res<SimulationMode>,
+    animation_sets: Res<Assets<AnimationSet>>,
+    mut query: Query<&mut Animator>,
+) {
+    // in lockstep the animators are part of the simulation
+    if simulation_mode.is_lockstep() {
+        return;
+    }
+
+    for mut animator in query.iter_mut() {
+        animator.advance(&animation_sets, time.delta_seconds());
+    }
+}
+
+pub fn simulation_animator_system(
+    simulation_time: Res<SimulationTime>,
+    network_settings: Res<NetworkSettings>,
+    animation_sets: Res<Assets<AnimationSet>>,
+    mut query: Query<&mut Animator>,
+) {
+    for mut animator in query.iter_mut() {
+        animator.advance(&animation_sets, simulation_time.delta_seconds());
+
+        if network_settings.is_client() {
+            // nobody replicates these in lockstep
+            animator.operations.clear();
+        }
+    }
+}
+
 pub fn animator_sprite_system(
     animation_sets: Res<Assets<AnimationSet>>,
     textures: Res<Assets<Texture>>,
@@ -223,43 +257,52 @@ pub fn animator_sprite_system(
 }
 
 pub fn server_network_animator_system(
-    mut net: ResMut<NetworkResource>,
+    simulation_mode: Res<SimulationMode>,
+    fixed_tick: Res<FixedTick>,
+    vision: Res<Vision>,
+    mut net: ResMut<Network>,
     mut query: Query<(&NetworkEntity, &mut Animator)>,
 ) {
     for (network_entity, mut animator) in query.iter_mut() {
         for operation in animator.operations.drain(..) {
+            if simulation_mode.is_lockstep() {
+                continue;
+            }
+
             let message = AnimatorMessage {
+                tick: fixed_tick.tick(),
                 operation,
                 network_entity: network_entity.clone(),
             };
 
-            net.broadcast_message(message);
+            vision.send_message(&mut net, *network_entity, message);
         }
     }
 }
 
 pub fn client_network_animator_system(
-    mut net: ResMut<NetworkResource>,
+    mut net: ResMut<Network>,
     network_entity_registry: Res<NetworkEntityRegistry>,
     mut query: Query<&mut Animator>,
 ) {
-    for (handle, connection) in net.connections.iter_mut() {
-        let channels = connection.channels().unwrap();
-
-        while let Some(animator_message) = channels.recv::<AnimatorMessage>() {
-            let entity =
-                if let Some(e) = network_entity_registry.get(&animator_message.network_entity) {
-                    e
-                } else {
-                    warn!(
-                        "Recieved animation message for unregistered entity {}",
-                        handle
-                    );
-                    continue;
-                };
-
-            let mut animator = query.get_mut(*entity).unwrap();
+    for (handle, animator_message) in net.recv::<AnimatorMessage>() {
+        let entity = if let Some(e) = network_entity_registry.get(&animator_message.network_entity)
+        {
+            e
+        } else {
+            warn!(
+                "Recieved animation message for unregistered entity {}",
+                handle
+            );
+            continue;
+        };
+
+        if let Ok(mut animator) = query.get_mut(*entity) {
+            if animator_message.tick < animator.synced_tick {
+                continue;
+            }
 
+            animator.synced_tick = animator_message.tick;
             animator.apply(animator_message.operation);
         }
     }
@@ -282,11 +325,12 @@ impl Plugin for AnimationPlugin {
         app_builder.add_asset_loader(AnimationSetLoader);
         app_builder.add_asset::<AnimationSet>();
         app_builder.register_type::<Animator>();
-        app_builder.add_system(animator_system.system());
+        app_builder.add_system_to_stage(SIMULATION_STAGE, simulation_animator_system.system());
 
         if self.0 {
             app_builder.add_system(server_network_animator_system.system());
         } else {
+            app_builder.add_system(animator_system.system());
             app_builder.add_system(client_network_animator_system.system());
             app_builder.add_system(animator_sprite_system.system());
         }
@@ -5,20 +5,38 @@ use std::collections::HashMap;
 pub enum Behaviour {
     Move {
         target: Vec2,
+        /// The destination of the flow field to follow, instead of a path of its own.
+        #[serde(default)]
+        flow_field: Option<Vec2>,
     },
     Attack {
         target_position: Vec2,
         target: Entity,
         damage: HashMap<u32, f32>,
     },
+    Harvest {
+        target_position: Vec2,
+        target: Entity,
+    },
+    /// Unloads all cargo into the owner's stockpile.
+    DropOff,
+    Construct {
+        target_position: Vec2,
+        target: Entity,
+    },
     Idle,
 }
 
 pub fn unit_command_behaviour_system(
-    time: Res<Time>,
+    simulation_time: Res<SimulationTime>,
     units: Res<Assets<Unit>>,
+    buildings: Res<Assets<Building>>,
     network_entity_registry: Res<NetworkEntityRegistry>,
+    mut match_stats: ResMut<MatchStats>,
+    mut stockpiles: ResMut<Stockpiles>,
     mut unit_instance_query: Query<&mut UnitInstance>,
+    mut resource_node_query: Query<&mut ResourceNode>,
+    mut construction_query: Query<(&Handle<Building>, &mut Construction)>,
     mut query: Query<(
         &Behaviour,
         &mut Position,
@@ -26,6 +44,10 @@ pub fn unit_command_behaviour_system(
         &mut UnitAnimator,
         &Handle<Unit>,
         &mut UnitDirection,
+        Option<&Owner>,
+        Option<&mut Cargo>,
+        Option<&UnitStats>,
+        Option<&mut Path>,
     )>,
 ) {
     for (
@@ -35,10 +57,14 @@ pub fn unit_command_behaviour_system(
         mut unit_animator,
         unit_handle,
         mut direction,
+        owner,
+        cargo,
+        unit_stats,
+        path,
     ) in query.iter_mut()
     {
         match behaviour {
-            Behaviour::Move { target } => {
+            Behaviour::Move { target, .. } => {
                 let unit = units.get(&*unit_handle).unwrap();
                 let movement_speed = match &unit.movement_speed {
                     MovementSpeed::Constant(s) => *s,
@@ -46,8 +72,16 @@ pub fn unit_command_behaviour_system(
                         frame_mods[animator.current_frame() as usize] * *speed
                     }
                 };
+                let movement_speed = unit_stats.map_or(movement_speed, |stats| {
+                    stats.movement_speed.apply(movement_speed)
+                });
 
-                let diff = *target - position.position.truncate();
+                // straight to the target once the path runs out
+                let waypoint = path
+                    .and_then(|mut path| path.next(position.position.truncate()))
+                    .unwrap_or(*target);
+
+                let diff = waypoint - position.position.truncate();
                 let dist = diff.length();
 
                 if dist == 0.0 {
@@ -56,7 +90,7 @@ pub fn unit_command_behaviour_system(
 
                 unit_animator.set_playing("walk");
 
-                let move_dist = dist.min(movement_speed * time.delta_seconds());
+                let move_dist = dist.min(movement_speed * simulation_time.delta_seconds());
 
                 let step = diff.normalize();
 
@@ -83,8 +117,86 @@ pub fn unit_command_behaviour_system(
 
                 if animator.frame_just_changed() {
                     if let Some(damage) = damage.get(&animator.current_frame()) {
+                        let damage =
+                            unit_stats.map_or(*damage, |stats| stats.attack_damage.apply(*damage));
+
                         if let Ok(mut unit_instance) = unit_instance_query.get_mut(*target) {
-                            unit_instance.subtract_health(*damage);
+                            unit_instance.subtract_health(damage);
+
+                            if let Some(owner) = owner {
+                                match_stats.record_damage(owner.0, damage);
+                            }
+                        }
+                    }
+                }
+            }
+            Behaviour::Harvest {
+                target_position,
+                target,
+            } => {
+                let diff = *target_position - position.position.truncate();
+
+                *direction = UnitDirection::from_vec2(diff);
+
+                // there is no gathering animation yet
+                if unit_animator.playing().as_str() != "attack" {
+                    unit_animator.play("attack");
+                }
+
+                let harvester = units
+                    .get(&*unit_handle)
+                    .and_then(|unit| unit.harvester.as_ref());
+
+                if let (Some(harvester), Some(mut cargo), Ok(mut resource_node)) =
+                    (harvester, cargo, resource_node_query.get_mut(*target))
+                {
+                    let amount = (harvester.rate * simulation_time.delta_seconds())
+                        .min(harvester.capacity - cargo.amount)
+                        .min(resource_node.amount)
+                        .max(0.0);
+
+                    resource_node.amount -= amount;
+                    cargo.amount += amount;
+                }
+            }
+            Behaviour::DropOff => {
+                if let (Some(owner), Some(mut cargo)) = (owner, cargo) {
+                    if cargo.amount > 0.0 {
+                        stockpiles.add(owner.0, cargo.amount);
+                        cargo.amount = 0.0;
+                    }
+                }
+
+                if unit_animator.playing().as_str() != "idle" {
+                    unit_animator.play("idle");
+                }
+            }
+            Behaviour::Construct {
+                target_position,
+                target,
+            } => {
+                let diff = *target_position - position.position.truncate();
+
+                *direction = UnitDirection::from_vec2(diff);
+
+                // there is no building animation yet
+                if unit_animator.playing().as_str() != "attack" {
+                    unit_animator.play("attack");
+                }
+
+                if let (Some(owner), Ok((building_handle, mut construction))) =
+                    (owner, construction_query.get_mut(*target))
+                {
+                    if let Some(building) = buildings.get(building_handle) {
+                        if !construction.paid && stockpiles.get(owner.0) >= building.cost {
+                            stockpiles.spend(owner.0, building.cost);
+                            construction.paid = true;
+                        }
+
+                        if construction.paid {
+                            construction.progress = (construction.progress
+                                + simulation_time.delta_seconds())
+                            .min(building.construction_time);
                         }
                     }
                 }
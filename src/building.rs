@@ -0,0 +1,685 @@
+use crate::*;
+use bevy::reflect::TypeUuid;
+use std::collections::VecDeque;
+
+const MAX_PRODUCTION_QUEUE: usize = 5;
+
+/// A unit a building can train.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Production {
+    pub name: String,
+    pub unit: String,
+    pub animation_set: String,
+    pub unit_animation_set: String,
+    pub cost: f32,
+    /// Seconds it takes to train.
+    pub build_time: f32,
+}
+
+#[derive(TypeUuid, Serialize, Deserialize)]
+#[uuid = "579fde7a-d85b-48e2-89d3-d3827d01ac34"]
+pub struct Building {
+    pub size: f32,
+    pub height: f32,
+    pub width: f32,
+    pub texture: String,
+    /// Where trained units appear, relative to the building.
+    pub exit: Vec2,
+    /// Whether harvesters can unload here.
+    #[serde(default)]
+    pub drop_off: bool,
+    #[serde(default)]
+    pub productions: Vec<Production>,
+    /// The tiles it covers, relative to the tile it's placed on.
+    #[serde(default = "default_footprint")]
+    pub footprint: Vec<TilePosition>,
+    /// What a worker has to pay to build it.
+    #[serde(default)]
+    pub cost: f32,
+    /// Seconds it takes to build.
+    #[serde(default)]
+    pub construction_time: f32,
+    /// The upgrades it can research.
+    #[serde(default)]
+    pub upgrades: Vec<String>,
+}
+
+fn default_footprint() -> Vec<TilePosition> {
+    vec![TilePosition { x: 0, y: 0 }]
+}
+
+impl Building {
+    /// The tiles it covers when placed on a tile.
+    pub fn tiles(&self, origin: &TilePosition) -> Vec<TilePosition> {
+        self.footprint
+            .iter()
+            .map(|offset| TilePosition {
+                x: origin.x + offset.x,
+                y: origin.y + offset.y,
+            })
+            .collect()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ProductionOperation {
+    /// Trains the production at the index, paying for it up front.
+    Train(usize),
+    /// Removes the last queued production and refunds it.
+    Cancel,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProductionMessage {
+    pub operation: ProductionOperation,
+    pub building: NetworkEntity,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProductionQueueMessage {
+    pub building: NetworkEntity,
+    pub production_queue: ProductionQueue,
+}
+
+/// What a building is training, the front of the queue first.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ProductionQueue {
+    pub queue: VecDeque<usize>,
+    /// Seconds spent training the front of the queue.
+    pub progress: f32,
+}
+
+impl ProductionQueue {
+    pub fn validate(
+        &self,
+        operation: &ProductionOperation,
+        owner: PlayerId,
+        building: &Building,
+        stockpiles: &Stockpiles,
+    ) -> Result<(), Rejection> {
+        match operation {
+            ProductionOperation::Train(index) => {
+                let production = building
+                    .productions
+                    .get(*index)
+                    .ok_or(Rejection::InvalidProduction(*index))?;
+
+                if self.queue.len() >= MAX_PRODUCTION_QUEUE {
+                    Err(Rejection::ProductionQueueFull)
+                } else if stockpiles.get(owner) < production.cost {
+                    Err(Rejection::NotEnoughResources)
+                } else {
+                    Ok(())
+                }
+            }
+            ProductionOperation::Cancel => Ok(()),
+        }
+    }
+
+    /// Checked again when applied, as the stockpile might have been spent in the meantime.
+    pub fn apply(
+        &mut self,
+        operation: &ProductionOperation,
+        owner: PlayerId,
+        building: &Building,
+        stockpiles: &mut Stockpiles,
+    ) {
+        if self
+            .validate(operation, owner, building, stockpiles)
+            .is_err()
+        {
+            return;
+        }
+
+        match operation {
+            ProductionOperation::Train(index) => {
+                if let Some(production) = building.productions.get(*index) {
+                    stockpiles.spend(owner, production.cost);
+                    self.queue.push_back(*index);
+                }
+            }
+            ProductionOperation::Cancel => {
+                if let Some(index) = self.queue.pop_back() {
+                    // nothing to refund if the building no longer has the production
+                    if let Some(production) = building.productions.get(index) {
+                        stockpiles.add(owner, production.cost);
+                    }
+
+                    if self.queue.is_empty() {
+                        self.progress = 0.0;
+                    }
+                }
+            }
+        }
+    }
+
+    /// How far along the front of the queue is, from 0 to 1.
+    pub fn fraction(&self, building: &Building) -> f32 {
+        self.queue
+            .front()
+            .and_then(|index| building.productions.get(*index))
+            .map_or(0.0, |production| {
+                (self.progress / production.build_time).min(1.0)
+            })
+    }
+}
+
+/// Applies a production message to the building it's for, if it still exists.
+pub fn apply_production_message(
+    production_message: &ProductionMessage,
+    network_entity_registry: &NetworkEntityRegistry,
+    buildings: &Assets<Building>,
+    stockpiles: &mut Stockpiles,
+    query: &mut Query<(&Owner, &Handle<Building>, &mut ProductionQueue)>,
+) {
+    let entity = match network_entity_registry.get(&production_message.building) {
+        Some(entity) => *entity,
+        None => return,
+    };
+
+    if let Ok((owner, building_handle, mut production_queue)) = query.get_mut(entity) {
+        if let Some(building) = buildings.get(building_handle) {
+            production_queue.apply(&production_message.operation, owner.0, building, stockpiles);
+        }
+    }
+}
+
+fn server_production_message_system(
+    simulation_mode: Res<SimulationMode>,
+    fixed_tick: Res<FixedTick>,
+    players: Res<Players>,
+    buildings: Res<Assets<Building>>,
+    network_entity_registry: Res<NetworkEntityRegistry>,
+    mut net: ResMut<Network>,
+    mut rejections: ResMut<Rejections>,
+    mut stockpiles: ResMut<Stockpiles>,
+    mut lockstep: ResMut<Lockstep>,
+    mut replay_recorder: ResMut<ReplayRecorder>,
+    constructions: Query<&Construction>,
+    mut query: Query<(&Owner, &Handle<Building>, &mut ProductionQueue)>,
+) {
+    for (handle, production_message) in net.recv::<ProductionMessage>() {
+        let network_entity = production_message.building;
+
+        let player = match players.player_ids.get(&handle) {
+            Some(player) => *player,
+            None => {
+                rejections.reject(&mut net, handle, Rejection::NotAPlayer);
+                continue;
+            }
+        };
+
+        let entity = match network_entity_registry.get(&network_entity) {
+            Some(entity) => *entity,
+            None => {
+                rejections.reject(&mut net, handle, Rejection::UnknownEntity(network_entity));
+                continue;
+            }
+        };
+
+        let (owner, building_handle, production_queue) = match query.get_mut(entity) {
+            Ok(components) => components,
+            Err(_) => {
+                rejections.reject(&mut net, handle, Rejection::NotCommandable(network_entity));
+                continue;
+            }
+        };
+
+        if owner.0 != player {
+            rejections.reject(&mut net, handle, Rejection::NotOwner(network_entity));
+            continue;
+        }
+
+        if constructions.get(entity).is_ok() {
+            rejections.reject(&mut net, handle, Rejection::NotConstructed(network_entity));
+            continue;
+        }
+
+        let building = match buildings.get(building_handle) {
+            Some(building) => building,
+            None => continue,
+        };
+
+        let operation = &production_message.operation;
+        if let Err(rejection) = production_queue.validate(operation, player, building, &stockpiles)
+        {
+            rejections.reject(&mut net, handle, rejection);
+            continue;
+        }
+
+        if simulation_mode.is_lockstep() {
+            lockstep.queue_production(production_message);
+        } else {
+            apply_production_message(
+                &production_message,
+                &network_entity_registry,
+                &buildings,
+                &mut stockpiles,
+                &mut query,
+            );
+            replay_recorder.record(
+                fixed_tick.tick(),
+                RecordedAction::Production(production_message),
+            );
+        }
+    }
+}
+
+fn production_system(
+    simulation_time: Res<SimulationTime>,
+    network_settings: Res<NetworkSettings>,
+    buildings: Res<Assets<Building>>,
+    spawn_resource: Res<SpawnResource>,
+    mut query: Query<
+        (&Position, &Owner, &Handle<Building>, &mut ProductionQueue),
+        Without<Construction>,
+    >,
+) {
+    for (position, owner, building_handle, mut production_queue) in query.iter_mut() {
+        let building = match buildings.get(building_handle) {
+            Some(building) => building,
+            None => continue,
+        };
+
+        let index = match production_queue.queue.front() {
+            Some(index) => *index,
+            None => continue,
+        };
+
+        let production = match building.productions.get(index) {
+            Some(production) => production,
+            // the building was reloaded without it
+            None => {
+                production_queue.queue.pop_front();
+                production_queue.progress = 0.0;
+                continue;
+            }
+        };
+
+        production_queue.progress += simulation_time.delta_seconds();
+
+        if production_queue.progress < production.build_time {
+            continue;
+        }
+
+        production_queue.queue.pop_front();
+        production_queue.progress = 0.0;
+
+        // the server spawns it for every peer, lockstep ones included
+        if network_settings.is_server() {
+            spawn_resource.spawn(UnitSpawnable {
+                position: position.position + building.exit.extend(0.0),
+                owner: owner.0,
+                unit: production.unit.clone(),
+                animation_set: production.animation_set.clone(),
+                unit_animation_set: production.unit_animation_set.clone(),
+            });
+        }
+    }
+}
+
+fn server_production_sync_system(
+    mut sync: Local<ThrottledSync<ProductionQueue>>,
+    time: Res<Time>,
+    simulation_mode: Res<SimulationMode>,
+    vision: Res<Vision>,
+    mut net: ResMut<Network>,
+    query: Query<(&NetworkEntity, &ProductionQueue)>,
+) {
+    // every peer trains on its own in lockstep
+    if simulation_mode.is_lockstep() {
+        return;
+    }
+
+    sync.tick(&time);
+
+    for (network_entity, production_queue) in query.iter() {
+        let changed = sync.should_send(*network_entity, Some(production_queue), |last, current| {
+            last.queue != current.queue
+        });
+
+        if changed {
+            vision.send_message(
+                &mut net,
+                *network_entity,
+                ProductionQueueMessage {
+                    building: *network_entity,
+                    production_queue: production_queue.clone(),
+                },
+            );
+        }
+    }
+
+    sync.retain(|network_entity| {
+        query
+            .iter()
+            .any(|(other_network_entity, _)| other_network_entity == network_entity)
+    });
+}
+
+fn client_production_queue_system(
+    mut net: ResMut<Network>,
+    network_entity_registry: Res<NetworkEntityRegistry>,
+    mut query: Query<&mut ProductionQueue>,
+) {
+    for (_handle, message) in net.recv::<ProductionQueueMessage>() {
+        let production_queue = network_entity_registry
+            .get(&message.building)
+            .and_then(|entity| query.get_mut(*entity).ok());
+
+        match production_queue {
+            Some(mut production_queue) => *production_queue = message.production_queue,
+            None => warn!(
+                "Recieved production queue for unknown entity {:?}",
+                message.building
+            ),
+        }
+    }
+}
+
+/// The child holding a building's progress bar.
+pub struct ProductionBar(pub usize);
+
+fn production_bar_system(
+    buildings: Res<Assets<Building>>,
+    query: Query<(
+        &Handle<Building>,
+        &ProductionQueue,
+        Option<&Construction>,
+        &ProductionBar,
+        &Children,
+    )>,
+    mut bar_query: Query<(&mut Bar, &mut Visible)>,
+) {
+    for (building_handle, production_queue, construction, production_bar, children) in query.iter()
+    {
+        let building = match buildings.get(building_handle) {
+            Some(building) => building,
+            None => continue,
+        };
+
+        if let Ok((mut bar, mut visible)) = bar_query.get_mut(children[production_bar.0]) {
+            // doubles as the construction progress until it's built
+            match construction {
+                Some(construction) => {
+                    bar.current_value = construction.fraction(building);
+                    visible.is_visible = true;
+                }
+                None => {
+                    bar.current_value = production_queue.fraction(building);
+                    visible.is_visible = !production_queue.queue.is_empty();
+                }
+            }
+        }
+    }
+}
+
+/// The building the player has clicked on, to train units in.
+#[derive(Default)]
+pub struct SelectedBuilding(pub Option<Entity>);
+
+fn client_building_selection_system(
+    mut selected_building: ResMut<SelectedBuilding>,
+    input_config: Res<Assets<InputConfig>>,
+    input_resource: Res<InputResource>,
+    mouse_position: Res<MousePosition>,
+    mouse_input: Res<Input<MouseButton>>,
+    keyboard_input: Res<Input<KeyCode>>,
+    buildings: Res<Assets<Building>>,
+    player_id: Res<Option<PlayerId>>,
+    query: Query<(Entity, &Position, &Owner, &Handle<Building>)>,
+) {
+    let player_id = match *player_id {
+        Some(player_id) => player_id,
+        None => return,
+    };
+
+    let input_config = match input_config.get(&input_resource.0) {
+        Some(input_config) => input_config,
+        None => return,
+    };
+
+    // the building might have been destroyed
+    if let Some(entity) = selected_building.0 {
+        if query.get(entity).is_err() {
+            selected_building.0 = None;
+        }
+    }
+
+    if !input_config
+        .select
+        .just_released(&keyboard_input, &mouse_input)
+    {
+        return;
+    }
+
+    selected_building.0 = query
+        .iter()
+        .filter(|(_, _, owner, _)| owner.0 == player_id)
+        .find(|(_, position, _, building_handle)| {
+            buildings.get(*building_handle).map_or(false, |building| {
+                (position.position.truncate() - mouse_position.position()).length() < building.size
+            })
+        })
+        .map(|(entity, _, _, _)| entity);
+}
+
+fn client_production_input_system(
+    selected_building: Res<SelectedBuilding>,
+    input_config: Res<Assets<InputConfig>>,
+    input_resource: Res<InputResource>,
+    chat_input: Res<ChatInput>,
+    mouse_input: Res<Input<MouseButton>>,
+    keyboard_input: Res<Input<KeyCode>>,
+    mut net: ResMut<Network>,
+    query: Query<&NetworkEntity>,
+) {
+    // typing in the chat shouldn't train anything
+    if chat_input.scope.is_some() {
+        return;
+    }
+
+    let input_config = match input_config.get(&input_resource.0) {
+        Some(input_config) => input_config,
+        None => return,
+    };
+
+    let network_entity = match selected_building
+        .0
+        .and_then(|entity| query.get(entity).ok())
+    {
+        Some(network_entity) => *network_entity,
+        None => return,
+    };
+
+    let mut operations: Vec<ProductionOperation> = input_config
+        .train
+        .iter()
+        .enumerate()
+        .filter(|(_, input)| input.just_pressed(&keyboard_input, &mouse_input))
+        .map(|(index, _)| ProductionOperation::Train(index))
+        .collect();
+
+    if input_config
+        .cancel_training
+        .just_pressed(&keyboard_input, &mouse_input)
+    {
+        operations.push(ProductionOperation::Cancel);
+    }
+
+    for operation in operations {
+        net.broadcast_message(ProductionMessage {
+            operation,
+            building: network_entity,
+        });
+    }
+}
+
+pub struct ProductionText;
+
+fn client_production_setup(commands: &mut Commands, asset_server: Res<AssetServer>) {
+    commands
+        .spawn(TextBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: Rect {
+                    bottom: Val::Px(48.0),
+                    right: Val::Px(16.0),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            text: Text {
+                value: String::new(),
+                font: asset_server.load("fonts/DejaVuSans.ttf"),
+                style: TextStyle {
+                    font_size: 20.0,
+                    color: Color::WHITE,
+                    ..Default::default()
+                },
+            },
+            ..Default::default()
+        })
+        .with(ProductionText);
+}
+
+fn client_production_text_system(
+    selected_building: Res<SelectedBuilding>,
+    input_config: Res<Assets<InputConfig>>,
+    input_resource: Res<InputResource>,
+    buildings: Res<Assets<Building>>,
+    upgrade_assets: Res<Assets<Upgrade>>,
+    upgrades: Res<Upgrades>,
+    player_id: Res<Option<PlayerId>>,
+    query: Query<(&Handle<Building>, &ProductionQueue, Option<&Construction>)>,
+    mut text_query: Query<&mut Text, With<ProductionText>>,
+) {
+    let selected = selected_building
+        .0
+        .and_then(|entity| query.get(entity).ok())
+        .and_then(|(building_handle, production_queue, construction)| {
+            Some((
+                buildings.get(building_handle)?,
+                production_queue,
+                construction,
+            ))
+        });
+
+    let value = match (selected, input_config.get(&input_resource.0)) {
+        (Some((building, _, Some(construction))), _) => format!(
+            "Under construction {:.0}%",
+            construction.fraction(building) * 100.0
+        ),
+        (Some((building, production_queue, None)), Some(input_config)) => {
+            let mut lines: Vec<String> = building
+                .productions
+                .iter()
+                .zip(input_config.train.iter())
+                .map(|(production, input)| {
+                    format!("{:?}: {} ({:.0})", input, production.name, production.cost)
+                })
+                .collect();
+
+            let training = production_queue
+                .queue
+                .front()
+                .and_then(|index| building.productions.get(*index));
+
+            if let Some(production) = training {
+                lines.push(format!(
+                    "Training {} {:.0}% ({} queued)",
+                    production.name,
+                    production_queue.fraction(building) * 100.0,
+                    production_queue.queue.len() - 1
+                ));
+            }
+
+            if let Some(player_id) = *player_id {
+                let researched = upgrades.researched(player_id);
+
+                for (upgrade_path, input) in
+                    building.upgrades.iter().zip(input_config.research.iter())
+                {
+                    if let Some(upgrade) =
+                        upgrade_assets.get(&upgrade_assets.get_handle(upgrade_path.as_str()))
+                    {
+                        if researched.contains(upgrade_path) {
+                            lines.push(format!("{:?}: {} (researched)", input, upgrade.name));
+                        } else {
+                            lines.push(format!(
+                                "{:?}: {} ({:.0})",
+                                input, upgrade.name, upgrade.cost
+                            ));
+                        }
+                    }
+                }
+
+                let research = upgrades.researching(player_id).and_then(|research| {
+                    Some((
+                        research,
+                        upgrade_assets
+                            .get(&upgrade_assets.get_handle(research.upgrade.as_str()))?,
+                    ))
+                });
+
+                if let Some((research, upgrade)) = research {
+                    lines.push(format!(
+                        "Researching {} {:.0}%",
+                        upgrade.name,
+                        research.fraction(upgrade) * 100.0
+                    ));
+                }
+            }
+
+            lines.join("\n")
+        }
+        _ => String::new(),
+    };
+
+    for mut text in text_query.iter_mut() {
+        if text.value != value {
+            text.value = value.clone();
+        }
+    }
+}
+
+pub struct BuildingLoader;
+
+ron_loader!(BuildingLoader, "building" => Building);
+
+pub struct BuildingPlugin(pub bool);
+
+impl BuildingPlugin {
+    pub fn server() -> Self {
+        Self(true)
+    }
+
+    pub fn client() -> Self {
+        Self(false)
+    }
+}
+
+impl Plugin for BuildingPlugin {
+    fn build(&self, app_builder: &mut AppBuilder) {
+        app_builder.add_asset_loader(BuildingLoader);
+        app_builder.add_asset::<Building>();
+
+        app_builder.add_system_to_stage(SIMULATION_STAGE, production_system.system());
+
+        if self.0 {
+            app_builder.add_system(server_production_message_system.system());
+            app_builder.add_system_to_stage(
+                bevy::app::stage::LAST,
+                server_production_sync_system.system(),
+            );
+        } else {
+            app_builder.init_resource::<SelectedBuilding>();
+            app_builder.add_startup_system(client_production_setup.system());
+            app_builder.add_system(client_production_queue_system.system());
+            app_builder.add_system(production_bar_system.system());
+            app_builder.add_system(client_building_selection_system.system());
+            app_builder.add_system(client_production_input_system.system());
+            app_builder.add_system(client_production_text_system.system());
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use crate::*;
+use bevy::render::pipeline::{RenderPipeline, RenderPipelines};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BuildingSpawnable {
+    pub position: Vec3,
+    pub owner: PlayerId,
+    pub building: String,
+    /// Placed by a worker, and has to be built first.
+    #[serde(default)]
+    pub under_construction: bool,
+}
+
+#[typetag::serde]
+impl Spawnable for BuildingSpawnable {
+    fn spawn(&self, commands: &mut Commands, resources: &Resources) -> Entity {
+        let network_settings = resources.get::<NetworkSettings>().unwrap();
+
+        let buildings = resources.get::<Assets<Building>>().unwrap();
+        let building_handle = buildings.get_handle(self.building.as_str());
+        let building = buildings.get(&building_handle).unwrap();
+
+        commands
+            .spawn((ProductionQueue::default(),))
+            .with(building_handle)
+            .with(Position {
+                position: self.position,
+            })
+            .with(Owner(self.owner));
+
+        if self.under_construction {
+            commands.with(Construction::default());
+        } else if building.drop_off {
+            commands.with(DropOff {
+                size: building.size,
+            });
+        }
+
+        if network_settings.is_client() {
+            let textures = resources.get::<Assets<Texture>>().unwrap();
+            let mut color_materials = resources.get_mut::<Assets<ColorMaterial>>().unwrap();
+            let texture = textures.get_handle(building.texture.as_str());
+
+            commands
+                .with_bundle(SpriteBundle {
+                    material: color_materials.add(texture.into()),
+                    render_pipelines: RenderPipelines::from_pipelines(vec![RenderPipeline::new(
+                        SPRITE_PIPELINE_HANDLE.typed(),
+                    )]),
+                    ..Default::default()
+                })
+                .with(ProductionBar(0))
+                .with_children(|parent| {
+                    parent.spawn(BarBundle {
+                        bar: Bar {
+                            size: Vec2::new(building.width, 6.0),
+                            max_value: 1.0,
+                            current_value: 0.0,
+                            color_a: Color::hex("3b8ed6").unwrap(),
+                            color_b: Color::hex("5fa0cc").unwrap(),
+                            ..Default::default()
+                        },
+                        transform: Transform::from_translation(Vec3::new(
+                            0.0,
+                            building.height / 2.0 + 8.0,
+                            0.0,
+                        )),
+                        visible: Visible {
+                            is_visible: false,
+                            is_transparent: true,
+                        },
+                        ..Default::default()
+                    });
+                });
+        }
+
+        commands.current_entity().unwrap()
+    }
+}
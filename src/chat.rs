@@ -0,0 +1,298 @@
+use crate::*;
+use bevy::input::keyboard::KeyboardInput;
+use bevy::window::ReceivedCharacter;
+use std::collections::VecDeque;
+
+const MAX_CHAT_MESSAGE_LENGTH: usize = 200;
+const CHAT_LOG_LENGTH: usize = 8;
+// how long a line stays visible while not typing
+const CHAT_LINE_DURATION: f64 = 10.0;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChatScope {
+    All,
+    Team,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ChatMessage {
+    pub scope: ChatScope,
+    /// Filled in by the server, whatever the client sends is ignored.
+    pub sender: String,
+    pub text: String,
+}
+
+fn server_chat_system(
+    mut net: ResMut<Network>,
+    mut rejections: ResMut<Rejections>,
+    players: Res<Players>,
+    lobby: Res<Lobby>,
+    teams: Res<Teams>,
+) {
+    for (handle, chat_message) in net.recv::<ChatMessage>() {
+        let text: String = chat_message
+            .text
+            .trim()
+            .chars()
+            .filter(|c| !c.is_control())
+            .take(MAX_CHAT_MESSAGE_LENGTH)
+            .collect();
+
+        if text.is_empty() {
+            continue;
+        }
+
+        let player_id = players.player_ids.get(&handle).cloned();
+
+        let sender = match player_id {
+            Some(player_id) => lobby.get(player_id).map_or_else(
+                || format!("Player {}", player_id.0 + 1),
+                |player| player.name.clone(),
+            ),
+            None if players.spectators.contains(&handle) => "Spectator".to_string(),
+            None => {
+                rejections.reject(&mut net, handle, Rejection::NotAPlayer);
+                continue;
+            }
+        };
+
+        info!("[{:?}] {}: {}", chat_message.scope, sender, text);
+
+        let message = ChatMessage {
+            scope: chat_message.scope,
+            sender,
+            text,
+        };
+
+        let recipients: Vec<ConnectionHandle> = match (chat_message.scope, player_id) {
+            (ChatScope::All, _) => net.connections().cloned().collect(),
+            (ChatScope::Team, Some(player_id)) => players
+                .player_ids
+                .iter()
+                .filter(|(_, other)| teams.is_allied(**other, player_id))
+                .map(|(handle, _)| *handle)
+                .collect(),
+            // spectators are on a team of their own
+            (ChatScope::Team, None) => players.spectators.iter().cloned().collect(),
+        };
+
+        for recipient in recipients {
+            net.send_message(recipient, message.clone());
+        }
+    }
+}
+
+/// The text being typed, while the chat is open.
+#[derive(Default)]
+pub struct ChatInput {
+    pub scope: Option<ChatScope>,
+    pub text: String,
+}
+
+impl ChatInput {
+    pub fn is_active(&self) -> bool {
+        self.scope.is_some()
+    }
+}
+
+#[derive(Default)]
+pub struct ChatLog {
+    lines: VecDeque<(f64, String)>,
+}
+
+impl ChatLog {
+    pub fn push(&mut self, time: f64, line: String) {
+        self.lines.push_back((time, line));
+
+        while self.lines.len() > CHAT_LOG_LENGTH {
+            self.lines.pop_front();
+        }
+    }
+}
+
+/// Types into the chat, and hides the keyboard from the game hotkeys while doing so.
+fn client_chat_input_system(
+    mut character_reader: Local<EventReader<ReceivedCharacter>>,
+    mut keyboard_reader: Local<EventReader<KeyboardInput>>,
+    character_events: Res<Events<ReceivedCharacter>>,
+    keyboard_events: Res<Events<KeyboardInput>>,
+    mut keyboard_input: ResMut<Input<KeyCode>>,
+    mouse_input: Res<Input<MouseButton>>,
+    input_config: Res<Assets<InputConfig>>,
+    input_resource: Res<InputResource>,
+    mut chat_input: ResMut<ChatInput>,
+    mut net: ResMut<Network>,
+) {
+    let input_config = match input_config.get(&input_resource.0) {
+        Some(input_config) => input_config,
+        None => return,
+    };
+
+    if !chat_input.is_active() {
+        // don't pick up the characters typed before the chat was opened
+        for _ in character_reader.iter(&character_events) {}
+        for _ in keyboard_reader.iter(&keyboard_events) {}
+
+        if input_config
+            .team_chat
+            .just_pressed(&keyboard_input, &mouse_input)
+        {
+            chat_input.scope = Some(ChatScope::Team);
+        } else if input_config
+            .chat
+            .just_pressed(&keyboard_input, &mouse_input)
+        {
+            chat_input.scope = Some(ChatScope::All);
+        } else {
+            return;
+        }
+    } else {
+        for event in character_reader.iter(&character_events) {
+            if !event.char.is_control() && chat_input.text.len() < MAX_CHAT_MESSAGE_LENGTH {
+                chat_input.text.push(event.char);
+            }
+        }
+
+        for event in keyboard_reader.iter(&keyboard_events) {
+            if !event.state.is_pressed() {
+                continue;
+            }
+
+            match event.key_code {
+                Some(KeyCode::Back) => {
+                    chat_input.text.pop();
+                }
+                Some(KeyCode::Return) => {
+                    let text = std::mem::replace(&mut chat_input.text, String::new());
+
+                    if !text.trim().is_empty() {
+                        net.broadcast_message(ChatMessage {
+                            scope: chat_input.scope.unwrap(),
+                            sender: String::new(),
+                            text,
+                        });
+                    }
+
+                    chat_input.scope = None;
+                }
+                Some(KeyCode::Escape) => {
+                    chat_input.text.clear();
+                    chat_input.scope = None;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let pressed: Vec<KeyCode> = keyboard_input.get_pressed().cloned().collect();
+    let released: Vec<KeyCode> = keyboard_input.get_just_released().cloned().collect();
+
+    for key_code in pressed.into_iter().chain(released) {
+        keyboard_input.reset(key_code);
+    }
+}
+
+fn client_chat_receive_system(
+    time: Res<Time>,
+    mut net: ResMut<Network>,
+    mut chat_log: ResMut<ChatLog>,
+) {
+    for (_handle, chat_message) in net.recv::<ChatMessage>() {
+        let line = match chat_message.scope {
+            ChatScope::All => format!("{}: {}", chat_message.sender, chat_message.text),
+            ChatScope::Team => format!("[Team] {}: {}", chat_message.sender, chat_message.text),
+        };
+
+        chat_log.push(time.seconds_since_startup(), line);
+    }
+}
+
+pub struct ChatText;
+
+fn client_chat_setup(commands: &mut Commands, asset_server: Res<AssetServer>) {
+    commands
+        .spawn(TextBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: Rect {
+                    bottom: Val::Px(64.0),
+                    left: Val::Px(16.0),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            text: Text {
+                value: String::new(),
+                font: asset_server.load("fonts/DejaVuSans.ttf"),
+                style: TextStyle {
+                    font_size: 18.0,
+                    color: Color::WHITE,
+                    ..Default::default()
+                },
+            },
+            ..Default::default()
+        })
+        .with(ChatText);
+}
+
+fn client_chat_log_system(
+    time: Res<Time>,
+    chat_input: Res<ChatInput>,
+    chat_log: Res<ChatLog>,
+    mut query: Query<&mut Text, With<ChatText>>,
+) {
+    let now = time.seconds_since_startup();
+
+    // the whole log is shown while typing, otherwise only the recent lines
+    let mut lines: Vec<String> = chat_log
+        .lines
+        .iter()
+        .filter(|(time, _)| chat_input.is_active() || now - time < CHAT_LINE_DURATION)
+        .map(|(_, line)| line.clone())
+        .collect();
+
+    match chat_input.scope {
+        Some(ChatScope::All) => lines.push(format!("> {}_", chat_input.text)),
+        Some(ChatScope::Team) => lines.push(format!("[Team] > {}_", chat_input.text)),
+        None => {}
+    }
+
+    let value = lines.join("\n");
+
+    for mut text in query.iter_mut() {
+        if text.value != value {
+            text.value = value.clone();
+        }
+    }
+}
+
+pub struct ChatPlugin(pub bool);
+
+impl ChatPlugin {
+    pub fn server() -> Self {
+        Self(true)
+    }
+
+    pub fn client() -> Self {
+        Self(false)
+    }
+}
+
+impl Plugin for ChatPlugin {
+    fn build(&self, app_builder: &mut AppBuilder) {
+        if self.0 {
+            app_builder.add_system(server_chat_system.system());
+        } else {
+            app_builder.init_resource::<ChatInput>();
+            app_builder.init_resource::<ChatLog>();
+            app_builder.add_startup_system(client_chat_setup.system());
+            // runs before any system looking at the keyboard
+            app_builder.add_system_to_stage(
+                bevy::app::stage::PRE_UPDATE,
+                client_chat_input_system.system(),
+            );
+            app_builder.add_system(client_chat_receive_system.system());
+            app_builder.add_system(client_chat_log_system.system());
+        }
+    }
+}
@@ -7,55 +7,116 @@ pub struct Client {
     ip: String,
     #[clap(long)]
     free_cursor: bool,
+    #[clap(short, long, default_value = "Player")]
+    name: String,
+    /// Watch the match without taking a slot in it.
+    #[clap(long)]
+    spectate: bool,
 }
 
 impl Client {
     pub fn run(self) {
+        self.app(Transport::Turbulence).run();
+    }
+
+    /// Builds the client app, which can be updated manually when using a loopback transport.
+    pub fn app(self, transport: Transport) -> AppBuilder {
+        self.build(transport, false)
+    }
+
+    /// Builds the client app without a window, sound or logging, so several can run in one process.
+    pub fn headless_app(self, transport: Transport) -> AppBuilder {
+        self.build(transport, true)
+    }
+
+    fn build(self, transport: Transport, headless: bool) -> AppBuilder {
         let grab_cursor = !self.free_cursor;
+        let connect = transport.is_turbulence();
+        let mut app_builder = App::build();
 
-        App::build()
+        app_builder
             // resources
             .init_resource::<SelectedUnits>()
             .init_resource::<NetworkEntityRegistry>()
             .init_resource::<Option<PlayerId>>()
+            .init_resource::<SimulationMode>()
             .add_resource(NetworkSettings::client())
+            .add_resource(PlayerName(self.name.clone()))
+            .add_resource(SpectatorRequest(self.spectate))
             .add_resource(self)
             .add_resource(WindowDescriptor {
                 cursor_locked: grab_cursor,
                 mode: bevy::window::WindowMode::BorderlessFullscreen,
                 ..Default::default()
-            })
+            });
+
+        if headless {
+            // the render graph is still built, it just never gets drawn
+            app_builder.add_plugins_with(DefaultPlugins, |group| {
+                group
+                    .disable::<bevy::log::LogPlugin>()
+                    .disable::<bevy::winit::WinitPlugin>()
+                    .disable::<bevy::wgpu::WgpuPlugin>()
+                    .disable::<bevy::audio::AudioPlugin>()
+                    .disable::<bevy::gilrs::GilrsPlugin>()
+            });
+        } else {
+            app_builder.add_plugins(DefaultPlugins);
+        }
+
+        app_builder
             // plugins
-            .add_plugins(DefaultPlugins)
-            .add_plugin(UnitPlugin::client())
+            // the simulation plugins are added in the same order as on the server,
+            // so lockstep runs the systems in the same order
+            .add_plugin(SimulationPlugin)
+            // the grids are rebuilt before anything in the simulation uses them
+            .add_plugin(PlacementPlugin::client())
+            .add_plugin(NavigationPlugin)
+            .add_plugin(LockstepPlugin::client())
             .add_plugin(SpawnPlugin::client())
             .add_plugin(AnimationPlugin::client())
             .add_plugin(TileMapPlugin::client())
+            .add_plugin(UnitPlugin::client())
             .add_plugin(ConnectionPlugin::client())
+            .add_plugin(LobbyPlugin::client())
+            .add_plugin(TeamPlugin::client())
+            .add_plugin(EconomyPlugin::client())
+            .add_plugin(BuildingPlugin::client())
+            .add_plugin(UpgradePlugin::client())
+            .add_plugin(DisconnectPlugin::client())
+            .add_plugin(SpectatorPlugin)
+            .add_plugin(ChatPlugin::client())
+            .add_plugin(VictoryPlugin::client())
+            .add_plugin(ValidationPlugin)
+            .add_plugin(ReplayPlugin::client())
             .add_plugin(PositionPlugin::client())
+            .add_plugin(TransportPlugin(transport))
+            .add_plugin(NetworkStatsPlugin::client())
+            .add_plugin(UnitAnimationPlugin)
             .add_plugin(InputPlugin)
-            .add_plugin(NetworkingPlugin)
             .add_plugin(SpriteShaderPlugin)
             .add_plugin(BarPlugin)
             // assets
             // loaders
             // startup systems
-            .add_startup_system(network_setup.system())
             .add_startup_system(setup.system())
             // systems
             .add_system(mouse_position_system.system())
-            .add_system(camera_movement_system.system())
-            // run
-            .run();
+            .add_system(camera_movement_system.system());
+
+        if connect {
+            app_builder.add_startup_system(connect_system.system());
+            app_builder.add_system(reconnect_system.system());
+        }
+
+        app_builder
     }
 }
 
 fn setup(
     commands: &mut Commands,
     asset_server: Res<AssetServer>,
-    client: Res<Client>,
     input_config: Res<Assets<InputConfig>>,
-    mut net: ResMut<NetworkResource>,
 ) {
     // we store these so they wont be automatically freed
     let handles = asset_server.load_folder(".").unwrap();
@@ -81,7 +142,9 @@ fn setup(
         .unwrap();
 
     commands.insert_resource(MousePosition::new(camera));
+}
 
+fn connect_system(mut net: ResMut<NetworkResource>, client: Res<Client>) {
     let addr = client.ip.to_socket_addrs().unwrap().next().unwrap();
 
     info!("connecting at {}", addr);
@@ -89,6 +152,31 @@ fn setup(
     net.connect(addr);
 }
 
+fn reconnect_system(
+    mut timer: Local<Timer>,
+    time: Res<Time>,
+    mut net: ResMut<NetworkResource>,
+    client: Res<Client>,
+    connection_status: Res<ConnectionStatus>,
+) {
+    // the server decides whether we get our player back
+    if !net.connections.is_empty() || !matches!(*connection_status, ConnectionStatus::Connecting) {
+        return;
+    }
+
+    timer.set_duration(2.0);
+    timer.set_repeating(true);
+    timer.tick(time.delta_seconds());
+
+    if timer.just_finished() {
+        let addr = client.ip.to_socket_addrs().unwrap().next().unwrap();
+
+        info!("reconnecting at {}", addr);
+
+        net.connect(addr);
+    }
+}
+
 fn camera_movement_system(
     time: Res<Time>,
     input_config: Res<Assets<InputConfig>>,
@@ -15,7 +15,48 @@ pub trait Command: std::fmt::Debug + CommandClone + Send + Sync + 'static {
         request_cancel: bool,
         network_entity_registry: &NetworkEntityRegistry,
         query: &Query<(&Position, &Animator)>,
+        economy: &EconomyQuery,
+        placement_grid: &PlacementGrid,
     ) -> CommandControlFlow;
+
+    /// Checks a command received from a client, before it's put in a queue.
+    fn validate(
+        &self,
+        _network_entity_registry: &NetworkEntityRegistry,
+        _query: &Query<(&Position, &Animator)>,
+        _economy: &EconomyQuery,
+    ) -> Result<(), Rejection> {
+        Ok(())
+    }
+
+    /// The unit the command attacks, which has to be hostile.
+    fn attack_target(&self) -> Option<NetworkEntity> {
+        None
+    }
+
+    /// The building the command places and the tile it goes on, which has to be free.
+    fn placement(&self) -> Option<(&str, &TilePosition)> {
+        None
+    }
+
+    /// Where the group the command was given to is headed, the server checks it's large enough.
+    fn group_target(&self) -> Option<Vec2> {
+        None
+    }
+
+    fn leave_group(&mut self) {}
+}
+
+// the target has to be something with a position, which we can walk to
+fn validate_target(
+    target: NetworkEntity,
+    network_entity_registry: &NetworkEntityRegistry,
+    query: &Query<(&Position, &Animator)>,
+) -> Result<(), Rejection> {
+    match network_entity_registry.get(&target) {
+        Some(entity) if query.get(*entity).is_ok() => Ok(()),
+        _ => Err(Rejection::InvalidTarget(target)),
+    }
 }
 
 pub trait CommandClone {
@@ -38,6 +79,9 @@ impl Clone for Box<dyn Command> {
 pub struct MovePositionCommand {
     pub target: Vec2,
     pub precise: bool,
+    /// Where a large group is headed, its units follow a shared flow field there.
+    #[serde(default)]
+    pub group_target: Option<Vec2>,
 }
 
 #[typetag::serde]
@@ -49,12 +93,17 @@ impl Command for MovePositionCommand {
         request_cancel: bool,
         network_entity_registry: &NetworkEntityRegistry,
         query: &Query<(&Position, &Animator)>,
+        _economy: &EconomyQuery,
+        _placement_grid: &PlacementGrid,
     ) -> CommandControlFlow {
         if request_cancel {
             return CommandControlFlow::Completed;
         }
 
-        let (position, _) = query.get(entity).unwrap();
+        let (position, _) = match query.get(entity) {
+            Ok(components) => components,
+            Err(_) => return CommandControlFlow::Completed,
+        };
         let dist = (self.target - position.position.truncate()).length();
 
         if self.precise {
@@ -63,6 +112,7 @@ impl Command for MovePositionCommand {
             } else {
                 CommandControlFlow::Behaviour(Behaviour::Move {
                     target: self.target,
+                    flow_field: self.group_target,
                 })
             }
         } else {
@@ -71,10 +121,34 @@ impl Command for MovePositionCommand {
             } else {
                 CommandControlFlow::Behaviour(Behaviour::Move {
                     target: self.target,
+                    flow_field: self.group_target,
                 })
             }
         }
     }
+
+    fn validate(
+        &self,
+        _network_entity_registry: &NetworkEntityRegistry,
+        _query: &Query<(&Position, &Animator)>,
+        _economy: &EconomyQuery,
+    ) -> Result<(), Rejection> {
+        let is_finite = |position: &Vec2| position.x.is_finite() && position.y.is_finite();
+
+        if is_finite(&self.target) && self.group_target.as_ref().map_or(true, is_finite) {
+            Ok(())
+        } else {
+            Err(Rejection::InvalidPosition)
+        }
+    }
+
+    fn group_target(&self) -> Option<Vec2> {
+        self.group_target
+    }
+
+    fn leave_group(&mut self) {
+        self.group_target = None;
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -91,22 +165,35 @@ impl Command for MoveUnitCommand {
         request_cancel: bool,
         network_entity_registry: &NetworkEntityRegistry,
         query: &Query<(&Position, &Animator)>,
+        _economy: &EconomyQuery,
+        _placement_grid: &PlacementGrid,
     ) -> CommandControlFlow {
         if request_cancel {
             return CommandControlFlow::Completed;
         }
 
-        match network_entity_registry.get(&self.target) {
-            Some(target_entity) => {
-                let (target_position, _) = query.get(*target_entity).unwrap();
+        let target_entity = match network_entity_registry.get(&self.target) {
+            Some(target_entity) => target_entity,
+            None => return CommandControlFlow::Completed,
+        };
 
-                CommandControlFlow::Behaviour(Behaviour::Move {
-                    target: target_position.position.truncate(),
-                })
-            }
-            None => CommandControlFlow::Completed,
+        match query.get(*target_entity) {
+            Ok((target_position, _)) => CommandControlFlow::Behaviour(Behaviour::Move {
+                target: target_position.position.truncate(),
+                flow_field: None,
+            }),
+            Err(_) => CommandControlFlow::Completed,
         }
     }
+
+    fn validate(
+        &self,
+        network_entity_registry: &NetworkEntityRegistry,
+        query: &Query<(&Position, &Animator)>,
+        _economy: &EconomyQuery,
+    ) -> Result<(), Rejection> {
+        validate_target(self.target, network_entity_registry, query)
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -123,10 +210,20 @@ impl Command for AttackUnitCommand {
         request_cancel: bool,
         network_entity_registry: &NetworkEntityRegistry,
         query: &Query<(&Position, &Animator)>,
+        _economy: &EconomyQuery,
+        _placement_grid: &PlacementGrid,
     ) -> CommandControlFlow {
-        match network_entity_registry.get(&self.target) {
+        let (position, animator) = match query.get(entity) {
+            Ok(components) => components,
+            Err(_) => return CommandControlFlow::Completed,
+        };
+
+        let target = network_entity_registry
+            .get(&self.target)
+            .filter(|target| query.get(**target).is_ok());
+
+        match target {
             Some(target) => {
-                let (position, animator) = query.get(entity).unwrap();
                 let (target_position, _) = query.get(*target).unwrap();
 
                 let diff = position.position.truncate() - target_position.position.truncate();
@@ -138,6 +235,7 @@ impl Command for AttackUnitCommand {
                     } else {
                         CommandControlFlow::Behaviour(Behaviour::Move {
                             target: target_position.position.truncate(),
+                            flow_field: None,
                         })
                     }
                 } else {
@@ -153,8 +251,6 @@ impl Command for AttackUnitCommand {
                 }
             }
             None => {
-                let (_, animator) = query.get(entity).unwrap();
-
                 if animator.current_frame() == 0 {
                     CommandControlFlow::Completed
                 } else {
@@ -163,4 +259,317 @@ impl Command for AttackUnitCommand {
             },
         }
     }
+
+    fn validate(
+        &self,
+        network_entity_registry: &NetworkEntityRegistry,
+        query: &Query<(&Position, &Animator)>,
+        _economy: &EconomyQuery,
+    ) -> Result<(), Rejection> {
+        validate_target(self.target, network_entity_registry, query)
+    }
+
+    fn attack_target(&self) -> Option<NetworkEntity> {
+        Some(self.target)
+    }
+}
+
+// the resource node behind the target, with its position and size
+fn find_resource_node(
+    target: NetworkEntity,
+    network_entity_registry: &NetworkEntityRegistry,
+    economy: &EconomyQuery,
+) -> Option<(Entity, Vec2, f32)> {
+    let entity = *network_entity_registry.get(&target)?;
+    let (position, _, _, resource_node, _) = economy.get(entity).ok()?;
+    let resource_node = resource_node?;
+
+    Some((entity, position.position.truncate(), resource_node.size))
+}
+
+// one of the player's own drop-offs, the closest one if none is given
+fn find_drop_off(
+    target: Option<NetworkEntity>,
+    position: Vec2,
+    owner: PlayerId,
+    network_entity_registry: &NetworkEntityRegistry,
+    economy: &EconomyQuery,
+) -> Option<(Vec2, f32)> {
+    let is_own = |drop_off_owner: Option<&Owner>| drop_off_owner.map_or(false, |o| o.0 == owner);
+
+    match target {
+        Some(target) => {
+            let entity = *network_entity_registry.get(&target)?;
+            let (drop_off_position, drop_off_owner, _, _, drop_off) = economy.get(entity).ok()?;
+
+            if !is_own(drop_off_owner) {
+                return None;
+            }
+
+            Some((drop_off_position.position.truncate(), drop_off?.size))
+        }
+        None => economy
+            .iter()
+            .filter(|(_, drop_off_owner, _, _, _)| is_own(*drop_off_owner))
+            .filter_map(|(drop_off_position, _, _, _, drop_off)| {
+                Some((drop_off_position.position.truncate(), drop_off?.size))
+            })
+            .min_by(|(a, _), (b, _)| {
+                let a = (*a - position).length();
+                let b = (*b - position).length();
+                a.partial_cmp(&b).unwrap()
+            }),
+    }
+}
+
+fn deliver_cargo(
+    position: Vec2,
+    harvester: &Harvester,
+    drop_off: (Vec2, f32),
+) -> CommandControlFlow {
+    let (drop_off_position, drop_off_size) = drop_off;
+
+    if (drop_off_position - position).length() > harvester.range + drop_off_size {
+        CommandControlFlow::Behaviour(Behaviour::Move {
+            target: drop_off_position,
+            flow_field: None,
+        })
+    } else {
+        CommandControlFlow::Behaviour(Behaviour::DropOff)
+    }
+}
+
+fn validate_resource_node(
+    target: NetworkEntity,
+    network_entity_registry: &NetworkEntityRegistry,
+    economy: &EconomyQuery,
+) -> Result<(), Rejection> {
+    match find_resource_node(target, network_entity_registry, economy) {
+        Some(_) => Ok(()),
+        None => Err(Rejection::InvalidTarget(target)),
+    }
+}
+
+/// Gathers from a resource node and brings it to the closest drop-off, until the node runs out.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HarvestCommand {
+    pub target: NetworkEntity,
+    /// Set while bringing the cargo back, until it's all unloaded.
+    #[serde(default)]
+    pub returning: bool,
+}
+
+#[typetag::serde]
+impl Command for HarvestCommand {
+    fn execute(
+        &mut self,
+        entity: Entity,
+        unit: &Unit,
+        request_cancel: bool,
+        network_entity_registry: &NetworkEntityRegistry,
+        _query: &Query<(&Position, &Animator)>,
+        economy: &EconomyQuery,
+        _placement_grid: &PlacementGrid,
+    ) -> CommandControlFlow {
+        if request_cancel {
+            return CommandControlFlow::Completed;
+        }
+
+        let harvester = match &unit.harvester {
+            Some(harvester) => harvester,
+            None => return CommandControlFlow::Completed,
+        };
+
+        let (position, owner, cargo) = match economy.get(entity) {
+            Ok((position, Some(owner), cargo, _, _)) => (
+                position.position.truncate(),
+                owner.0,
+                cargo.map_or(0.0, |cargo| cargo.amount),
+            ),
+            _ => return CommandControlFlow::Completed,
+        };
+
+        let resource_node = find_resource_node(self.target, network_entity_registry, economy);
+
+        if cargo <= 0.0 {
+            self.returning = false;
+        } else if cargo >= harvester.capacity || resource_node.is_none() {
+            self.returning = true;
+        }
+
+        if self.returning {
+            return match find_drop_off(None, position, owner, network_entity_registry, economy) {
+                Some(drop_off) => deliver_cargo(position, harvester, drop_off),
+                None => CommandControlFlow::Completed,
+            };
+        }
+
+        match resource_node {
+            Some((resource_node, node_position, node_size)) => {
+                if (node_position - position).length() > harvester.range + node_size {
+                    CommandControlFlow::Behaviour(Behaviour::Move {
+                        target: node_position,
+                        flow_field: None,
+                    })
+                } else {
+                    CommandControlFlow::Behaviour(Behaviour::Harvest {
+                        target_position: node_position,
+                        target: resource_node,
+                    })
+                }
+            }
+            None => CommandControlFlow::Completed,
+        }
+    }
+
+    fn validate(
+        &self,
+        network_entity_registry: &NetworkEntityRegistry,
+        _query: &Query<(&Position, &Animator)>,
+        economy: &EconomyQuery,
+    ) -> Result<(), Rejection> {
+        validate_resource_node(self.target, network_entity_registry, economy)
+    }
+}
+
+/// Brings what a harvester carries to a drop-off, the closest one if none is given.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ReturnCargoCommand {
+    pub target: Option<NetworkEntity>,
+}
+
+#[typetag::serde]
+impl Command for ReturnCargoCommand {
+    fn execute(
+        &mut self,
+        entity: Entity,
+        unit: &Unit,
+        request_cancel: bool,
+        network_entity_registry: &NetworkEntityRegistry,
+        _query: &Query<(&Position, &Animator)>,
+        economy: &EconomyQuery,
+        _placement_grid: &PlacementGrid,
+    ) -> CommandControlFlow {
+        if request_cancel {
+            return CommandControlFlow::Completed;
+        }
+
+        let harvester = match &unit.harvester {
+            Some(harvester) => harvester,
+            None => return CommandControlFlow::Completed,
+        };
+
+        let (position, owner) = match economy.get(entity) {
+            Ok((position, Some(owner), Some(cargo), _, _)) if cargo.amount > 0.0 => {
+                (position.position.truncate(), owner.0)
+            }
+            _ => return CommandControlFlow::Completed,
+        };
+
+        match find_drop_off(
+            self.target,
+            position,
+            owner,
+            network_entity_registry,
+            economy,
+        ) {
+            Some(drop_off) => deliver_cargo(position, harvester, drop_off),
+            None => CommandControlFlow::Completed,
+        }
+    }
+
+    fn validate(
+        &self,
+        network_entity_registry: &NetworkEntityRegistry,
+        _query: &Query<(&Position, &Animator)>,
+        economy: &EconomyQuery,
+    ) -> Result<(), Rejection> {
+        let target = match self.target {
+            Some(target) => target,
+            None => return Ok(()),
+        };
+
+        let drop_off = network_entity_registry
+            .get(&target)
+            .and_then(|entity| economy.get(*entity).ok());
+
+        match drop_off {
+            Some((_, _, _, _, Some(_))) => Ok(()),
+            _ => Err(Rejection::InvalidTarget(target)),
+        }
+    }
+}
+
+/// Places a building and builds it, along with every other worker sent to the same site.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PlaceBuildingCommand {
+    pub building: String,
+    pub position: TilePosition,
+    /// Set once the site is there, so the command ends if it's destroyed.
+    #[serde(default)]
+    pub started: bool,
+}
+
+#[typetag::serde]
+impl Command for PlaceBuildingCommand {
+    fn execute(
+        &mut self,
+        entity: Entity,
+        unit: &Unit,
+        request_cancel: bool,
+        _network_entity_registry: &NetworkEntityRegistry,
+        _query: &Query<(&Position, &Animator)>,
+        economy: &EconomyQuery,
+        placement_grid: &PlacementGrid,
+    ) -> CommandControlFlow {
+        if request_cancel {
+            return CommandControlFlow::Completed;
+        }
+
+        let builder = match &unit.builder {
+            Some(builder) if builder.buildings.contains(&self.building) => builder,
+            _ => return CommandControlFlow::Completed,
+        };
+
+        let (position, owner) = match economy.get(entity) {
+            Ok((position, Some(owner), _, _, _)) => (position.position.truncate(), owner.0),
+            _ => return CommandControlFlow::Completed,
+        };
+
+        let site_position = self.position.pos();
+        let dist = (site_position - position).length();
+
+        match placement_grid.site(&self.position) {
+            Some(site) if site.owner == owner && site.under_construction => {
+                self.started = true;
+
+                if dist > builder.range + site.size {
+                    CommandControlFlow::Behaviour(Behaviour::Move {
+                        target: site_position,
+                        flow_field: None,
+                    })
+                } else {
+                    CommandControlFlow::Behaviour(Behaviour::Construct {
+                        target_position: site_position,
+                        target: site.entity,
+                    })
+                }
+            }
+            // finished, or someone else got there first
+            Some(_) => CommandControlFlow::Completed,
+            None if self.started || placement_grid.is_blocked(&self.position) => {
+                CommandControlFlow::Completed
+            }
+            // the server hasn't placed it yet
+            None if dist <= builder.range => CommandControlFlow::Wait,
+            None => CommandControlFlow::Behaviour(Behaviour::Move {
+                target: site_position,
+                flow_field: None,
+            }),
+        }
+    }
+
+    fn placement(&self) -> Option<(&str, &TilePosition)> {
+        Some((self.building.as_str(), &self.position))
+    }
 }
@@ -1,7 +1,24 @@
 use crate::*;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+/// Bump whenever a change makes builds unable to play with each other.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+// assets affecting the simulation, which all peers have to agree on
+const GAMEPLAY_ASSET_EXTENSIONS: &[&str] = &[
+    "unit",
+    "map",
+    "anim",
+    "unit_anim",
+    "tile_map",
+    "tile_set",
+    "spn",
+    "building",
+    "upgrade",
+];
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct PlayerId(pub u64);
 
 #[derive(Clone, Debug)]
@@ -11,6 +28,7 @@ pub struct Owner(pub PlayerId);
 pub struct Players {
     pub player_ids: HashMap<ConnectionHandle, PlayerId>,
     pub connection_handles: HashMap<PlayerId, ConnectionHandle>,
+    pub spectators: HashSet<ConnectionHandle>,
 }
 
 impl Players {
@@ -18,56 +36,305 @@ impl Players {
         self.player_ids.insert(connection_handle, player_id);
         self.connection_handles.insert(player_id, connection_handle);
     }
+
+    pub fn remove_connection(&mut self, connection_handle: &ConnectionHandle) -> Option<PlayerId> {
+        let player_id = self.player_ids.remove(connection_handle)?;
+        self.connection_handles.remove(&player_id);
+        Some(player_id)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum ConnectionMessage {
-    Client,
-    Server(PlayerId),
+    Client {
+        protocol_version: u32,
+        content_hash: u64,
+        session: u64,
+        spectator: bool,
+    },
+    /// The assigned player, how the match is simulated and its tick rate.
+    Server(PlayerId, SimulationMode, u32),
+    Spectator(SimulationMode, u32),
+    Rejected(String),
+}
+
+/// A hash of every gameplay asset, so peers with different assets can't join each other.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ContentHash(pub u64);
+
+impl ContentHash {
+    pub fn from_asset_folder() -> Self {
+        let root = asset_root().join("assets");
+
+        let mut paths = Vec::new();
+        collect_gameplay_assets(&root, &mut paths);
+        paths.sort();
+
+        // fnv-1a, as it's stable across builds unlike the std hasher
+        let mut hash: u64 = 0xcbf29ce484222325;
+        let mut write = |bytes: &[u8]| {
+            for byte in bytes {
+                hash ^= *byte as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+        };
+
+        for path in paths {
+            let relative = path.strip_prefix(&root).unwrap();
+
+            for component in relative.components() {
+                write(component.as_os_str().to_string_lossy().as_bytes());
+            }
+
+            match std::fs::read(&path) {
+                Ok(bytes) => {
+                    // line endings might differ depending on the platform it was checked out on
+                    let bytes: Vec<u8> = bytes.into_iter().filter(|b| *b != b'\r').collect();
+                    write(&bytes);
+                }
+                Err(err) => error!("Failed to hash {:?}: {}", path, err),
+            }
+        }
+
+        Self(hash)
+    }
+}
+
+// same as where bevy looks for the asset folder
+fn asset_root() -> PathBuf {
+    if let Ok(manifest_dir) = std::env::var("CARGO_MANIFEST_DIR") {
+        PathBuf::from(manifest_dir)
+    } else {
+        std::env::current_exe()
+            .ok()
+            .and_then(|path| path.parent().map(|path| path.to_path_buf()))
+            .unwrap_or_default()
+    }
+}
+
+fn collect_gameplay_assets(path: &Path, paths: &mut Vec<PathBuf>) {
+    let entries = match std::fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+
+        if path.is_dir() {
+            collect_gameplay_assets(&path, paths);
+        } else if let Some(extension) = path.extension().and_then(|e| e.to_str()) {
+            if GAMEPLAY_ASSET_EXTENSIONS.contains(&extension) {
+                paths.push(path);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum ConnectionStatus {
+    Connecting,
+    Connected(PlayerId),
+    Spectating,
+    Rejected(String),
+}
+
+impl Default for ConnectionStatus {
+    fn default() -> Self {
+        ConnectionStatus::Connecting
+    }
+}
+
+/// Identifies the client across reconnects, so it can reclaim its player.
+pub struct Session(pub u64);
+
+/// Whether the client connects as a spectator, which doesn't take a slot in the match.
+#[derive(Default)]
+pub struct SpectatorRequest(pub bool);
+
+/// Sent on the server when a connection has passed the handshake.
+pub struct HandshakeEvent {
+    pub handle: ConnectionHandle,
+    pub session: u64,
+    pub spectator: bool,
+}
+
+pub struct ConnectionStatusText;
+
+fn client_connection_status_setup(commands: &mut Commands, asset_server: Res<AssetServer>) {
+    commands
+        .spawn(TextBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: Rect {
+                    top: Val::Px(16.0),
+                    left: Val::Px(16.0),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            text: Text {
+                value: String::new(),
+                font: asset_server.load("fonts/DejaVuSans.ttf"),
+                style: TextStyle {
+                    font_size: 24.0,
+                    color: Color::WHITE,
+                    ..Default::default()
+                },
+            },
+            ..Default::default()
+        })
+        .with(ConnectionStatusText);
+}
+
+fn client_connection_status_system(
+    connection_status: Res<ConnectionStatus>,
+    mut query: Query<&mut Text, With<ConnectionStatusText>>,
+) {
+    let value = match &*connection_status {
+        ConnectionStatus::Connecting => "Connecting...".to_string(),
+        ConnectionStatus::Connected(_) | ConnectionStatus::Spectating => String::new(),
+        ConnectionStatus::Rejected(reason) => format!("Rejected by server: {}", reason),
+    };
+
+    for mut text in query.iter_mut() {
+        // only touch the text when it differs, so it isn't laid out again every frame
+        if text.value != value {
+            text.value = value.clone();
+        }
+    }
 }
 
 fn client_connection_system(
     mut timer: Local<Timer>,
     time: Res<Time>,
-    mut net: ResMut<NetworkResource>,
+    content_hash: Res<ContentHash>,
+    session: Res<Session>,
+    spectator_request: Res<SpectatorRequest>,
+    mut net: ResMut<Network>,
+    mut reader: Local<EventReader<NetworkEvent>>,
+    events: Res<Events<NetworkEvent>>,
+    mut connection_status: ResMut<ConnectionStatus>,
     mut player_id: ResMut<Option<PlayerId>>,
+    mut simulation_mode: ResMut<SimulationMode>,
+    mut fixed_tick: ResMut<FixedTick>,
 ) {
+    for event in reader.iter(&events) {
+        if let NetworkEvent::Disconnected(_) = event {
+            warn!("Lost connection to the server");
+
+            // the handshake is repeated once we are connected again
+            if let ConnectionStatus::Connected(_) | ConnectionStatus::Spectating =
+                &*connection_status
+            {
+                *connection_status = ConnectionStatus::Connecting;
+            }
+        }
+    }
+
     timer.set_duration(1.0);
     timer.set_repeating(true);
     timer.tick(time.delta_seconds());
 
+    // keep asking until the server has made up its mind
     if timer.just_finished() {
-        net.broadcast_message(ConnectionMessage::Client);
+        if let ConnectionStatus::Connecting = &*connection_status {
+            net.broadcast_message(ConnectionMessage::Client {
+                protocol_version: PROTOCOL_VERSION,
+                content_hash: content_hash.0,
+                session: session.0,
+                spectator: spectator_request.0,
+            });
+        }
     }
 
-    for (_handle, connection) in net.connections.iter_mut() {
-        let channels = connection.channels().unwrap();
+    for (_handle, connection_message) in net.recv::<ConnectionMessage>() {
+        match connection_message {
+            ConnectionMessage::Client { .. } => {
+                error!("Server connected with {:?}", connection_message);
+            }
+            ConnectionMessage::Server(id, mode, tick_rate) => {
+                info!("Server responded and assigned player_id: {:?}", id);
+                info!(
+                    "Server is running in {:?} mode at {} ticks",
+                    mode, tick_rate
+                );
 
-        while let Some(connection_message) = channels.recv::<ConnectionMessage>() {
-            match connection_message {
-                ConnectionMessage::Client => {
-                    error!("Server connected with {:?}", connection_message);
-                }
-                ConnectionMessage::Server(id) => {
-                    info!("Server responded and assigned player_id: {:?}", id);
+                *player_id = Some(id);
+                *simulation_mode = mode;
+                fixed_tick.set_tick_rate(tick_rate);
+                *connection_status = ConnectionStatus::Connected(id);
+            }
+            ConnectionMessage::Spectator(mode, tick_rate) => {
+                info!("Spectating a match running in {:?} mode", mode);
 
-                    *player_id = Some(id);
-                }
+                *simulation_mode = mode;
+                fixed_tick.set_tick_rate(tick_rate);
+                *connection_status = ConnectionStatus::Spectating;
+            }
+            ConnectionMessage::Rejected(reason) => {
+                error!("Server rejected the connection: {}", reason);
+
+                *connection_status = ConnectionStatus::Rejected(reason);
             }
         }
     }
 }
 
-fn server_connection_system(mut net: ResMut<NetworkResource>) {
-    for (_handle, connection) in net.connections.iter_mut() {
-        let channels = connection.channels().unwrap();
+fn server_connection_system(
+    content_hash: Res<ContentHash>,
+    players: Res<Players>,
+    mut rejections: ResMut<Rejections>,
+    mut net: ResMut<Network>,
+    mut handshake_events: ResMut<Events<HandshakeEvent>>,
+) {
+    for (handle, connection_message) in net.recv::<ConnectionMessage>() {
+        match connection_message {
+            ConnectionMessage::Client {
+                protocol_version,
+                content_hash: client_content_hash,
+                session,
+                spectator,
+            } => {
+                // the client repeats the handshake until it gets an answer
+                if players.player_ids.contains_key(&handle) || players.spectators.contains(&handle)
+                {
+                    continue;
+                }
+
+                if protocol_version != PROTOCOL_VERSION {
+                    let reason = format!(
+                        "protocol version mismatch, server has {} and client has {}",
+                        PROTOCOL_VERSION, protocol_version
+                    );
+                    warn!("rejected {}: {}", handle, reason);
+                    net.send_message(handle, ConnectionMessage::Rejected(reason));
+                    net.disconnect(handle);
+                    continue;
+                }
 
-        while let Some(connection_message) = channels.recv::<ConnectionMessage>() {
-            match connection_message {
-                ConnectionMessage::Client => {}
-                ConnectionMessage::Server(_) => {
-                    error!("Client connected with {:?}", connection_message);
+                if client_content_hash != content_hash.0 {
+                    let reason = format!(
+                        "game content mismatch, server has {:016x} and client has {:016x}",
+                        content_hash.0, client_content_hash
+                    );
+                    warn!("rejected {}: {}", handle, reason);
+                    net.send_message(handle, ConnectionMessage::Rejected(reason));
+                    net.disconnect(handle);
+                    continue;
                 }
+
+                handshake_events.send(HandshakeEvent {
+                    handle,
+                    session,
+                    spectator,
+                });
+            }
+            ConnectionMessage::Server(..)
+            | ConnectionMessage::Spectator(..)
+            | ConnectionMessage::Rejected(_) => {
+                let rejection = Rejection::UnexpectedMessage(format!("{:?}", connection_message));
+                rejections.reject(&mut net, handle, rejection);
             }
         }
     }
@@ -87,11 +354,18 @@ impl ConnectionPlugin {
 
 impl Plugin for ConnectionPlugin {
     fn build(&self, app_builder: &mut AppBuilder) {
+        app_builder.add_resource(ContentHash::from_asset_folder());
+
         if self.0 {
             app_builder.init_resource::<Players>();
+            app_builder.add_event::<HandshakeEvent>();
             app_builder.add_system(server_connection_system.system());
         } else {
+            app_builder.add_resource(Session(rand::random()));
+            app_builder.init_resource::<ConnectionStatus>();
+            app_builder.add_startup_system(client_connection_status_setup.system());
             app_builder.add_system(client_connection_system.system());
+            app_builder.add_system(client_connection_status_system.system());
         }
     }
 }
@@ -0,0 +1,397 @@
+use crate::*;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+
+// how often ai controlled units look for something to do
+const AI_THINK_INTERVAL: f32 = 1.0;
+// how far ai controlled units look for enemies
+const AI_AGGRO_RANGE: f32 = 12.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AbandonAction {
+    /// An ai takes control of the player's units.
+    Ai,
+    /// The player's units are removed from the match.
+    Remove,
+}
+
+impl FromStr for AbandonAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ai" => Ok(AbandonAction::Ai),
+            "remove" => Ok(AbandonAction::Remove),
+            _ => Err(format!("unknown abandon action '{}'", s)),
+        }
+    }
+}
+
+/// How the server handles players disconnecting during a match.
+#[derive(Clone, Copy, Debug)]
+pub struct DisconnectSettings {
+    pub grace_period: f32,
+    pub pause: bool,
+    pub abandon_action: AbandonAction,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum MatchEventMessage {
+    PlayerDisconnected {
+        player_id: PlayerId,
+        grace_period: f32,
+    },
+    PlayerReconnected(PlayerId),
+    PlayerAbandoned(PlayerId, AbandonAction),
+    Paused(bool),
+    /// The server ended the match and is shutting down.
+    Ended,
+}
+
+struct DisconnectedPlayer {
+    session: u64,
+    remaining: f32,
+}
+
+/// Keeps the slots of players who dropped during a match, until they reconnect or give up.
+#[derive(Default)]
+pub struct Disconnects {
+    sessions: HashMap<ConnectionHandle, u64>,
+    kicked: HashSet<ConnectionHandle>,
+    disconnected: HashMap<PlayerId, DisconnectedPlayer>,
+    abandoned: HashSet<PlayerId>,
+    paused: bool,
+    admin_paused: bool,
+}
+
+impl Disconnects {
+    pub fn connect(&mut self, handle: ConnectionHandle, session: u64) {
+        self.sessions.insert(handle, session);
+    }
+
+    /// Starts the grace period of the player, returns false if the session was unknown.
+    pub fn disconnect(
+        &mut self,
+        handle: ConnectionHandle,
+        player_id: PlayerId,
+        grace_period: f32,
+    ) -> bool {
+        match self.sessions.remove(&handle) {
+            Some(session) => {
+                self.disconnected.insert(
+                    player_id,
+                    DisconnectedPlayer {
+                        session,
+                        remaining: grace_period,
+                    },
+                );
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn forget(&mut self, handle: ConnectionHandle) {
+        self.sessions.remove(&handle);
+    }
+
+    /// Forgets the connection, so its player can't reclaim the slot after the disconnect.
+    pub fn kick(&mut self, handle: ConnectionHandle) {
+        self.sessions.remove(&handle);
+        self.kicked.insert(handle);
+    }
+
+    /// Whether the connection was kicked, only true once.
+    pub fn take_kicked(&mut self, handle: ConnectionHandle) -> bool {
+        self.kicked.remove(&handle)
+    }
+
+    /// Gives a reconnecting client back the player it had before.
+    pub fn reclaim(&mut self, session: u64) -> Option<PlayerId> {
+        let player_id = self
+            .disconnected
+            .iter()
+            .find(|(_, disconnected)| disconnected.session == session)
+            .map(|(player_id, _)| *player_id)?;
+
+        self.disconnected.remove(&player_id);
+        Some(player_id)
+    }
+
+    /// Whether the slot is still held by a player that left.
+    pub fn is_reserved(&self, player_id: PlayerId) -> bool {
+        self.disconnected.contains_key(&player_id) || self.abandoned.contains(&player_id)
+    }
+
+    /// The players whose slots are held while they're gone.
+    pub fn reserved(&self) -> impl Iterator<Item = &PlayerId> {
+        self.disconnected.keys().chain(self.abandoned.iter())
+    }
+
+    pub fn is_ai(&self, player_id: PlayerId) -> bool {
+        self.abandoned.contains(&player_id)
+    }
+
+    pub fn has_disconnected(&self) -> bool {
+        !self.disconnected.is_empty()
+    }
+
+    /// Frees the slots of everyone who left, once the match is over.
+    pub fn reset(&mut self) {
+        self.disconnected.clear();
+        self.abandoned.clear();
+    }
+
+    /// Keeps the match paused until resumed, whoever is connected.
+    pub fn set_admin_paused(&mut self, paused: bool) {
+        self.admin_paused = paused;
+    }
+}
+
+fn server_disconnect_system(
+    time: Res<Time>,
+    settings: Res<DisconnectSettings>,
+    simulation_mode: Res<SimulationMode>,
+    mut disconnects: ResMut<Disconnects>,
+    mut paused: ResMut<SimulationPaused>,
+    mut lockstep: ResMut<Lockstep>,
+    mut replay_recorder: ResMut<ReplayRecorder>,
+    fixed_tick: Res<FixedTick>,
+    mut net: ResMut<Network>,
+    mut query: Query<(&Owner, &mut UnitInstance)>,
+) {
+    let mut abandoned = Vec::new();
+
+    for (player_id, disconnected) in disconnects.disconnected.iter_mut() {
+        disconnected.remaining -= time.delta_seconds();
+
+        if disconnected.remaining <= 0.0 {
+            abandoned.push(*player_id);
+        }
+    }
+
+    for player_id in abandoned {
+        info!(
+            "{:?} did not return in time, {:?}",
+            player_id, settings.abandon_action
+        );
+
+        disconnects.disconnected.remove(&player_id);
+        disconnects.abandoned.insert(player_id);
+
+        if settings.abandon_action == AbandonAction::Remove {
+            // every peer has to remove them at the same time in lockstep
+            if simulation_mode.is_lockstep() {
+                lockstep.remove_player(player_id);
+            } else {
+                for (owner, mut unit_instance) in query.iter_mut() {
+                    if owner.0 == player_id {
+                        unit_instance.set_health(0.0);
+                    }
+                }
+
+                replay_recorder.record(fixed_tick.tick(), RecordedAction::RemovePlayer(player_id));
+            }
+        }
+
+        net.broadcast_message(MatchEventMessage::PlayerAbandoned(
+            player_id,
+            settings.abandon_action,
+        ));
+    }
+
+    let should_pause =
+        disconnects.admin_paused || (settings.pause && disconnects.has_disconnected());
+
+    if should_pause != disconnects.paused {
+        info!("match paused: {}", should_pause);
+
+        disconnects.paused = should_pause;
+        net.broadcast_message(MatchEventMessage::Paused(should_pause));
+    }
+
+    paused.0 = disconnects.paused;
+}
+
+/// Keeps the units of abandoned players fighting, by attacking whatever comes close.
+fn server_ai_system(
+    mut timer: Local<Timer>,
+    time: Res<Time>,
+    settings: Res<DisconnectSettings>,
+    simulation_mode: Res<SimulationMode>,
+    disconnects: Res<Disconnects>,
+    mut lockstep: ResMut<Lockstep>,
+    mut replay_recorder: ResMut<ReplayRecorder>,
+    fixed_tick: Res<FixedTick>,
+    teams: Res<Teams>,
+    targets: Query<(&NetworkEntity, &Position, &Owner), With<UnitInstance>>,
+    mut query: Query<(&NetworkEntity, &Position, &Owner, &mut CommandQueue)>,
+) {
+    if settings.abandon_action != AbandonAction::Ai || disconnects.abandoned.is_empty() {
+        return;
+    }
+
+    timer.set_duration(AI_THINK_INTERVAL);
+    timer.set_repeating(true);
+    timer.tick(time.delta_seconds());
+
+    if !timer.just_finished() {
+        return;
+    }
+
+    for (network_entity, position, owner, mut command_queue) in query.iter_mut() {
+        if !disconnects.is_ai(owner.0) || !command_queue.commands.is_empty() {
+            continue;
+        }
+
+        let target = targets
+            .iter()
+            .filter(|(_, _, target_owner)| teams.is_hostile(owner.0, target_owner.0))
+            .map(|(target, target_position, _)| {
+                let distance = (target_position.position - position.position).length();
+                (target, distance)
+            })
+            .filter(|(_, distance)| *distance < AI_AGGRO_RANGE)
+            .min_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap());
+
+        if let Some((target, _)) = target {
+            let command_message = CommandMessage {
+                operation: CommandQueueOperation::SetCommand(Box::new(AttackUnitCommand {
+                    target: *target,
+                })),
+                network_entity: *network_entity,
+            };
+
+            // goes through the turns like any player command in lockstep
+            if simulation_mode.is_lockstep() {
+                lockstep.queue(command_message);
+            } else {
+                command_queue.apply(command_message.operation.clone());
+                replay_recorder.record(fixed_tick.tick(), RecordedAction::Command(command_message));
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct MatchNotice {
+    pub text: String,
+    pub remaining: f32,
+    pub paused: bool,
+}
+
+pub struct MatchNoticeText;
+
+fn client_match_notice_setup(commands: &mut Commands, asset_server: Res<AssetServer>) {
+    commands
+        .spawn(TextBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: Rect {
+                    top: Val::Px(16.0),
+                    left: Val::Percent(40.0),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            text: Text {
+                value: String::new(),
+                font: asset_server.load("fonts/DejaVuSans.ttf"),
+                style: TextStyle {
+                    font_size: 24.0,
+                    color: Color::WHITE,
+                    ..Default::default()
+                },
+            },
+            ..Default::default()
+        })
+        .with(MatchNoticeText);
+}
+
+fn client_match_event_system(
+    time: Res<Time>,
+    mut net: ResMut<Network>,
+    mut match_notice: ResMut<MatchNotice>,
+    mut query: Query<&mut Text, With<MatchNoticeText>>,
+) {
+    for (_handle, match_event) in net.recv::<MatchEventMessage>() {
+        info!("{:?}", match_event);
+
+        let text = match match_event {
+            MatchEventMessage::PlayerDisconnected {
+                player_id,
+                grace_period,
+            } => format!(
+                "Player {} disconnected, waiting {:.0} seconds for them to return",
+                player_id.0 + 1,
+                grace_period
+            ),
+            MatchEventMessage::PlayerReconnected(player_id) => {
+                format!("Player {} reconnected", player_id.0 + 1)
+            }
+            MatchEventMessage::PlayerAbandoned(player_id, AbandonAction::Ai) => {
+                format!("Player {} left, an ai took over", player_id.0 + 1)
+            }
+            MatchEventMessage::PlayerAbandoned(player_id, AbandonAction::Remove) => {
+                format!("Player {} left, their units were removed", player_id.0 + 1)
+            }
+            MatchEventMessage::Paused(paused) => {
+                match_notice.paused = paused;
+                continue;
+            }
+            MatchEventMessage::Ended => {
+                // stays up, there's nothing coming after it
+                match_notice.text = "The server ended the match".to_string();
+                match_notice.remaining = f32::INFINITY;
+                continue;
+            }
+        };
+
+        match_notice.text = text;
+        match_notice.remaining = 5.0;
+    }
+
+    match_notice.remaining -= time.delta_seconds();
+
+    let mut value = if match_notice.remaining > 0.0 {
+        match_notice.text.clone()
+    } else {
+        String::new()
+    };
+
+    if match_notice.paused {
+        value = format!("Paused\n{}", value);
+    }
+
+    for mut text in query.iter_mut() {
+        if text.value != value {
+            text.value = value.clone();
+        }
+    }
+}
+
+pub struct DisconnectPlugin(pub bool);
+
+impl DisconnectPlugin {
+    pub fn server() -> Self {
+        Self(true)
+    }
+
+    pub fn client() -> Self {
+        Self(false)
+    }
+}
+
+impl Plugin for DisconnectPlugin {
+    fn build(&self, app_builder: &mut AppBuilder) {
+        if self.0 {
+            app_builder.init_resource::<Disconnects>();
+            app_builder.add_system(server_disconnect_system.system());
+            app_builder.add_system(server_ai_system.system());
+        } else {
+            app_builder.init_resource::<MatchNotice>();
+            app_builder.add_startup_system(client_match_notice_setup.system());
+            app_builder.add_system(client_match_event_system.system());
+        }
+    }
+}
@@ -0,0 +1,226 @@
+use crate::*;
+use std::collections::HashMap;
+
+/// What a unit can gather, declared in its `.unit` file.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Harvester {
+    /// How much it carries back to a drop-off at once.
+    pub capacity: f32,
+    /// How much it gathers per second.
+    pub rate: f32,
+    /// How close it has to get to a node or drop-off.
+    pub range: f32,
+}
+
+/// Something harvesters gather from, until it runs out.
+pub struct ResourceNode {
+    pub amount: f32,
+    pub size: f32,
+}
+
+/// Where harvesters bring their cargo to fill up their owner's stockpile.
+pub struct DropOff {
+    pub size: f32,
+}
+
+/// What a harvester is carrying.
+#[derive(Default)]
+pub struct Cargo {
+    pub amount: f32,
+}
+
+/// What commands can see of the economy, the harvester itself included.
+pub type EconomyQuery<'a> = Query<
+    'a,
+    (
+        &'a Position,
+        Option<&'a Owner>,
+        Option<&'a Cargo>,
+        Option<&'a ResourceNode>,
+        Option<&'a DropOff>,
+    ),
+>;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StockpileMessage {
+    pub amounts: HashMap<PlayerId, f32>,
+}
+
+/// The resources every player has gathered and not spent yet.
+#[derive(Default)]
+pub struct Stockpiles {
+    amounts: HashMap<PlayerId, f32>,
+    changed: bool,
+    reset: bool,
+}
+
+impl Stockpiles {
+    pub fn get(&self, player_id: PlayerId) -> f32 {
+        self.amounts.get(&player_id).cloned().unwrap_or(0.0)
+    }
+
+    pub fn add(&mut self, player_id: PlayerId, amount: f32) {
+        *self.amounts.entry(player_id).or_insert(0.0) += amount;
+        self.changed = true;
+    }
+
+    pub fn spend(&mut self, player_id: PlayerId, amount: f32) {
+        self.add(player_id, -amount);
+    }
+
+    /// Replaces every stockpile, at the start of a match or when one is loaded.
+    pub fn reset(&mut self, amounts: HashMap<PlayerId, f32>) {
+        self.amounts = amounts;
+        self.changed = true;
+        self.reset = true;
+    }
+
+    pub fn amounts(&self) -> &HashMap<PlayerId, f32> {
+        &self.amounts
+    }
+}
+
+fn server_stockpile_system(
+    mut reader: Local<EventReader<SynchronizeEvent>>,
+    events: Res<Events<SynchronizeEvent>>,
+    simulation_mode: Res<SimulationMode>,
+    mut stockpiles: ResMut<Stockpiles>,
+    mut net: ResMut<Network>,
+) {
+    for SynchronizeEvent(handle) in reader.iter(&events) {
+        net.send_message(
+            *handle,
+            StockpileMessage {
+                amounts: stockpiles.amounts.clone(),
+            },
+        );
+    }
+
+    if !stockpiles.changed {
+        return;
+    }
+
+    // lockstep peers gather on their own, they only need to know where they start
+    if simulation_mode.is_authoritative() || stockpiles.reset {
+        net.broadcast_message(StockpileMessage {
+            amounts: stockpiles.amounts.clone(),
+        });
+    }
+
+    stockpiles.changed = false;
+    stockpiles.reset = false;
+}
+
+// the registry is cleaned up by the despawn system, which also tells the clients
+fn server_resource_node_system(commands: &mut Commands, query: Query<(Entity, &ResourceNode)>) {
+    for (entity, resource_node) in query.iter() {
+        if resource_node.amount <= 0.0 {
+            commands.despawn_recursive(entity);
+        }
+    }
+}
+
+fn client_resource_node_system(
+    commands: &mut Commands,
+    mut network_entity_registry: ResMut<NetworkEntityRegistry>,
+    query: Query<(Entity, &ResourceNode, &NetworkEntity)>,
+) {
+    for (entity, resource_node, network_entity) in query.iter() {
+        if resource_node.amount <= 0.0 {
+            network_entity_registry.remove(&network_entity);
+            commands.despawn_recursive(entity);
+        }
+    }
+}
+
+pub struct StockpileText;
+
+fn client_stockpile_setup(commands: &mut Commands, asset_server: Res<AssetServer>) {
+    commands
+        .spawn(TextBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: Rect {
+                    bottom: Val::Px(16.0),
+                    right: Val::Px(16.0),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            text: Text {
+                value: String::new(),
+                font: asset_server.load("fonts/DejaVuSans.ttf"),
+                style: TextStyle {
+                    font_size: 20.0,
+                    color: Color::WHITE,
+                    ..Default::default()
+                },
+            },
+            ..Default::default()
+        })
+        .with(StockpileText);
+}
+
+fn client_stockpile_system(
+    mut net: ResMut<Network>,
+    mut stockpiles: ResMut<Stockpiles>,
+    lobby_state: Res<LobbyState>,
+    player_id: Res<Option<PlayerId>>,
+    mut query: Query<&mut Text, With<StockpileText>>,
+) {
+    for (_handle, stockpile_message) in net.recv::<StockpileMessage>() {
+        stockpiles.amounts = stockpile_message.amounts;
+    }
+
+    let value = match *player_id {
+        _ if !lobby_state.started => String::new(),
+        Some(player_id) => format!("Resources: {:.0}", stockpiles.get(player_id)),
+        // spectators see everyone's
+        None => {
+            let mut amounts: Vec<(&PlayerId, &f32)> = stockpiles.amounts.iter().collect();
+            amounts.sort_by_key(|(player_id, _)| **player_id);
+
+            amounts
+                .iter()
+                .map(|(player_id, amount)| format!("Player {}: {:.0}", player_id.0 + 1, amount))
+                .collect::<Vec<String>>()
+                .join("\n")
+        }
+    };
+
+    for mut text in query.iter_mut() {
+        if text.value != value {
+            text.value = value.clone();
+        }
+    }
+}
+
+pub struct EconomyPlugin(pub bool);
+
+impl EconomyPlugin {
+    pub fn server() -> Self {
+        Self(true)
+    }
+
+    pub fn client() -> Self {
+        Self(false)
+    }
+}
+
+impl Plugin for EconomyPlugin {
+    fn build(&self, app_builder: &mut AppBuilder) {
+        app_builder.init_resource::<Stockpiles>();
+
+        if self.0 {
+            app_builder.add_system_to_stage(SIMULATION_STAGE, server_resource_node_system.system());
+            app_builder
+                .add_system_to_stage(bevy::app::stage::LAST, server_stockpile_system.system());
+        } else {
+            // only runs in lockstep, where the client simulates everything itself
+            app_builder.add_system_to_stage(SIMULATION_STAGE, client_resource_node_system.system());
+
+            app_builder.add_startup_system(client_stockpile_setup.system());
+            app_builder.add_system(client_stockpile_system.system());
+        }
+    }
+}
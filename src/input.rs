@@ -1,7 +1,7 @@
 use crate::*;
 use bevy::prelude::*;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug)]
 pub enum InputType {
     Keyboard(KeyCode),
     Mouse(MouseButton),
@@ -37,6 +37,25 @@ pub struct InputConfig {
     pub add_to_selection: InputType,
     pub queue_actions: InputType,
     pub move_command: InputType,
+    pub toggle_network_stats: InputType,
+    pub toggle_ready: InputType,
+    pub next_slot: InputType,
+    pub follow_next_player: InputType,
+    pub chat: InputType,
+    pub team_chat: InputType,
+    pub replay_pause: InputType,
+    pub replay_faster: InputType,
+    pub replay_slower: InputType,
+    pub replay_forward: InputType,
+    pub replay_backward: InputType,
+    /// Trains the building's productions, in order.
+    pub train: Vec<InputType>,
+    pub cancel_training: InputType,
+    /// Places the selected workers' buildings, in order.
+    pub build: Vec<InputType>,
+    pub cancel_placement: InputType,
+    /// Researches the building's upgrades, in order.
+    pub research: Vec<InputType>,
     pub camera_scroll_speed: f32,
 }
 
@@ -0,0 +1,97 @@
+pub mod admin;
+pub mod animation;
+pub mod asset_loading;
+pub mod bar;
+pub mod behaviour;
+pub mod building;
+pub mod building_spawnable;
+pub mod chat;
+pub mod client;
+pub mod command;
+pub mod connection;
+pub mod disconnect;
+pub mod economy;
+pub mod input;
+pub mod isometric;
+pub mod lobby;
+pub mod lockstep;
+pub mod map;
+pub mod mouse_position;
+pub mod navigation;
+pub mod network;
+pub mod network_stats;
+pub mod placement;
+pub mod position;
+pub mod replay;
+pub mod resource_node_spawnable;
+pub mod robots;
+pub mod save;
+pub mod selection;
+pub mod server;
+pub mod simulation;
+pub mod size;
+pub mod spectator;
+pub mod spawnable;
+pub mod sprite_shader;
+pub mod team;
+pub mod tile_map;
+pub mod tile_map_spawnable;
+pub mod transport;
+pub mod unit;
+pub mod unit_animation;
+pub mod unit_spawnable;
+pub mod upgrade;
+pub mod validation;
+pub mod victory;
+
+pub use bar::*;
+pub use behaviour::*;
+pub use building::*;
+pub use building_spawnable::*;
+pub use chat::*;
+pub use command::*;
+pub use map::*;
+pub use sprite_shader::*;
+pub use tile_map_spawnable::*;
+pub use unit_animation::*;
+pub use unit_spawnable::*;
+#[macro_use]
+pub use asset_loading::*;
+pub use admin::*;
+pub use animation::*;
+pub use bevy::prelude::*;
+pub use bevy_networking_turbulence::*;
+use clap::Clap;
+pub use client::*;
+pub use connection::*;
+pub use disconnect::*;
+pub use economy::*;
+pub use input::*;
+pub use isometric::*;
+pub use lobby::*;
+pub use lockstep::*;
+pub use mouse_position::*;
+pub use navigation::*;
+pub use network::*;
+pub use network_stats::*;
+pub use placement::*;
+pub use position::*;
+pub use rand::prelude::*;
+pub use replay::*;
+pub use resource_node_spawnable::*;
+pub use robots::*;
+pub use save::*;
+pub use selection::*;
+pub use serde::{Deserialize, Serialize};
+pub use server::*;
+pub use simulation::*;
+pub use size::*;
+pub use spectator::*;
+pub use spawnable::*;
+pub use team::*;
+pub use tile_map::*;
+pub use transport::*;
+pub use unit::*;
+pub use upgrade::*;
+pub use validation::*;
+pub use victory::*;
@@ -0,0 +1,426 @@
+use crate::*;
+use std::collections::HashMap;
+
+const MAX_NAME_LENGTH: usize = 24;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum LobbyMessage {
+    SetName(String),
+    SelectSlot(PlayerId),
+    SetReady(bool),
+    State(LobbyState),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LobbyPlayer {
+    pub name: String,
+    pub ready: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LobbySlot {
+    pub player_id: PlayerId,
+    pub team: u32,
+    pub player: Option<LobbyPlayer>,
+}
+
+/// What the clients know about the lobby.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct LobbyState {
+    pub slots: Vec<LobbySlot>,
+    pub started: bool,
+}
+
+impl LobbyState {
+    pub fn slot(&self, player_id: PlayerId) -> Option<&LobbySlot> {
+        self.slots.iter().find(|slot| slot.player_id == player_id)
+    }
+
+    pub fn teams(&self) -> Teams {
+        let mut teams = Teams::default();
+
+        for slot in &self.slots {
+            teams.insert(slot.player_id, slot.team);
+        }
+
+        teams
+    }
+}
+
+/// The name the client shows up with in the lobby.
+pub struct PlayerName(pub String);
+
+/// The players in the lobby on the server, the match starts once all of them are ready.
+#[derive(Default)]
+pub struct Lobby {
+    players: HashMap<PlayerId, LobbyPlayer>,
+    changed: bool,
+}
+
+impl Lobby {
+    pub fn join(&mut self, player_id: PlayerId) {
+        self.players.insert(
+            player_id,
+            LobbyPlayer {
+                name: format!("Player {}", player_id.0 + 1),
+                ready: false,
+            },
+        );
+        self.changed = true;
+    }
+
+    pub fn leave(&mut self, player_id: PlayerId) {
+        self.players.remove(&player_id);
+        self.changed = true;
+    }
+
+    pub fn get(&self, player_id: PlayerId) -> Option<&LobbyPlayer> {
+        self.players.get(&player_id)
+    }
+
+    pub fn player_ids(&self) -> impl Iterator<Item = &PlayerId> {
+        self.players.keys()
+    }
+
+    /// Gets the lobby ready for another match, with whoever is still connected.
+    pub fn reset(&mut self, players: &Players) {
+        self.players
+            .retain(|player_id, _| players.connection_handles.contains_key(player_id));
+
+        for player in self.players.values_mut() {
+            player.ready = false;
+        }
+
+        self.changed = true;
+    }
+
+    pub fn all_ready(&self, map: &Map) -> bool {
+        map.players.iter().all(|player_id| {
+            self.players
+                .get(player_id)
+                .map_or(false, |player| player.ready)
+        })
+    }
+
+    pub fn state(&self, map: &Map, started: bool) -> LobbyState {
+        let teams = Teams::from_map(map);
+        let mut slots: Vec<LobbySlot> = map
+            .players
+            .iter()
+            .map(|player_id| LobbySlot {
+                player_id: *player_id,
+                team: teams.team(*player_id),
+                player: self.players.get(player_id).cloned(),
+            })
+            .collect();
+
+        slots.sort_by_key(|slot| slot.player_id.0);
+
+        LobbyState { slots, started }
+    }
+}
+
+fn server_lobby_system(
+    mut net: ResMut<Network>,
+    mut rejections: ResMut<Rejections>,
+    mut lobby: ResMut<Lobby>,
+    mut players: ResMut<Players>,
+    mut match_state: ResMut<MatchState>,
+    mut lockstep: ResMut<Lockstep>,
+    mut replay_recorder: ResMut<ReplayRecorder>,
+    mut loaded_match: ResMut<LoadedMatch>,
+    mut network_entity_registry: ResMut<NetworkEntityRegistry>,
+    mut stockpiles: ResMut<Stockpiles>,
+    mut upgrades: ResMut<Upgrades>,
+    simulation_mode: Res<SimulationMode>,
+    fixed_tick: Res<FixedTick>,
+    maps: Res<Assets<Map>>,
+    map_handle: Res<Handle<Map>>,
+    spawn_resource: Res<SpawnResource>,
+) {
+    let map = match maps.get(&*map_handle) {
+        Some(map) => map,
+        None => return,
+    };
+
+    for (handle, lobby_message) in net.recv::<LobbyMessage>() {
+        let player_id = match players.player_ids.get(&handle) {
+            Some(player_id) => *player_id,
+            None => {
+                rejections.reject(&mut net, handle, Rejection::NotAPlayer);
+                continue;
+            }
+        };
+
+        match lobby_message {
+            LobbyMessage::SetName(name) => {
+                let name: String = name.trim().chars().take(MAX_NAME_LENGTH).collect();
+
+                if let (Some(player), false) = (lobby.players.get_mut(&player_id), name.is_empty())
+                {
+                    player.name = name;
+                    lobby.changed = true;
+                }
+            }
+            // the slots are locked once the match is running
+            LobbyMessage::SelectSlot(_) | LobbyMessage::SetReady(_)
+                if *match_state == MatchState::Running =>
+            {
+                rejections.reject(&mut net, handle, Rejection::MatchRunning);
+            }
+            LobbyMessage::SelectSlot(slot) => {
+                if !map.players.contains(&slot) || players.connection_handles.contains_key(&slot) {
+                    rejections.reject(&mut net, handle, Rejection::InvalidSlot(slot));
+                    continue;
+                }
+
+                players.remove_connection(&handle);
+                players.insert(slot, handle);
+
+                if let Some(mut player) = lobby.players.remove(&player_id) {
+                    player.ready = false;
+                    lobby.players.insert(slot, player);
+                }
+                lobby.changed = true;
+
+                net.send_message(
+                    handle,
+                    ConnectionMessage::Server(slot, *simulation_mode, fixed_tick.tick_rate()),
+                );
+            }
+            LobbyMessage::SetReady(ready) => {
+                if let Some(player) = lobby.players.get_mut(&player_id) {
+                    player.ready = ready;
+                    lobby.changed = true;
+                }
+            }
+            LobbyMessage::State(_) => {
+                let rejection = Rejection::UnexpectedMessage("LobbyMessage::State".to_string());
+                rejections.reject(&mut net, handle, rejection);
+            }
+        }
+    }
+
+    if *match_state == MatchState::Waiting && map.all_connected(&players) && lobby.all_ready(map) {
+        match loaded_match.0.take() {
+            Some(saved_match) => {
+                info!("all players ready, resuming saved match");
+                saved_match.restore(
+                    &mut network_entity_registry,
+                    &spawn_resource,
+                    &mut stockpiles,
+                    &mut upgrades,
+                );
+            }
+            None => {
+                info!("all players ready, spawning map");
+                map.spawn(&players, &spawn_resource);
+
+                let player_ids: Vec<PlayerId> = players.player_ids.values().cloned().collect();
+                stockpiles.reset(map.starting_stockpiles(&player_ids));
+                upgrades.reset(HashMap::new(), HashMap::new());
+            }
+        }
+        lockstep.start();
+
+        let mut recorded_players: Vec<RecordedPlayer> = players
+            .player_ids
+            .values()
+            .map(|player_id| RecordedPlayer {
+                player_id: *player_id,
+                name: lobby
+                    .get(*player_id)
+                    .map_or_else(String::new, |player| player.name.clone()),
+            })
+            .collect();
+        recorded_players.sort_by_key(|player| player.player_id);
+        replay_recorder.start(recorded_players);
+
+        *match_state = MatchState::Running;
+        lobby.changed = true;
+    }
+
+    if lobby.changed {
+        let state = lobby.state(map, *match_state != MatchState::Waiting);
+        net.broadcast_message(LobbyMessage::State(state));
+        lobby.changed = false;
+    }
+}
+
+fn client_lobby_system(
+    mut name_sent: Local<bool>,
+    mut net: ResMut<Network>,
+    mut lobby_state: ResMut<LobbyState>,
+    mut teams: ResMut<Teams>,
+    player_name: Res<PlayerName>,
+    player_id: Res<Option<PlayerId>>,
+    keyboard_input: Res<Input<KeyCode>>,
+    mouse_input: Res<Input<MouseButton>>,
+    input_config: Res<Assets<InputConfig>>,
+    input_resource: Res<InputResource>,
+) {
+    for (_handle, lobby_message) in net.recv::<LobbyMessage>() {
+        match lobby_message {
+            LobbyMessage::State(state) => {
+                *teams = state.teams();
+                *lobby_state = state;
+            }
+            _ => error!("Server sent {:?}", lobby_message),
+        }
+    }
+
+    let player_id = match *player_id {
+        Some(player_id) => player_id,
+        None => return,
+    };
+
+    if !*name_sent {
+        net.broadcast_message(LobbyMessage::SetName(player_name.0.clone()));
+        *name_sent = true;
+    }
+
+    if lobby_state.started {
+        return;
+    }
+
+    let input_config = match input_config.get(&input_resource.0) {
+        Some(input_config) => input_config,
+        None => return,
+    };
+
+    if input_config
+        .toggle_ready
+        .just_pressed(&keyboard_input, &mouse_input)
+    {
+        let ready = lobby_state
+            .slot(player_id)
+            .and_then(|slot| slot.player.as_ref())
+            .map_or(false, |player| player.ready);
+
+        net.broadcast_message(LobbyMessage::SetReady(!ready));
+    }
+
+    if input_config
+        .next_slot
+        .just_pressed(&keyboard_input, &mouse_input)
+    {
+        let current = lobby_state
+            .slots
+            .iter()
+            .position(|slot| slot.player_id == player_id)
+            .unwrap_or(0);
+
+        // the first free slot after our own, wrapping around
+        let free_slot = (1..lobby_state.slots.len())
+            .map(|offset| &lobby_state.slots[(current + offset) % lobby_state.slots.len()])
+            .find(|slot| slot.player.is_none());
+
+        if let Some(slot) = free_slot {
+            net.broadcast_message(LobbyMessage::SelectSlot(slot.player_id));
+        }
+    }
+}
+
+pub struct LobbyText;
+
+fn client_lobby_screen_setup(commands: &mut Commands, asset_server: Res<AssetServer>) {
+    commands
+        .spawn(TextBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: Rect {
+                    top: Val::Px(64.0),
+                    left: Val::Px(64.0),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            text: Text {
+                value: String::new(),
+                font: asset_server.load("fonts/DejaVuSans.ttf"),
+                style: TextStyle {
+                    font_size: 24.0,
+                    color: Color::WHITE,
+                    ..Default::default()
+                },
+            },
+            ..Default::default()
+        })
+        .with(LobbyText);
+}
+
+fn client_lobby_screen_system(
+    lobby_state: Res<LobbyState>,
+    player_id: Res<Option<PlayerId>>,
+    input_config: Res<Assets<InputConfig>>,
+    input_resource: Res<InputResource>,
+    mut query: Query<&mut Text, With<LobbyText>>,
+) {
+    let input_config = input_config.get(&input_resource.0);
+
+    let value = match (*player_id, input_config) {
+        (Some(player_id), Some(input_config)) if !lobby_state.started => {
+            let mut lines = vec![format!(
+                "Lobby - {:?} to change slot, {:?} to toggle ready",
+                input_config.next_slot, input_config.toggle_ready
+            )];
+
+            for slot in &lobby_state.slots {
+                let player = match &slot.player {
+                    Some(player) if player.ready => format!("{} (ready)", player.name),
+                    Some(player) => player.name.clone(),
+                    None => "<empty>".to_string(),
+                };
+
+                let you = if slot.player_id == player_id {
+                    " <"
+                } else {
+                    ""
+                };
+
+                lines.push(format!(
+                    "Slot {} (team {}): {}{}",
+                    slot.player_id.0 + 1,
+                    slot.team + 1,
+                    player,
+                    you
+                ));
+            }
+
+            lines.join("\n")
+        }
+        _ => String::new(),
+    };
+
+    for mut text in query.iter_mut() {
+        if text.value != value {
+            text.value = value.clone();
+        }
+    }
+}
+
+pub struct LobbyPlugin(pub bool);
+
+impl LobbyPlugin {
+    pub fn server() -> Self {
+        Self(true)
+    }
+
+    pub fn client() -> Self {
+        Self(false)
+    }
+}
+
+impl Plugin for LobbyPlugin {
+    fn build(&self, app_builder: &mut AppBuilder) {
+        if self.0 {
+            app_builder.init_resource::<Lobby>();
+            app_builder.add_system(server_lobby_system.system());
+        } else {
+            app_builder.init_resource::<LobbyState>();
+            app_builder.add_startup_system(client_lobby_screen_setup.system());
+            app_builder.add_system(client_lobby_system.system());
+            app_builder.add_system(client_lobby_screen_system.system());
+        }
+    }
+}
@@ -0,0 +1,264 @@
+use crate::*;
+use bevy::ecs::ShouldRun;
+use std::collections::BTreeMap;
+
+pub const LOCKSTEP_TURN_TICKS: u64 = 6;
+// with more turns than this buffered, the simulation runs several ticks per frame to catch up
+const LOCKSTEP_MAX_BUFFERED_TURNS: usize = 2;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TurnMessage {
+    pub turn: u64,
+    pub commands: Vec<CommandMessage>,
+    pub productions: Vec<ProductionMessage>,
+    pub researches: Vec<ResearchMessage>,
+    /// Players whose units are removed at the start of the turn.
+    pub removed_players: Vec<PlayerId>,
+    /// How many entities the match starts with, sent with the first turn only.
+    pub entities: Option<usize>,
+}
+
+#[derive(Default)]
+pub struct Lockstep {
+    started: bool,
+    simulating: bool,
+    tick: u64,
+    next_turn: u64,
+    pending: Vec<CommandMessage>,
+    pending_productions: Vec<ProductionMessage>,
+    pending_researches: Vec<ResearchMessage>,
+    pending_removed_players: Vec<PlayerId>,
+    turns: BTreeMap<u64, TurnMessage>,
+}
+
+impl Lockstep {
+    pub fn start(&mut self) {
+        self.started = true;
+    }
+
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    pub fn turn(&self) -> u64 {
+        self.tick / LOCKSTEP_TURN_TICKS
+    }
+
+    pub fn is_turn_start(&self) -> bool {
+        self.tick % LOCKSTEP_TURN_TICKS == 0
+    }
+
+    /// Queues a command to be executed in the next turn sent out.
+    pub fn queue(&mut self, command_message: CommandMessage) {
+        self.pending.push(command_message);
+    }
+
+    /// Queues a production order to be applied in the next turn sent out.
+    pub fn queue_production(&mut self, production_message: ProductionMessage) {
+        self.pending_productions.push(production_message);
+    }
+
+    /// Queues a research order to be applied in the next turn sent out.
+    pub fn queue_research(&mut self, research_message: ResearchMessage) {
+        self.pending_researches.push(research_message);
+    }
+
+    /// Queues the removal of a player's units in the next turn sent out.
+    pub fn remove_player(&mut self, player_id: PlayerId) {
+        self.pending_removed_players.push(player_id);
+    }
+
+    pub fn insert_turn(&mut self, turn_message: TurnMessage) {
+        if turn_message.turn < self.turn() {
+            warn!(
+                "Recieved turn {} which was already simulated",
+                turn_message.turn
+            );
+            return;
+        }
+
+        self.turns.insert(turn_message.turn, turn_message);
+    }
+
+    /// Finishes the previous tick, and decides whether the next one can be simulated.
+    pub fn next_tick(&mut self, network_entity_registry: &NetworkEntityRegistry) -> ShouldRun {
+        if self.simulating {
+            self.simulating = false;
+            self.tick += 1;
+
+            if self.is_turn_start() {
+                let finished_turn = self.turn() - 1;
+                self.turns.remove(&finished_turn);
+            }
+        }
+
+        let turn = match self.turns.get(&self.turn()) {
+            Some(turn) => turn,
+            // waiting for the server to send the turn
+            None => return ShouldRun::No,
+        };
+
+        // spawns come on their own channel, and may be resent a few frames later
+        if let Some(entities) = turn.entities {
+            if network_entity_registry.iter().count() < entities {
+                return ShouldRun::No;
+            }
+        }
+
+        self.simulating = true;
+
+        if self.turns.len() > LOCKSTEP_MAX_BUFFERED_TURNS {
+            ShouldRun::YesAndLoop
+        } else {
+            ShouldRun::Yes
+        }
+    }
+}
+
+fn server_lockstep_turn_system(
+    mut timer: Local<Timer>,
+    time: Res<Time>,
+    simulation_mode: Res<SimulationMode>,
+    paused: Res<SimulationPaused>,
+    fixed_tick: Res<FixedTick>,
+    network_entity_registry: Res<NetworkEntityRegistry>,
+    mut net: ResMut<Network>,
+    mut lockstep: ResMut<Lockstep>,
+    mut replay_recorder: ResMut<ReplayRecorder>,
+) {
+    // without new turns every peer stops simulating
+    if !simulation_mode.is_lockstep() || !lockstep.started || paused.0 {
+        return;
+    }
+
+    // the peers simulate turns as fast as they come in, so this is the game speed
+    timer.set_duration(fixed_tick.tick_seconds() * LOCKSTEP_TURN_TICKS as f32 / fixed_tick.speed);
+    timer.set_repeating(true);
+    timer.tick(time.delta_seconds());
+
+    if timer.just_finished() {
+        let turn_message = TurnMessage {
+            turn: lockstep.next_turn,
+            commands: std::mem::replace(&mut lockstep.pending, Vec::new()),
+            productions: std::mem::replace(&mut lockstep.pending_productions, Vec::new()),
+            researches: std::mem::replace(&mut lockstep.pending_researches, Vec::new()),
+            removed_players: std::mem::replace(&mut lockstep.pending_removed_players, Vec::new()),
+            entities: if lockstep.next_turn == 0 {
+                Some(network_entity_registry.iter().count())
+            } else {
+                None
+            },
+        };
+
+        lockstep.next_turn += 1;
+        lockstep.insert_turn(turn_message.clone());
+
+        // the turn is executed on its first tick
+        let tick = turn_message.turn * LOCKSTEP_TURN_TICKS;
+
+        for player_id in &turn_message.removed_players {
+            replay_recorder.record(tick, RecordedAction::RemovePlayer(*player_id));
+        }
+
+        for command_message in &turn_message.commands {
+            replay_recorder.record(tick, RecordedAction::Command(command_message.clone()));
+        }
+
+        for production_message in &turn_message.productions {
+            replay_recorder.record(tick, RecordedAction::Production(production_message.clone()));
+        }
+
+        for research_message in &turn_message.researches {
+            replay_recorder.record(tick, RecordedAction::Research(research_message.clone()));
+        }
+
+        net.broadcast_message(turn_message);
+    }
+}
+
+fn client_lockstep_turn_system(mut net: ResMut<Network>, mut lockstep: ResMut<Lockstep>) {
+    for (_handle, turn_message) in net.recv::<TurnMessage>() {
+        lockstep.insert_turn(turn_message);
+    }
+}
+
+fn lockstep_command_system(
+    simulation_mode: Res<SimulationMode>,
+    lockstep: Res<Lockstep>,
+    network_entity_registry: Res<NetworkEntityRegistry>,
+    buildings: Res<Assets<Building>>,
+    upgrade_assets: Res<Assets<Upgrade>>,
+    mut stockpiles: ResMut<Stockpiles>,
+    mut upgrades: ResMut<Upgrades>,
+    mut query: Query<&mut CommandQueue>,
+    mut unit_query: Query<(&Owner, &mut UnitInstance)>,
+    mut building_query: Query<(&Owner, &Handle<Building>, &mut ProductionQueue)>,
+    research_query: Query<(&Owner, &Handle<Building>)>,
+) {
+    if !simulation_mode.is_lockstep() || !lockstep.is_turn_start() {
+        return;
+    }
+
+    if let Some(turn) = lockstep.turns.get(&lockstep.turn()) {
+        for (owner, mut unit_instance) in unit_query.iter_mut() {
+            if turn.removed_players.contains(&owner.0) {
+                unit_instance.set_health(0.0);
+            }
+        }
+
+        for command_message in &turn.commands {
+            // entities may have died since the command was sent, that's fine
+            if let Some(entity) = network_entity_registry.get(&command_message.network_entity) {
+                if let Ok(mut command_queue) = query.get_mut(*entity) {
+                    command_queue.apply(command_message.operation.clone());
+                }
+            }
+        }
+
+        for production_message in &turn.productions {
+            apply_production_message(
+                production_message,
+                &network_entity_registry,
+                &buildings,
+                &mut stockpiles,
+                &mut building_query,
+            );
+        }
+
+        for research_message in &turn.researches {
+            apply_research_message(
+                research_message,
+                &network_entity_registry,
+                &buildings,
+                &upgrade_assets,
+                &mut upgrades,
+                &mut stockpiles,
+                &research_query,
+            );
+        }
+    }
+}
+
+pub struct LockstepPlugin(bool);
+
+impl LockstepPlugin {
+    pub fn server() -> Self {
+        Self(true)
+    }
+
+    pub fn client() -> Self {
+        Self(false)
+    }
+}
+
+impl Plugin for LockstepPlugin {
+    fn build(&self, app_builder: &mut AppBuilder) {
+        app_builder.add_system_to_stage(SIMULATION_STAGE, lockstep_command_system.system());
+
+        if self.0 {
+            app_builder.add_system(server_lockstep_turn_system.system());
+        } else {
+            app_builder.add_system(client_lockstep_turn_system.system());
+        }
+    }
+}
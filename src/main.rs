@@ -1,63 +1,11 @@
-pub mod animation;
-pub mod asset_loading;
-pub mod bar;
-pub mod behaviour;
-mod client;
-pub mod command;
-pub mod connection;
-pub mod input;
-pub mod isometric;
-pub mod map;
-pub mod mouse_position;
-pub mod network;
-pub mod position;
-pub mod robots;
-pub mod selection;
-pub mod server;
-pub mod size;
-pub mod spawnable;
-pub mod sprite_shader;
-pub mod tile_map;
-pub mod tile_map_spawnable;
-pub mod unit;
-pub mod unit_animation;
-pub mod unit_spawnable;
-
-pub use bar::*;
-pub use behaviour::*;
-pub use command::*;
-pub use map::*;
-pub use sprite_shader::*;
-pub use tile_map_spawnable::*;
-pub use unit_animation::*;
-pub use unit_spawnable::*;
-#[macro_use]
-pub use asset_loading::*;
-pub use animation::*;
-pub use bevy::prelude::*;
-pub use bevy_networking_turbulence::*;
 use clap::Clap;
-use client::*;
-pub use connection::*;
-pub use input::*;
-pub use isometric::*;
-pub use mouse_position::*;
-pub use network::*;
-pub use position::*;
-pub use rand::prelude::*;
-pub use robots::*;
-pub use selection::*;
-pub use serde::{Deserialize, Serialize};
-pub use server::*;
-pub use size::*;
-pub use spawnable::*;
-pub use tile_map::*;
-pub use unit::*;
+use robots_or_smth::*;
 
 #[derive(Clap)]
 enum RunMode {
     Server(Server),
     Client(Client),
+    Replay(Replay),
 }
 
 #[derive(Clap)]
@@ -81,5 +29,8 @@ fn main() {
         RunMode::Client(client) => {
             client.run();
         }
+        RunMode::Replay(replay) => {
+            replay.run();
+        }
     }
 }
@@ -7,12 +7,29 @@ pub struct Map {
     pub players: HashSet<PlayerId>,
     pub spawns: Vec<Box<dyn Spawnable>>,
     pub player_spawns: HashMap<PlayerId, Vec<Box<dyn Spawnable>>>,
+    /// Players without a team play on their own.
+    #[serde(default)]
+    pub teams: HashMap<PlayerId, u32>,
+    #[serde(default = "default_win_conditions")]
+    pub win_conditions: Vec<WinCondition>,
+    /// What every player's stockpile starts out with.
+    #[serde(default)]
+    pub starting_resources: f32,
 }
 
 impl Map {
-    pub fn get_unused(&self, players: &Players) -> Option<PlayerId> {
+    pub fn starting_stockpiles(&self, player_ids: &[PlayerId]) -> HashMap<PlayerId, f32> {
+        player_ids
+            .iter()
+            .map(|player_id| (*player_id, self.starting_resources))
+            .collect()
+    }
+
+    pub fn get_unused(&self, players: &Players, disconnects: &Disconnects) -> Option<PlayerId> {
         for map_player in &self.players {
-            if !players.connection_handles.contains_key(map_player) {
+            if !players.connection_handles.contains_key(map_player)
+                && !disconnects.is_reserved(*map_player)
+            {
                 return Some(*map_player);
             }
         }
@@ -31,11 +48,19 @@ impl Map {
     }
 
     pub fn spawn(&self, players: &Players, spawn_resource: &SpawnResource) {
+        let mut player_ids: Vec<PlayerId> = players.player_ids.values().cloned().collect();
+        player_ids.sort();
+
+        self.spawn_players(&player_ids, spawn_resource);
+    }
+
+    /// Spawns the map in the same order every time, so the entities get the same network ids.
+    pub fn spawn_players(&self, player_ids: &[PlayerId], spawn_resource: &SpawnResource) {
         for spawn in &self.spawns {
             spawn_resource.spawn(Spawner::new(spawn.clone()));
         }
 
-        for (_, player_id) in &players.player_ids {
+        for player_id in player_ids {
             for player_spawn in &self.player_spawns[player_id] {
                 spawn_resource.spawn(Spawner::new(player_spawn.clone()));
             }
@@ -17,6 +17,10 @@ impl MousePosition {
         }
     }
 
+    pub fn camera(&self) -> Entity {
+        self.camera
+    }
+
     pub fn position(&self) -> Vec2 {
         self.position
     }
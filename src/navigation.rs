@@ -0,0 +1,590 @@
+use crate::*;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::f32::consts::SQRT_2;
+
+// how far around a tile is searched for obstacles, bigger units fit anywhere this far from one
+const CLEARANCE_RADIUS: i32 = 3;
+// a search giving up this early walks to the closest tile it found instead
+const MAX_SEARCH_TILES: usize = 4096;
+// a path is searched again once its target has moved this far
+const REPATH_DISTANCE: f32 = 1.0;
+// how close a unit has to get to a waypoint before heading for the next one
+const WAYPOINT_RADIUS: f32 = 0.25;
+// how finely straight lines are checked when smoothing paths
+const LINE_STEP: f32 = 0.25;
+/// Move orders for at least this many units share a flow field instead of searching paths.
+pub const FLOW_FIELD_GROUP_SIZE: usize = 50;
+/// How many frames the server waits for the rest of a group order that arrived split up.
+pub const FLOW_FIELD_GROUP_FRAMES: u32 = 5;
+
+const NEIGHBOURS: [(i32, i32); 8] = [
+    (1, 0),
+    (-1, 0),
+    (0, 1),
+    (0, -1),
+    (1, 1),
+    (1, -1),
+    (-1, 1),
+    (-1, -1),
+];
+
+/// Where units can walk, built from the tile map and the buildings standing on it.
+#[derive(Default)]
+pub struct NavigationGrid {
+    tile_map: Option<Handle<TileMap>>,
+    occupied: HashSet<TilePosition>,
+    /// Walkable tiles, and how far their center is from the closest obstacle.
+    clearance: HashMap<TilePosition, f32>,
+    /// Bumped whenever the grid changes, so paths know to search again.
+    generation: u64,
+}
+
+impl NavigationGrid {
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Whether a unit of the given size fits on the tile.
+    pub fn is_walkable(&self, tile: &TilePosition, size: f32) -> bool {
+        let max_clearance = CLEARANCE_RADIUS as f32 - 0.5;
+
+        self.clearance
+            .get(tile)
+            .map_or(false, |clearance| *clearance >= size.min(max_clearance))
+    }
+
+    fn update(&mut self, tiles: &HashMap<TilePosition, bool>, occupied: &HashSet<TilePosition>) {
+        let is_open = |tile: &TilePosition| {
+            tiles.get(tile).cloned().unwrap_or(false) && !occupied.contains(tile)
+        };
+
+        self.clearance = tiles
+            .keys()
+            .filter(|tile| is_open(tile))
+            .map(|tile| {
+                let mut clearance = CLEARANCE_RADIUS as f32 - 0.5;
+
+                for x in -CLEARANCE_RADIUS..=CLEARANCE_RADIUS {
+                    for y in -CLEARANCE_RADIUS..=CLEARANCE_RADIUS {
+                        let other = TilePosition {
+                            x: tile.x + x,
+                            y: tile.y + y,
+                        };
+
+                        if !is_open(&other) {
+                            // from the center of the tile to the closest point of the other
+                            let distance = Vec2::new(
+                                (x.abs() as f32 - 0.5).max(0.0),
+                                (y.abs() as f32 - 0.5).max(0.0),
+                            )
+                            .length();
+
+                            clearance = clearance.min(distance);
+                        }
+                    }
+                }
+
+                (*tile, clearance)
+            })
+            .collect();
+
+        self.occupied = occupied.clone();
+        self.generation += 1;
+    }
+
+    /// The waypoints from one position to another, walking around anything a unit of the given
+    /// size doesn't fit through. Ends at the closest reachable tile if the target can't be reached.
+    pub fn find_path(&self, from: Vec2, to: Vec2, size: f32) -> Vec<Vec2> {
+        let start = TilePosition::from_position(from);
+        let goal = TilePosition::from_position(to);
+
+        // without a map there's nothing to walk around
+        if self.clearance.is_empty() || start == goal {
+            return vec![to];
+        }
+
+        let mut open = BinaryHeap::new();
+        let mut closed = HashSet::new();
+        let mut came_from: HashMap<TilePosition, TilePosition> = HashMap::new();
+        let mut costs: HashMap<TilePosition, f32> = HashMap::new();
+        let mut closest = (start, heuristic(&start, &goal));
+        let mut order = 0;
+
+        costs.insert(start, 0.0);
+        open.push(SearchNode {
+            estimate: closest.1,
+            order,
+            tile: start,
+        });
+
+        while let Some(SearchNode { tile, .. }) = open.pop() {
+            if !closed.insert(tile) {
+                continue;
+            }
+
+            let distance = heuristic(&tile, &goal);
+            if distance < closest.1 {
+                closest = (tile, distance);
+            }
+
+            if tile == goal || closed.len() >= MAX_SEARCH_TILES {
+                break;
+            }
+
+            let cost = costs[&tile];
+
+            for (next, step) in self.neighbours(&tile, size) {
+                let next_cost = cost + step;
+
+                if costs.get(&next).map_or(true, |other| next_cost < *other) {
+                    costs.insert(next, next_cost);
+                    came_from.insert(next, tile);
+
+                    order += 1;
+                    open.push(SearchNode {
+                        estimate: next_cost + heuristic(&next, &goal),
+                        order,
+                        tile: next,
+                    });
+                }
+            }
+        }
+
+        let mut tiles = vec![closest.0];
+        while let Some(previous) = came_from.get(tiles.last().unwrap()) {
+            tiles.push(*previous);
+        }
+
+        // the unit is already on the first one
+        tiles.pop();
+        tiles.reverse();
+
+        let mut waypoints: Vec<Vec2> = tiles.iter().map(|tile| tile.pos()).collect();
+
+        if closest.0 == goal {
+            waypoints.pop();
+            waypoints.push(to);
+        }
+
+        self.smooth(from, waypoints, size)
+    }
+
+    // the walkable tiles around a tile, and how far away they are
+    fn neighbours(&self, tile: &TilePosition, size: f32) -> Vec<(TilePosition, f32)> {
+        let walkable = |x, y| self.is_walkable(&TilePosition { x, y }, size);
+
+        NEIGHBOURS
+            .iter()
+            .filter_map(|(x, y)| {
+                let next = TilePosition {
+                    x: tile.x + x,
+                    y: tile.y + y,
+                };
+                let diagonal = *x != 0 && *y != 0;
+
+                if !walkable(next.x, next.y) {
+                    return None;
+                }
+
+                // no cutting corners
+                if diagonal && !(walkable(next.x, tile.y) && walkable(tile.x, next.y)) {
+                    return None;
+                }
+
+                Some((next, if diagonal { SQRT_2 } else { 1.0 }))
+            })
+            .collect()
+    }
+
+    // skips every waypoint that can be walked past in a straight line
+    fn smooth(&self, from: Vec2, waypoints: Vec<Vec2>, size: f32) -> Vec<Vec2> {
+        let mut smoothed = Vec::new();
+        let mut current = from;
+        let mut index = 0;
+
+        while index < waypoints.len() {
+            let furthest = (index + 1..waypoints.len())
+                .rev()
+                .find(|other| self.is_clear(current, waypoints[*other], size))
+                .unwrap_or(index);
+
+            smoothed.push(waypoints[furthest]);
+            current = waypoints[furthest];
+            index = furthest + 1;
+        }
+
+        smoothed
+    }
+
+    /// Whether a unit of the given size can walk from one position to another in a straight line.
+    /// The tile it starts on is fine even if it doesn't fit, it has to get off it somehow.
+    pub fn is_clear(&self, from: Vec2, to: Vec2, size: f32) -> bool {
+        let start = TilePosition::from_position(from);
+        let diff = to - from;
+        let steps = ((diff.length() / LINE_STEP).ceil() as usize).max(1);
+
+        (0..=steps).all(|step| {
+            let tile = TilePosition::from_position(from + diff * (step as f32 / steps as f32));
+            tile == start || self.is_walkable(&tile, size)
+        })
+    }
+}
+
+// the octile distance, the exact cost without anything in the way
+fn heuristic(a: &TilePosition, b: &TilePosition) -> f32 {
+    let x = (a.x - b.x).abs() as f32;
+    let y = (a.y - b.y).abs() as f32;
+
+    x.max(y) + (SQRT_2 - 1.0) * x.min(y)
+}
+
+// ordered so the heap pops the lowest estimate first, and the oldest one of equal estimates,
+// which keeps the search the same on every lockstep peer
+struct SearchNode {
+    estimate: f32,
+    order: u64,
+    tile: TilePosition,
+}
+
+impl PartialEq for SearchNode {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for SearchNode {}
+
+impl Ord for SearchNode {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .estimate
+            .partial_cmp(&self.estimate)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| other.order.cmp(&self.order))
+    }
+}
+
+impl PartialOrd for SearchNode {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// The waypoints a moving unit follows to its target.
+#[derive(Default)]
+pub struct Path {
+    target: Option<Vec2>,
+    generation: u64,
+    waypoints: VecDeque<Vec2>,
+}
+
+impl Path {
+    /// The waypoint to head for, past the ones already reached.
+    pub fn next(&mut self, position: Vec2) -> Option<Vec2> {
+        while let Some(waypoint) = self.waypoints.front() {
+            if (*waypoint - position).length() > WAYPOINT_RADIUS {
+                return Some(*waypoint);
+            }
+
+            self.waypoints.pop_front();
+        }
+
+        None
+    }
+
+    pub fn clear(&mut self) {
+        self.target = None;
+        self.waypoints.clear();
+    }
+}
+
+/// The next tile toward a destination from every tile it can be reached from.
+pub struct FlowField {
+    next: HashMap<TilePosition, TilePosition>,
+    /// Set whenever a unit samples it, fields nobody used in a tick are dropped.
+    used: bool,
+}
+
+impl FlowField {
+    fn new(navigation_grid: &NavigationGrid, goal: TilePosition, size: f32) -> Self {
+        let mut open = BinaryHeap::new();
+        let mut next: HashMap<TilePosition, TilePosition> = HashMap::new();
+        let mut costs: HashMap<TilePosition, f32> = HashMap::new();
+        let mut closed = HashSet::new();
+        let mut order = 0;
+
+        costs.insert(goal, 0.0);
+        open.push(SearchNode {
+            estimate: 0.0,
+            order,
+            tile: goal,
+        });
+
+        // a search outward from the goal, every tile points back where it was reached from
+        while let Some(SearchNode { tile, .. }) = open.pop() {
+            if !closed.insert(tile) {
+                continue;
+            }
+
+            let cost = costs[&tile];
+
+            for (other, step) in navigation_grid.neighbours(&tile, size) {
+                let other_cost = cost + step;
+
+                if costs
+                    .get(&other)
+                    .map_or(true, |previous| other_cost < *previous)
+                {
+                    costs.insert(other, other_cost);
+                    next.insert(other, tile);
+
+                    order += 1;
+                    open.push(SearchNode {
+                        estimate: other_cost,
+                        order,
+                        tile: other,
+                    });
+                }
+            }
+        }
+
+        Self { next, used: true }
+    }
+
+    /// The tile to head for from a position, if the destination can be reached from there.
+    pub fn sample(&self, position: Vec2) -> Option<TilePosition> {
+        self.next
+            .get(&TilePosition::from_position(position))
+            .cloned()
+    }
+}
+
+/// Flow fields by destination and unit size, kept for as long as units are following them.
+#[derive(Default)]
+pub struct FlowFields {
+    generation: u64,
+    fields: HashMap<(TilePosition, u32), FlowField>,
+}
+
+impl FlowFields {
+    // drops the fields nobody followed since the last tick, or all of them if the grid changed
+    fn retain_used(&mut self, navigation_grid: &NavigationGrid) {
+        if self.generation != navigation_grid.generation() {
+            self.fields.clear();
+            self.generation = navigation_grid.generation();
+        }
+
+        self.fields.retain(|_, field| field.used);
+
+        for field in self.fields.values_mut() {
+            field.used = false;
+        }
+    }
+
+    /// The flow field to a destination for units of the given size, made when first needed.
+    pub fn get(
+        &mut self,
+        navigation_grid: &NavigationGrid,
+        destination: Vec2,
+        size: f32,
+    ) -> &FlowField {
+        let goal = TilePosition::from_position(destination);
+        // sizes only need to be told apart as far as they fit through different gaps
+        let key = (goal, (size * 100.0).round() as u32);
+
+        let field = self
+            .fields
+            .entry(key)
+            .or_insert_with(|| FlowField::new(navigation_grid, goal, size));
+
+        field.used = true;
+        field
+    }
+}
+
+fn navigation_grid_system(
+    mut navigation_grid: ResMut<NavigationGrid>,
+    placement_grid: Res<PlacementGrid>,
+) {
+    // the tiles only change along with the map, the buildings whenever one is placed or destroyed
+    if navigation_grid.tile_map.as_ref() != placement_grid.tile_map()
+        || navigation_grid.occupied != *placement_grid.occupied()
+    {
+        navigation_grid.update(placement_grid.tiles(), placement_grid.occupied());
+        navigation_grid.tile_map = placement_grid.tile_map().cloned();
+    }
+}
+
+/// Finds paths for moving units, once they start moving and again when their target moves.
+/// Units in a large group sample their group's flow field every tick instead.
+pub fn unit_pathfinding_system(
+    navigation_grid: Res<NavigationGrid>,
+    units: Res<Assets<Unit>>,
+    mut flow_fields: ResMut<FlowFields>,
+    mut query: Query<(&Behaviour, &Position, &Handle<Unit>, &mut Path)>,
+) {
+    flow_fields.retain_used(&navigation_grid);
+
+    for (behaviour, position, unit_handle, mut path) in query.iter_mut() {
+        let (target, flow_field) = match behaviour {
+            Behaviour::Move { target, flow_field } => (*target, *flow_field),
+            _ => {
+                path.clear();
+                continue;
+            }
+        };
+
+        let unit = match units.get(unit_handle) {
+            Some(unit) => unit,
+            None => continue,
+        };
+
+        let position = position.position.truncate();
+
+        if let Some(destination) = flow_field {
+            path.clear();
+
+            // the field leads to the group's destination, the unit's own spot in the formation
+            // is walked to once nothing is in the way
+            if !navigation_grid.is_clear(position, target, unit.size) {
+                let next = flow_fields
+                    .get(&navigation_grid, destination, unit.size)
+                    .sample(position);
+
+                if let Some(next) = next {
+                    path.waypoints.push_back(next.pos());
+                }
+            }
+
+            continue;
+        }
+
+        let up_to_date = path.generation == navigation_grid.generation()
+            && path.target.map_or(false, |path_target| {
+                (path_target - target).length() < REPATH_DISTANCE
+            });
+
+        if up_to_date {
+            continue;
+        }
+
+        let waypoints = navigation_grid.find_path(position, target, unit.size);
+
+        path.target = Some(target);
+        path.generation = navigation_grid.generation();
+        path.waypoints = waypoints.into();
+    }
+}
+
+pub struct NavigationPlugin;
+
+impl Plugin for NavigationPlugin {
+    fn build(&self, app_builder: &mut AppBuilder) {
+        app_builder.init_resource::<NavigationGrid>();
+        app_builder.init_resource::<FlowFields>();
+        app_builder.add_system_to_stage(SIMULATION_STAGE, navigation_grid_system.system());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SMALL: f32 = 0.4;
+    const LARGE: f32 = 0.8;
+
+    // a 20 by 20 map, impassable where it's blocked
+    fn grid(blocked: impl Fn(i32, i32) -> bool) -> NavigationGrid {
+        let mut tiles = HashMap::new();
+
+        for x in 0..20 {
+            for y in 0..20 {
+                tiles.insert(TilePosition { x, y }, !blocked(x, y));
+            }
+        }
+
+        let mut navigation_grid = NavigationGrid::default();
+        navigation_grid.update(&tiles, &HashSet::new());
+        navigation_grid
+    }
+
+    fn assert_walkable(navigation_grid: &NavigationGrid, from: Vec2, path: &[Vec2], size: f32) {
+        let mut current = from;
+
+        for waypoint in path {
+            assert!(
+                navigation_grid.is_clear(current, *waypoint, size),
+                "can't walk from {:?} to {:?}",
+                current,
+                waypoint
+            );
+            current = *waypoint;
+        }
+    }
+
+    #[test]
+    fn path_goes_around_an_obstacle() {
+        let navigation_grid = grid(|x, y| x == 10 && y < 15);
+        let from = Vec2::new(5.0, 5.0);
+        let to = Vec2::new(15.0, 5.0);
+
+        let path = navigation_grid.find_path(from, to, SMALL);
+
+        assert_eq!(path.last(), Some(&to));
+        assert!(path.len() > 1);
+        assert!(path.iter().any(|waypoint| waypoint.y >= 14.0));
+        assert_walkable(&navigation_grid, from, &path, SMALL);
+    }
+
+    #[test]
+    fn unreachable_goal_ends_at_the_closest_tile() {
+        let navigation_grid = grid(|x, _| x == 10);
+        let from = Vec2::new(5.0, 5.0);
+
+        let path = navigation_grid.find_path(from, Vec2::new(15.0, 5.0), SMALL);
+
+        assert_eq!(path, vec![Vec2::new(9.0, 5.0)]);
+    }
+
+    #[test]
+    fn wide_unit_does_not_fit_through_a_one_tile_gap() {
+        let navigation_grid = grid(|x, y| x == 10 && y != 10);
+        let from = Vec2::new(5.0, 5.0);
+        let to = Vec2::new(15.0, 5.0);
+
+        let small_path = navigation_grid.find_path(from, to, SMALL);
+        assert_eq!(small_path.last(), Some(&to));
+        assert_walkable(&navigation_grid, from, &small_path, SMALL);
+
+        // it gets as close as it fits, two tiles from the wall
+        let large_path = navigation_grid.find_path(from, to, LARGE);
+        assert_eq!(large_path.last(), Some(&Vec2::new(8.0, 5.0)));
+        assert_walkable(&navigation_grid, from, &large_path, LARGE);
+    }
+
+    #[test]
+    fn flow_field_leads_to_the_goal() {
+        let navigation_grid = grid(|x, y| x == 10 && y != 10);
+        let goal = TilePosition { x: 15, y: 5 };
+        let flow_field = FlowField::new(&navigation_grid, goal, SMALL);
+
+        assert_eq!(flow_field.sample(Vec2::new(14.0, 5.0)), Some(goal));
+        assert_eq!(flow_field.sample(Vec2::new(10.0, 5.0)), None);
+
+        let mut tile = TilePosition { x: 5, y: 5 };
+
+        for _ in 0..100 {
+            if tile == goal {
+                break;
+            }
+
+            let next = flow_field.sample(tile.pos()).unwrap();
+            assert!((next.x - tile.x).abs() <= 1 && (next.y - tile.y).abs() <= 1);
+            assert!(navigation_grid.is_walkable(&next, SMALL));
+            tile = next;
+        }
+
+        assert_eq!(tile, goal);
+    }
+}
@@ -47,6 +47,19 @@ impl NetworkEntityRegistry {
     pub fn remove(&mut self, network_entity: &NetworkEntity) {
         self.entities.remove(&network_entity);
     }
+
+    /// The id the next spawned entity gets.
+    pub fn next_entity(&self) -> NetworkEntity {
+        self.next_entity
+    }
+
+    pub fn set_next_entity(&mut self, next_entity: NetworkEntity) {
+        self.next_entity = next_entity;
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&NetworkEntity, &Entity)> {
+        self.entities.iter()
+    }
 }
 
 #[derive(bevy::reflect::TypeUuid)]
@@ -73,6 +86,111 @@ impl NetworkSettings {
     }
 }
 
+// progress changes every tick, so on its own it's only sent this often
+const SYNC_INTERVAL: f32 = 0.2;
+
+/// Sends something that changes every tick only every so often.
+pub struct SyncThrottle {
+    timer: Timer,
+}
+
+impl Default for SyncThrottle {
+    fn default() -> Self {
+        Self {
+            timer: Timer::from_seconds(SYNC_INTERVAL, true),
+        }
+    }
+}
+
+impl SyncThrottle {
+    pub fn tick(&mut self, time: &Time) {
+        self.timer.tick(time.delta_seconds());
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.timer.just_finished()
+    }
+}
+
+/// Remembers what was last sent for each entity, to only send it again once it's changed.
+pub struct ThrottledSync<T> {
+    throttle: SyncThrottle,
+    sent: HashMap<NetworkEntity, T>,
+}
+
+impl<T> Default for ThrottledSync<T> {
+    fn default() -> Self {
+        Self {
+            throttle: SyncThrottle::default(),
+            sent: HashMap::new(),
+        }
+    }
+}
+
+impl<T: Clone + PartialEq> ThrottledSync<T> {
+    pub fn tick(&mut self, time: &Time) {
+        self.throttle.tick(time);
+    }
+
+    /// Whether the value has to be sent. A value that appears or goes away is sent right away, so
+    /// is one that changed in a way `urgent` says can't wait, anything else waits for the throttle.
+    pub fn should_send(
+        &mut self,
+        network_entity: NetworkEntity,
+        value: Option<&T>,
+        urgent: impl FnOnce(&T, &T) -> bool,
+    ) -> bool {
+        let send = match (value, self.sent.get(&network_entity)) {
+            (Some(value), Some(last)) => {
+                urgent(last, value) || (self.throttle.is_due() && last != value)
+            }
+            (Some(_), None) | (None, Some(_)) => true,
+            (None, None) => false,
+        };
+
+        if send {
+            match value {
+                Some(value) => self.sent.insert(network_entity, value.clone()),
+                None => self.sent.remove(&network_entity),
+            };
+        }
+
+        send
+    }
+
+    /// Forgets entities that are gone.
+    pub fn retain(&mut self, mut keep: impl FnMut(&NetworkEntity) -> bool) {
+        self.sent.retain(|network_entity, _| keep(network_entity));
+    }
+}
+
+pub fn register_messages(network: &mut Network) {
+    network.register::<ConnectionMessage>();
+    network.register::<AnimatorMessage>();
+    network.register::<SpawnMessage>();
+    network.register::<CommandMessage>();
+    network.register::<PositionSnapshotMessage>();
+    network.register::<UnitInstanceMessage>();
+    network.register::<SnapshotAckMessage>();
+    network.register::<TurnMessage>();
+    network.register::<DespawnMessage>();
+    network.register::<PingMessage>();
+    network.register::<PongMessage>();
+    network.register::<LobbyMessage>();
+    network.register::<MatchEventMessage>();
+    network.register::<ChatMessage>();
+    network.register::<RejectionMessage>();
+    network.register::<ReplayControlMessage>();
+    network.register::<ReplayStatusMessage>();
+    network.register::<MatchResultMessage>();
+    network.register::<StockpileMessage>();
+    network.register::<ProductionMessage>();
+    network.register::<ProductionQueueMessage>();
+    network.register::<ConstructionMessage>();
+    network.register::<ResearchMessage>();
+    network.register::<UpgradeMessage>();
+}
+
 pub fn network_setup(mut net: ResMut<NetworkResource>) {
     net.set_channels_builder(|builder: &mut ConnectionChannelsBuilder| {
         builder
@@ -92,12 +210,84 @@ pub fn network_setup(mut net: ResMut<NetworkResource>) {
             .unwrap();
 
         builder
-            .register::<PositionMessage>(POSITION_MESSAGE_SETTINGS)
+            .register::<PositionSnapshotMessage>(POSITION_SNAPSHOT_MESSAGE_SETTINGS)
             .unwrap();
 
         builder
             .register::<UnitInstanceMessage>(UNIT_INSTANCE_MESSAGE_SETTINGS)
             .unwrap();
+
+        builder
+            .register::<SnapshotAckMessage>(SNAPSHOT_ACK_MESSAGE_SETTINGS)
+            .unwrap();
+
+        builder
+            .register::<TurnMessage>(TURN_MESSAGE_SETTINGS)
+            .unwrap();
+
+        builder
+            .register::<DespawnMessage>(DESPAWN_MESSAGE_SETTINGS)
+            .unwrap();
+
+        builder
+            .register::<PingMessage>(PING_MESSAGE_SETTINGS)
+            .unwrap();
+
+        builder
+            .register::<PongMessage>(PONG_MESSAGE_SETTINGS)
+            .unwrap();
+
+        builder
+            .register::<LobbyMessage>(LOBBY_MESSAGE_SETTINGS)
+            .unwrap();
+
+        builder
+            .register::<MatchEventMessage>(MATCH_EVENT_MESSAGE_SETTINGS)
+            .unwrap();
+
+        builder
+            .register::<ChatMessage>(CHAT_MESSAGE_SETTINGS)
+            .unwrap();
+
+        builder
+            .register::<RejectionMessage>(REJECTION_MESSAGE_SETTINGS)
+            .unwrap();
+
+        builder
+            .register::<ReplayControlMessage>(REPLAY_CONTROL_MESSAGE_SETTINGS)
+            .unwrap();
+
+        builder
+            .register::<ReplayStatusMessage>(REPLAY_STATUS_MESSAGE_SETTINGS)
+            .unwrap();
+
+        builder
+            .register::<MatchResultMessage>(MATCH_RESULT_MESSAGE_SETTINGS)
+            .unwrap();
+
+        builder
+            .register::<StockpileMessage>(STOCKPILE_MESSAGE_SETTINGS)
+            .unwrap();
+
+        builder
+            .register::<ProductionMessage>(PRODUCTION_MESSAGE_SETTINGS)
+            .unwrap();
+
+        builder
+            .register::<ProductionQueueMessage>(PRODUCTION_QUEUE_MESSAGE_SETTINGS)
+            .unwrap();
+
+        builder
+            .register::<ConstructionMessage>(CONSTRUCTION_MESSAGE_SETTINGS)
+            .unwrap();
+
+        builder
+            .register::<ResearchMessage>(RESEARCH_MESSAGE_SETTINGS)
+            .unwrap();
+
+        builder
+            .register::<UpgradeMessage>(UPGRADE_MESSAGE_SETTINGS)
+            .unwrap();
     });
 }
 
@@ -171,7 +361,7 @@ const ACTION_MESSAGE_SETTINGS: MessageChannelSettings = MessageChannelSettings {
     packet_buffer_size: 64,
 };
 
-const POSITION_MESSAGE_SETTINGS: MessageChannelSettings = MessageChannelSettings {
+const POSITION_SNAPSHOT_MESSAGE_SETTINGS: MessageChannelSettings = MessageChannelSettings {
     channel: 4,
     channel_mode: MessageChannelMode::Unreliable,
     message_buffer_size: 64,
@@ -197,4 +387,314 @@ const UNIT_INSTANCE_MESSAGE_SETTINGS: MessageChannelSettings = MessageChannelSet
     },
     message_buffer_size: 64,
     packet_buffer_size: 64,
-};
\ No newline at end of file
+};
+
+const SNAPSHOT_ACK_MESSAGE_SETTINGS: MessageChannelSettings = MessageChannelSettings {
+    channel: 6,
+    channel_mode: MessageChannelMode::Unreliable,
+    message_buffer_size: 64,
+    packet_buffer_size: 64,
+};
+
+const TURN_MESSAGE_SETTINGS: MessageChannelSettings = MessageChannelSettings {
+    channel: 7,
+    channel_mode: MessageChannelMode::Reliable {
+        reliability_settings: ReliableChannelSettings {
+            bandwidth: 4096,
+            recv_window_size: 1024,
+            send_window_size: 1024,
+            burst_bandwidth: 1024,
+            init_send: 512,
+            wakeup_time: Duration::from_millis(100),
+            initial_rtt: Duration::from_millis(200),
+            max_rtt: Duration::from_secs(2),
+            rtt_update_factor: 0.1,
+            rtt_resend_factor: 1.5,
+        },
+        // a turn carries every command issued during it
+        max_message_len: 8192,
+    },
+    message_buffer_size: 64,
+    packet_buffer_size: 64,
+};
+
+const DESPAWN_MESSAGE_SETTINGS: MessageChannelSettings = MessageChannelSettings {
+    channel: 8,
+    channel_mode: MessageChannelMode::Reliable {
+        reliability_settings: ReliableChannelSettings {
+            bandwidth: 4096,
+            recv_window_size: 1024,
+            send_window_size: 1024,
+            burst_bandwidth: 1024,
+            init_send: 512,
+            wakeup_time: Duration::from_millis(100),
+            initial_rtt: Duration::from_millis(200),
+            max_rtt: Duration::from_secs(2),
+            rtt_update_factor: 0.1,
+            rtt_resend_factor: 1.5,
+        },
+        max_message_len: 1024,
+    },
+    message_buffer_size: 64,
+    packet_buffer_size: 64,
+};
+
+const PING_MESSAGE_SETTINGS: MessageChannelSettings = MessageChannelSettings {
+    channel: 9,
+    channel_mode: MessageChannelMode::Unreliable,
+    message_buffer_size: 64,
+    packet_buffer_size: 64,
+};
+
+const PONG_MESSAGE_SETTINGS: MessageChannelSettings = MessageChannelSettings {
+    channel: 10,
+    channel_mode: MessageChannelMode::Unreliable,
+    message_buffer_size: 64,
+    packet_buffer_size: 64,
+};
+
+const LOBBY_MESSAGE_SETTINGS: MessageChannelSettings = MessageChannelSettings {
+    channel: 11,
+    channel_mode: MessageChannelMode::Reliable {
+        reliability_settings: ReliableChannelSettings {
+            bandwidth: 4096,
+            recv_window_size: 1024,
+            send_window_size: 1024,
+            burst_bandwidth: 1024,
+            init_send: 512,
+            wakeup_time: Duration::from_millis(100),
+            initial_rtt: Duration::from_millis(200),
+            max_rtt: Duration::from_secs(2),
+            rtt_update_factor: 0.1,
+            rtt_resend_factor: 1.5,
+        },
+        max_message_len: 1024,
+    },
+    message_buffer_size: 64,
+    packet_buffer_size: 64,
+};
+
+const MATCH_EVENT_MESSAGE_SETTINGS: MessageChannelSettings = MessageChannelSettings {
+    channel: 12,
+    channel_mode: MessageChannelMode::Reliable {
+        reliability_settings: ReliableChannelSettings {
+            bandwidth: 4096,
+            recv_window_size: 1024,
+            send_window_size: 1024,
+            burst_bandwidth: 1024,
+            init_send: 512,
+            wakeup_time: Duration::from_millis(100),
+            initial_rtt: Duration::from_millis(200),
+            max_rtt: Duration::from_secs(2),
+            rtt_update_factor: 0.1,
+            rtt_resend_factor: 1.5,
+        },
+        max_message_len: 1024,
+    },
+    message_buffer_size: 64,
+    packet_buffer_size: 64,
+};
+
+const CHAT_MESSAGE_SETTINGS: MessageChannelSettings = MessageChannelSettings {
+    channel: 13,
+    channel_mode: MessageChannelMode::Reliable {
+        reliability_settings: ReliableChannelSettings {
+            bandwidth: 4096,
+            recv_window_size: 1024,
+            send_window_size: 1024,
+            burst_bandwidth: 1024,
+            init_send: 512,
+            wakeup_time: Duration::from_millis(100),
+            initial_rtt: Duration::from_millis(200),
+            max_rtt: Duration::from_secs(2),
+            rtt_update_factor: 0.1,
+            rtt_resend_factor: 1.5,
+        },
+        max_message_len: 1024,
+    },
+    message_buffer_size: 64,
+    packet_buffer_size: 64,
+};
+
+const REJECTION_MESSAGE_SETTINGS: MessageChannelSettings = MessageChannelSettings {
+    channel: 14,
+    channel_mode: MessageChannelMode::Unreliable,
+    message_buffer_size: 64,
+    packet_buffer_size: 64,
+};
+
+const REPLAY_CONTROL_MESSAGE_SETTINGS: MessageChannelSettings = MessageChannelSettings {
+    channel: 15,
+    channel_mode: MessageChannelMode::Reliable {
+        reliability_settings: ReliableChannelSettings {
+            bandwidth: 4096,
+            recv_window_size: 1024,
+            send_window_size: 1024,
+            burst_bandwidth: 1024,
+            init_send: 512,
+            wakeup_time: Duration::from_millis(100),
+            initial_rtt: Duration::from_millis(200),
+            max_rtt: Duration::from_secs(2),
+            rtt_update_factor: 0.1,
+            rtt_resend_factor: 1.5,
+        },
+        max_message_len: 1024,
+    },
+    message_buffer_size: 64,
+    packet_buffer_size: 64,
+};
+
+const REPLAY_STATUS_MESSAGE_SETTINGS: MessageChannelSettings = MessageChannelSettings {
+    channel: 16,
+    channel_mode: MessageChannelMode::Unreliable,
+    message_buffer_size: 64,
+    packet_buffer_size: 64,
+};
+
+const MATCH_RESULT_MESSAGE_SETTINGS: MessageChannelSettings = MessageChannelSettings {
+    channel: 17,
+    channel_mode: MessageChannelMode::Reliable {
+        reliability_settings: ReliableChannelSettings {
+            bandwidth: 4096,
+            recv_window_size: 1024,
+            send_window_size: 1024,
+            burst_bandwidth: 1024,
+            init_send: 512,
+            wakeup_time: Duration::from_millis(100),
+            initial_rtt: Duration::from_millis(200),
+            max_rtt: Duration::from_secs(2),
+            rtt_update_factor: 0.1,
+            rtt_resend_factor: 1.5,
+        },
+        // carries the stats of every player
+        max_message_len: 4096,
+    },
+    message_buffer_size: 8,
+    packet_buffer_size: 8,
+};
+
+const STOCKPILE_MESSAGE_SETTINGS: MessageChannelSettings = MessageChannelSettings {
+    channel: 18,
+    channel_mode: MessageChannelMode::Reliable {
+        reliability_settings: ReliableChannelSettings {
+            bandwidth: 4096,
+            recv_window_size: 1024,
+            send_window_size: 1024,
+            burst_bandwidth: 1024,
+            init_send: 512,
+            wakeup_time: Duration::from_millis(100),
+            initial_rtt: Duration::from_millis(200),
+            max_rtt: Duration::from_secs(2),
+            rtt_update_factor: 0.1,
+            rtt_resend_factor: 1.5,
+        },
+        max_message_len: 1024,
+    },
+    message_buffer_size: 64,
+    packet_buffer_size: 64,
+};
+
+const PRODUCTION_MESSAGE_SETTINGS: MessageChannelSettings = MessageChannelSettings {
+    channel: 19,
+    channel_mode: MessageChannelMode::Reliable {
+        reliability_settings: ReliableChannelSettings {
+            bandwidth: 4096,
+            recv_window_size: 1024,
+            send_window_size: 1024,
+            burst_bandwidth: 1024,
+            init_send: 512,
+            wakeup_time: Duration::from_millis(100),
+            initial_rtt: Duration::from_millis(200),
+            max_rtt: Duration::from_secs(2),
+            rtt_update_factor: 0.1,
+            rtt_resend_factor: 1.5,
+        },
+        max_message_len: 1024,
+    },
+    message_buffer_size: 64,
+    packet_buffer_size: 64,
+};
+
+const PRODUCTION_QUEUE_MESSAGE_SETTINGS: MessageChannelSettings = MessageChannelSettings {
+    channel: 20,
+    channel_mode: MessageChannelMode::Reliable {
+        reliability_settings: ReliableChannelSettings {
+            bandwidth: 4096,
+            recv_window_size: 1024,
+            send_window_size: 1024,
+            burst_bandwidth: 1024,
+            init_send: 512,
+            wakeup_time: Duration::from_millis(100),
+            initial_rtt: Duration::from_millis(200),
+            max_rtt: Duration::from_secs(2),
+            rtt_update_factor: 0.1,
+            rtt_resend_factor: 1.5,
+        },
+        max_message_len: 1024,
+    },
+    message_buffer_size: 64,
+    packet_buffer_size: 64,
+};
+
+const CONSTRUCTION_MESSAGE_SETTINGS: MessageChannelSettings = MessageChannelSettings {
+    channel: 21,
+    channel_mode: MessageChannelMode::Reliable {
+        reliability_settings: ReliableChannelSettings {
+            bandwidth: 4096,
+            recv_window_size: 1024,
+            send_window_size: 1024,
+            burst_bandwidth: 1024,
+            init_send: 512,
+            wakeup_time: Duration::from_millis(100),
+            initial_rtt: Duration::from_millis(200),
+            max_rtt: Duration::from_secs(2),
+            rtt_update_factor: 0.1,
+            rtt_resend_factor: 1.5,
+        },
+        max_message_len: 1024,
+    },
+    message_buffer_size: 64,
+    packet_buffer_size: 64,
+};
+
+const RESEARCH_MESSAGE_SETTINGS: MessageChannelSettings = MessageChannelSettings {
+    channel: 22,
+    channel_mode: MessageChannelMode::Reliable {
+        reliability_settings: ReliableChannelSettings {
+            bandwidth: 4096,
+            recv_window_size: 1024,
+            send_window_size: 1024,
+            burst_bandwidth: 1024,
+            init_send: 512,
+            wakeup_time: Duration::from_millis(100),
+            initial_rtt: Duration::from_millis(200),
+            max_rtt: Duration::from_secs(2),
+            rtt_update_factor: 0.1,
+            rtt_resend_factor: 1.5,
+        },
+        max_message_len: 1024,
+    },
+    message_buffer_size: 64,
+    packet_buffer_size: 64,
+};
+
+const UPGRADE_MESSAGE_SETTINGS: MessageChannelSettings = MessageChannelSettings {
+    channel: 23,
+    channel_mode: MessageChannelMode::Reliable {
+        reliability_settings: ReliableChannelSettings {
+            bandwidth: 4096,
+            recv_window_size: 1024,
+            send_window_size: 1024,
+            burst_bandwidth: 1024,
+            init_send: 512,
+            wakeup_time: Duration::from_millis(100),
+            initial_rtt: Duration::from_millis(200),
+            max_rtt: Duration::from_secs(2),
+            rtt_update_factor: 0.1,
+            rtt_resend_factor: 1.5,
+        },
+        max_message_len: 1024,
+    },
+    message_buffer_size: 64,
+    packet_buffer_size: 64,
+};
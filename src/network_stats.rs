@@ -0,0 +1,380 @@
+use crate::*;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+
+const PING_INTERVAL: f32 = 1.0;
+// a ping without a pong after this long counts as lost
+const PING_TIMEOUT: f64 = 2.0;
+// how many pings packet loss is measured over
+const PING_WINDOW: usize = 32;
+const STATS_LOG_INTERVAL: f32 = 10.0;
+const OVERLAY_UPDATE_INTERVAL: f32 = 0.5;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PingMessage {
+    pub sequence: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PongMessage {
+    pub sequence: u32,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MessageCounter {
+    pub messages: u64,
+    /// Serialized payload size, excluding the overhead added by the transport.
+    pub bytes: u64,
+}
+
+impl MessageCounter {
+    fn add(&mut self, bytes: u64) {
+        self.messages += 1;
+        self.bytes += bytes;
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MessageStats {
+    pub sent: MessageCounter,
+    pub received: MessageCounter,
+    /// Messages that never made it out, because the send buffer was full or the send failed.
+    pub dropped: MessageCounter,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionStats {
+    pub total: MessageStats,
+    pub message_types: BTreeMap<&'static str, MessageStats>,
+    /// Smoothed round trip time in seconds.
+    pub rtt: Option<f64>,
+    /// Fraction of recent pings which never got an answer.
+    pub packet_loss: f32,
+    pings: VecDeque<(u32, f64, bool)>,
+}
+
+impl ConnectionStats {
+    fn pong(&mut self, sequence: u32, now: f64) {
+        let ping = self
+            .pings
+            .iter_mut()
+            .find(|(ping_sequence, _, answered)| *ping_sequence == sequence && !*answered);
+
+        if let Some((_, sent, answered)) = ping {
+            *answered = true;
+
+            let rtt = now - *sent;
+            self.rtt = Some(match self.rtt {
+                Some(smoothed) => smoothed + (rtt - smoothed) * 0.1,
+                None => rtt,
+            });
+        }
+    }
+
+    fn update_packet_loss(&mut self, now: f64) {
+        let (lost, total) = self
+            .pings
+            .iter()
+            // too early to tell for the most recent ones
+            .filter(|(_, sent, answered)| *answered || now - sent > PING_TIMEOUT)
+            .fold((0, 0), |(lost, total), (_, _, answered)| {
+                (lost + !answered as usize, total + 1)
+            });
+
+        if total > 0 {
+            self.packet_loss = lost as f32 / total as f32;
+        }
+    }
+}
+
+/// Counts the messages going through the [`Network`], per connection and per message type.
+///
+/// Every message type has its own channel, so the message type counters are also channel counters.
+#[derive(Default)]
+pub struct NetworkStats {
+    pub connections: HashMap<ConnectionHandle, ConnectionStats>,
+    pub message_types: BTreeMap<&'static str, MessageStats>,
+}
+
+impl NetworkStats {
+    pub fn record_sent(
+        &mut self,
+        handle: ConnectionHandle,
+        message_type: &'static str,
+        bytes: u64,
+    ) {
+        let connection = self.connections.entry(handle).or_default();
+        connection.total.sent.add(bytes);
+        connection
+            .message_types
+            .entry(message_type)
+            .or_default()
+            .sent
+            .add(bytes);
+
+        self.message_types
+            .entry(message_type)
+            .or_default()
+            .sent
+            .add(bytes);
+    }
+
+    pub fn record_dropped(
+        &mut self,
+        handle: ConnectionHandle,
+        message_type: &'static str,
+        bytes: u64,
+    ) {
+        let connection = self.connections.entry(handle).or_default();
+        connection.total.dropped.add(bytes);
+        connection
+            .message_types
+            .entry(message_type)
+            .or_default()
+            .dropped
+            .add(bytes);
+
+        self.message_types
+            .entry(message_type)
+            .or_default()
+            .dropped
+            .add(bytes);
+    }
+
+    pub fn record_received(
+        &mut self,
+        handle: ConnectionHandle,
+        message_type: &'static str,
+        bytes: u64,
+    ) {
+        let connection = self.connections.entry(handle).or_default();
+        connection.total.received.add(bytes);
+        connection
+            .message_types
+            .entry(message_type)
+            .or_default()
+            .received
+            .add(bytes);
+
+        self.message_types
+            .entry(message_type)
+            .or_default()
+            .received
+            .add(bytes);
+    }
+
+    pub fn describe(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+
+        let mut handles: Vec<&ConnectionHandle> = self.connections.keys().collect();
+        handles.sort();
+
+        for handle in handles {
+            let connection = &self.connections[handle];
+
+            let rtt = match connection.rtt {
+                Some(rtt) => format!("{:.0}ms", rtt * 1000.0),
+                None => "-".to_string(),
+            };
+
+            lines.push(format!(
+                "connection {}: rtt {}, loss {:.1}%, sent {} ({} B), dropped {}, received {} ({} B)",
+                handle,
+                rtt,
+                connection.packet_loss * 100.0,
+                connection.total.sent.messages,
+                connection.total.sent.bytes,
+                connection.total.dropped.messages,
+                connection.total.received.messages,
+                connection.total.received.bytes,
+            ));
+        }
+
+        for (message_type, stats) in &self.message_types {
+            lines.push(format!(
+                "{}: sent {} ({} B), dropped {}, received {} ({} B)",
+                message_type,
+                stats.sent.messages,
+                stats.sent.bytes,
+                stats.dropped.messages,
+                stats.received.messages,
+                stats.received.bytes,
+            ));
+        }
+
+        lines
+    }
+}
+
+pub fn ping_system(
+    mut timer: Local<Timer>,
+    mut sequence: Local<u32>,
+    time: Res<Time>,
+    mut net: ResMut<Network>,
+    mut network_stats: ResMut<NetworkStats>,
+) {
+    let now = time.seconds_since_startup();
+
+    for (handle, ping) in net.recv::<PingMessage>() {
+        net.send_message(
+            handle,
+            PongMessage {
+                sequence: ping.sequence,
+            },
+        );
+    }
+
+    for (handle, pong) in net.recv::<PongMessage>() {
+        if let Some(connection) = network_stats.connections.get_mut(&handle) {
+            connection.pong(pong.sequence, now);
+        }
+    }
+
+    let handles: HashSet<ConnectionHandle> = net.connections().cloned().collect();
+    network_stats
+        .connections
+        .retain(|handle, _| handles.contains(handle));
+
+    timer.set_duration(PING_INTERVAL);
+    timer.set_repeating(true);
+    timer.tick(time.delta_seconds());
+
+    if !timer.just_finished() {
+        return;
+    }
+
+    *sequence = sequence.wrapping_add(1);
+
+    for handle in handles {
+        net.send_message(
+            handle,
+            PingMessage {
+                sequence: *sequence,
+            },
+        );
+
+        let connection = network_stats.connections.entry(handle).or_default();
+        connection.pings.push_back((*sequence, now, false));
+
+        while connection.pings.len() > PING_WINDOW {
+            connection.pings.pop_front();
+        }
+
+        connection.update_packet_loss(now);
+    }
+}
+
+fn server_network_stats_log_system(
+    mut timer: Local<Timer>,
+    time: Res<Time>,
+    network_stats: Res<NetworkStats>,
+) {
+    timer.set_duration(STATS_LOG_INTERVAL);
+    timer.set_repeating(true);
+    timer.tick(time.delta_seconds());
+
+    if timer.just_finished() {
+        for line in network_stats.describe() {
+            info!("{}", line);
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct NetworkStatsOverlay {
+    pub visible: bool,
+}
+
+pub struct NetworkStatsText;
+
+fn client_network_stats_overlay_setup(commands: &mut Commands, asset_server: Res<AssetServer>) {
+    commands
+        .spawn(TextBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: Rect {
+                    top: Val::Px(16.0),
+                    right: Val::Px(16.0),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            text: Text {
+                value: String::new(),
+                font: asset_server.load("fonts/DejaVuSans.ttf"),
+                style: TextStyle {
+                    font_size: 14.0,
+                    color: Color::WHITE,
+                    ..Default::default()
+                },
+            },
+            ..Default::default()
+        })
+        .with(NetworkStatsText);
+}
+
+fn client_network_stats_overlay_system(
+    mut timer: Local<Timer>,
+    time: Res<Time>,
+    keyboard_input: Res<Input<KeyCode>>,
+    mouse_input: Res<Input<MouseButton>>,
+    input_config: Res<Assets<InputConfig>>,
+    input_resource: Res<InputResource>,
+    network_stats: Res<NetworkStats>,
+    mut overlay: ResMut<NetworkStatsOverlay>,
+    mut query: Query<&mut Text, With<NetworkStatsText>>,
+) {
+    let input_config = match input_config.get(&input_resource.0) {
+        Some(input_config) => input_config,
+        None => return,
+    };
+
+    let toggled = input_config
+        .toggle_network_stats
+        .just_pressed(&keyboard_input, &mouse_input);
+
+    if toggled {
+        overlay.visible = !overlay.visible;
+    }
+
+    timer.set_duration(OVERLAY_UPDATE_INTERVAL);
+    timer.set_repeating(true);
+    timer.tick(time.delta_seconds());
+
+    if !toggled && !timer.just_finished() {
+        return;
+    }
+
+    for mut text in query.iter_mut() {
+        text.value = if overlay.visible {
+            network_stats.describe().join("\n")
+        } else {
+            String::new()
+        };
+    }
+}
+
+pub struct NetworkStatsPlugin(pub bool);
+
+impl NetworkStatsPlugin {
+    pub fn server() -> Self {
+        Self(true)
+    }
+
+    pub fn client() -> Self {
+        Self(false)
+    }
+}
+
+impl Plugin for NetworkStatsPlugin {
+    fn build(&self, app_builder: &mut AppBuilder) {
+        app_builder.add_system(ping_system.system());
+
+        if self.0 {
+            app_builder.add_system(server_network_stats_log_system.system());
+        } else {
+            app_builder.init_resource::<NetworkStatsOverlay>();
+            app_builder.add_startup_system(client_network_stats_overlay_setup.system());
+            app_builder.add_system(client_network_stats_overlay_system.system());
+        }
+    }
+}
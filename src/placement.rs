@@ -0,0 +1,634 @@
+use crate::*;
+use std::collections::{HashMap, HashSet};
+
+/// What a unit can build, declared in its `.unit` file.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Builder {
+    /// The buildings it can place, in the order of the build keys.
+    pub buildings: Vec<String>,
+    /// How close it has to get to a site to work on it.
+    pub range: f32,
+}
+
+/// A building that isn't finished yet, it can't train or take cargo until it is.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Construction {
+    /// Seconds spent building it.
+    pub progress: f32,
+    /// The cost is paid once a worker starts building, it waits until the owner can afford it.
+    pub paid: bool,
+}
+
+impl Construction {
+    /// How far along it is, from 0 to 1.
+    pub fn fraction(&self, building: &Building) -> f32 {
+        if building.construction_time > 0.0 {
+            (self.progress / building.construction_time).min(1.0)
+        } else {
+            1.0
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ConstructionMessage {
+    pub building: NetworkEntity,
+    /// Gone once the building is finished.
+    pub construction: Option<Construction>,
+}
+
+/// A building standing on the grid.
+pub struct Site {
+    pub entity: Entity,
+    pub owner: PlayerId,
+    pub building: Handle<Building>,
+    pub size: f32,
+    pub under_construction: bool,
+}
+
+/// Where buildings can go and what's already standing, gathered every simulation tick.
+#[derive(Default)]
+pub struct PlacementGrid {
+    tile_map: Option<Handle<TileMap>>,
+    /// Every tile on the map, and whether it's passable.
+    tiles: HashMap<TilePosition, bool>,
+    /// Tiles covered by buildings.
+    occupied: HashSet<TilePosition>,
+    /// Buildings by the tile they were placed on.
+    sites: HashMap<TilePosition, Site>,
+    units: Vec<(Entity, Vec2, f32)>,
+    /// Sites the server tried to place since the grid was gathered, but couldn't.
+    blocked: HashSet<TilePosition>,
+}
+
+impl PlacementGrid {
+    pub fn site(&self, origin: &TilePosition) -> Option<&Site> {
+        self.sites.get(origin)
+    }
+
+    pub fn is_blocked(&self, origin: &TilePosition) -> bool {
+        self.blocked.contains(origin)
+    }
+
+    pub fn tile_map(&self) -> Option<&Handle<TileMap>> {
+        self.tile_map.as_ref()
+    }
+
+    pub fn tiles(&self) -> &HashMap<TilePosition, bool> {
+        &self.tiles
+    }
+
+    pub fn occupied(&self) -> &HashSet<TilePosition> {
+        &self.occupied
+    }
+
+    /// Whether every tile is on the map, passable and clear of buildings and units,
+    /// the ignored ones aside.
+    pub fn is_free(&self, tiles: &[TilePosition], ignore: &[Entity]) -> bool {
+        tiles.iter().all(|tile| {
+            self.tiles.get(tile).cloned().unwrap_or(false)
+                && !self.occupied.contains(tile)
+                && !self.units.iter().any(|(entity, position, size)| {
+                    !ignore.contains(entity) && overlaps_tile(*position, *size, tile)
+                })
+        })
+    }
+
+    /// Checks a worker may place a building on a tile, or join a site of its owner's already there.
+    pub fn check(
+        &self,
+        building: &str,
+        origin: &TilePosition,
+        owner: PlayerId,
+        worker: Entity,
+        buildings: &Assets<Building>,
+    ) -> Result<(), Rejection> {
+        let building_handle = buildings.get_handle(building);
+        let building = buildings
+            .get(&building_handle)
+            .ok_or_else(|| Rejection::UnknownBuilding(building.to_string()))?;
+
+        match self.site(origin) {
+            Some(site)
+                if site.owner == owner
+                    && site.building == building_handle
+                    && site.under_construction =>
+            {
+                Ok(())
+            }
+            _ if self.is_free(&building.tiles(origin), &[worker]) => Ok(()),
+            _ => Err(Rejection::PlacementBlocked(*origin)),
+        }
+    }
+}
+
+// tiles are a unit wide, the unit's circle is checked against the closest point on it
+fn overlaps_tile(position: Vec2, size: f32, tile: &TilePosition) -> bool {
+    let half = Vec2::new(0.5, 0.5);
+    let closest = position.max(tile.pos() - half).min(tile.pos() + half);
+
+    (position - closest).length() < size
+}
+
+pub fn finish_construction(commands: &mut Commands, entity: Entity, building: &Building) {
+    commands.remove_one::<Construction>(entity);
+
+    if building.drop_off {
+        commands.insert_one(
+            entity,
+            DropOff {
+                size: building.size,
+            },
+        );
+    }
+}
+
+fn placement_grid_system(
+    mut placement_grid: ResMut<PlacementGrid>,
+    tile_maps: Res<Assets<TileMap>>,
+    tile_sets: Res<Assets<TileSet>>,
+    buildings: Res<Assets<Building>>,
+    units: Res<Assets<Unit>>,
+    tile_map_query: Query<(&Handle<TileMap>, &Handle<TileSet>)>,
+    building_query: Query<(
+        Entity,
+        &Position,
+        &Owner,
+        &Handle<Building>,
+        Option<&Construction>,
+    )>,
+    unit_query: Query<(Entity, &Position, &Handle<Unit>)>,
+) {
+    // the tiles only change along with the map
+    if let Some((tile_map_handle, tile_set_handle)) = tile_map_query.iter().next() {
+        if placement_grid.tile_map.as_ref() != Some(tile_map_handle) {
+            if let (Some(tile_map), Some(tile_set)) = (
+                tile_maps.get(tile_map_handle),
+                tile_sets.get(tile_set_handle),
+            ) {
+                placement_grid.tiles = tile_map.passable_tiles(tile_set);
+                placement_grid.tile_map = Some(tile_map_handle.clone());
+            }
+        }
+    }
+
+    placement_grid.occupied.clear();
+    placement_grid.sites.clear();
+    placement_grid.blocked.clear();
+
+    for (entity, position, owner, building_handle, construction) in building_query.iter() {
+        let building = match buildings.get(building_handle) {
+            Some(building) => building,
+            None => continue,
+        };
+
+        let origin = TilePosition::from_position(position.position.truncate());
+
+        placement_grid.occupied.extend(building.tiles(&origin));
+        placement_grid.sites.insert(
+            origin,
+            Site {
+                entity,
+                owner: owner.0,
+                building: building_handle.clone(),
+                size: building.size,
+                under_construction: construction.is_some(),
+            },
+        );
+    }
+
+    placement_grid.units = unit_query
+        .iter()
+        .filter_map(|(entity, position, unit_handle)| {
+            Some((
+                entity,
+                position.position.truncate(),
+                units.get(unit_handle)?.size,
+            ))
+        })
+        .collect();
+}
+
+// clients only simulate in lockstep, otherwise the grid is just for placing buildings
+fn client_placement_grid_system(
+    simulation_mode: Res<SimulationMode>,
+    placement_grid: ResMut<PlacementGrid>,
+    tile_maps: Res<Assets<TileMap>>,
+    tile_sets: Res<Assets<TileSet>>,
+    buildings: Res<Assets<Building>>,
+    units: Res<Assets<Unit>>,
+    tile_map_query: Query<(&Handle<TileMap>, &Handle<TileSet>)>,
+    building_query: Query<(
+        Entity,
+        &Position,
+        &Owner,
+        &Handle<Building>,
+        Option<&Construction>,
+    )>,
+    unit_query: Query<(Entity, &Position, &Handle<Unit>)>,
+) {
+    if simulation_mode.is_lockstep() {
+        return;
+    }
+
+    placement_grid_system(
+        placement_grid,
+        tile_maps,
+        tile_sets,
+        buildings,
+        units,
+        tile_map_query,
+        building_query,
+        unit_query,
+    );
+}
+
+// sites are placed as soon as a worker gets to its placement command, it then walks over
+fn server_construction_site_system(
+    buildings: Res<Assets<Building>>,
+    units: Res<Assets<Unit>>,
+    spawn_resource: Res<SpawnResource>,
+    mut placement_grid: ResMut<PlacementGrid>,
+    query: Query<(Entity, &CommandQueue, &Owner, &Handle<Unit>)>,
+) {
+    let mut placed = HashSet::new();
+
+    for (entity, command_queue, owner, unit_handle) in query.iter() {
+        let (building_path, origin) = match command_queue
+            .commands
+            .back()
+            .and_then(|command| command.placement())
+        {
+            Some(placement) => placement,
+            None => continue,
+        };
+
+        let can_build = units
+            .get(unit_handle)
+            .and_then(|unit| unit.builder.as_ref())
+            .map_or(false, |builder| {
+                builder
+                    .buildings
+                    .iter()
+                    .any(|building| building == building_path)
+            });
+
+        // other workers sent to the same site just join in
+        if !can_build || placement_grid.site(origin).is_some() || placed.contains(origin) {
+            continue;
+        }
+
+        let building = match buildings.get(&buildings.get_handle(building_path)) {
+            Some(building) => building,
+            None => continue,
+        };
+
+        if placement_grid.is_free(&building.tiles(origin), &[entity]) {
+            spawn_resource.spawn(BuildingSpawnable {
+                position: origin.pos().extend(0.0),
+                owner: owner.0,
+                building: building_path.to_string(),
+                under_construction: true,
+            });
+
+            placed.insert(*origin);
+        } else {
+            placement_grid.blocked.insert(*origin);
+        }
+    }
+}
+
+fn construction_system(
+    commands: &mut Commands,
+    buildings: Res<Assets<Building>>,
+    query: Query<(Entity, &Handle<Building>, &Construction)>,
+) {
+    for (entity, building_handle, construction) in query.iter() {
+        if let Some(building) = buildings.get(building_handle) {
+            if construction.progress >= building.construction_time {
+                finish_construction(commands, entity, building);
+            }
+        }
+    }
+}
+
+fn server_construction_sync_system(
+    mut sync: Local<ThrottledSync<Construction>>,
+    time: Res<Time>,
+    simulation_mode: Res<SimulationMode>,
+    vision: Res<Vision>,
+    mut net: ResMut<Network>,
+    query: Query<(&NetworkEntity, Option<&Construction>), With<Handle<Building>>>,
+) {
+    // every peer builds on its own in lockstep
+    if simulation_mode.is_lockstep() {
+        return;
+    }
+
+    sync.tick(&time);
+
+    for (network_entity, construction) in query.iter() {
+        if sync.should_send(*network_entity, construction, |_, _| false) {
+            vision.send_message(
+                &mut net,
+                *network_entity,
+                ConstructionMessage {
+                    building: *network_entity,
+                    construction: construction.cloned(),
+                },
+            );
+        }
+    }
+
+    sync.retain(|network_entity| {
+        query
+            .iter()
+            .any(|(other_network_entity, _)| other_network_entity == network_entity)
+    });
+}
+
+fn client_construction_system(
+    commands: &mut Commands,
+    mut net: ResMut<Network>,
+    network_entity_registry: Res<NetworkEntityRegistry>,
+    buildings: Res<Assets<Building>>,
+    mut query: Query<(&Handle<Building>, &mut Construction)>,
+) {
+    for (_handle, message) in net.recv::<ConstructionMessage>() {
+        let entity = match network_entity_registry.get(&message.building) {
+            Some(entity) => *entity,
+            None => {
+                warn!(
+                    "Recieved construction for unknown entity {:?}",
+                    message.building
+                );
+                continue;
+            }
+        };
+
+        if let Ok((building_handle, mut construction)) = query.get_mut(entity) {
+            match message.construction {
+                Some(new_construction) => *construction = new_construction,
+                None => {
+                    if let Some(building) = buildings.get(building_handle) {
+                        finish_construction(commands, entity, building);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The building the player is about to place.
+#[derive(Default)]
+pub struct Placement {
+    pub building: Option<String>,
+    /// Set on the frame a building was placed, so the same click doesn't select anything.
+    pub just_placed: bool,
+}
+
+impl Placement {
+    pub fn is_active(&self) -> bool {
+        self.building.is_some() || self.just_placed
+    }
+}
+
+/// One tile of the footprint shown while placing.
+pub struct PlacementGhost;
+
+pub struct PlacementMaterials {
+    valid: Handle<ColorMaterial>,
+    invalid: Handle<ColorMaterial>,
+}
+
+fn client_placement_setup(
+    commands: &mut Commands,
+    asset_server: Res<AssetServer>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+) {
+    let texture = asset_server.load("sprites/grass_tile.png");
+
+    commands.insert_resource(PlacementMaterials {
+        valid: materials.add(ColorMaterial {
+            color: Color::rgba(0.3, 1.0, 0.3, 0.6),
+            texture: Some(texture.clone()),
+        }),
+        invalid: materials.add(ColorMaterial {
+            color: Color::rgba(1.0, 0.3, 0.3, 0.6),
+            texture: Some(texture),
+        }),
+    });
+}
+
+fn client_placement_input_system(
+    mut placement: ResMut<Placement>,
+    selected_units: Res<SelectedUnits>,
+    input_config: Res<Assets<InputConfig>>,
+    input_resource: Res<InputResource>,
+    chat_input: Res<ChatInput>,
+    mouse_position: Res<MousePosition>,
+    mouse_input: Res<Input<MouseButton>>,
+    keyboard_input: Res<Input<KeyCode>>,
+    units: Res<Assets<Unit>>,
+    buildings: Res<Assets<Building>>,
+    placement_grid: Res<PlacementGrid>,
+    mut net: ResMut<Network>,
+    query: Query<(&Handle<Unit>, &NetworkEntity)>,
+) {
+    placement.just_placed = false;
+
+    let input_config = match input_config.get(&input_resource.0) {
+        Some(input_config) => input_config,
+        None => return,
+    };
+
+    let builders: Vec<(Entity, NetworkEntity, &Builder)> = selected_units
+        .units
+        .iter()
+        .filter_map(|entity| {
+            let (unit_handle, network_entity) = query.get(*entity).ok()?;
+            let builder = units.get(unit_handle)?.builder.as_ref()?;
+
+            Some((*entity, *network_entity, builder))
+        })
+        .collect();
+
+    if builders.is_empty() {
+        placement.building = None;
+        return;
+    }
+
+    // typing in the chat shouldn't start placing anything
+    if chat_input.scope.is_none() {
+        for (index, input) in input_config.build.iter().enumerate() {
+            if input.just_pressed(&keyboard_input, &mouse_input) {
+                if let Some(building) = builders
+                    .iter()
+                    .find_map(|(_, _, builder)| builder.buildings.get(index))
+                {
+                    placement.building = Some(building.clone());
+                }
+            }
+        }
+
+        if input_config
+            .cancel_placement
+            .just_pressed(&keyboard_input, &mouse_input)
+        {
+            placement.building = None;
+        }
+    }
+
+    let building_path = match &placement.building {
+        Some(building_path) => building_path.clone(),
+        None => return,
+    };
+
+    if !input_config
+        .select
+        .just_released(&keyboard_input, &mouse_input)
+    {
+        return;
+    }
+
+    let building = match buildings.get(&buildings.get_handle(building_path.as_str())) {
+        Some(building) => building,
+        None => return,
+    };
+
+    let origin = TilePosition::from_position(mouse_position.position());
+
+    let workers: Vec<(Entity, NetworkEntity)> = builders
+        .iter()
+        .filter(|(_, _, builder)| builder.buildings.contains(&building_path))
+        .map(|(entity, network_entity, _)| (*entity, *network_entity))
+        .collect();
+    let ignore: Vec<Entity> = workers.iter().map(|(entity, _)| *entity).collect();
+
+    // keep placing until the spot is valid
+    if !placement_grid.is_free(&building.tiles(&origin), &ignore) {
+        return;
+    }
+
+    let queue = input_config
+        .queue_actions
+        .pressed(&keyboard_input, &mouse_input);
+
+    for (_, network_entity) in workers {
+        let command = Box::new(PlaceBuildingCommand {
+            building: building_path.clone(),
+            position: origin,
+            started: false,
+        });
+
+        let operation = if queue {
+            CommandQueueOperation::AddCommand(command)
+        } else {
+            CommandQueueOperation::SetCommand(command)
+        };
+
+        net.broadcast_message(CommandMessage {
+            operation,
+            network_entity,
+        });
+    }
+
+    // holding the queue key places more of the same
+    if !queue {
+        placement.building = None;
+    }
+
+    placement.just_placed = true;
+}
+
+fn client_placement_ghost_system(
+    commands: &mut Commands,
+    placement: Res<Placement>,
+    placement_materials: Res<PlacementMaterials>,
+    placement_grid: Res<PlacementGrid>,
+    selected_units: Res<SelectedUnits>,
+    mouse_position: Res<MousePosition>,
+    buildings: Res<Assets<Building>>,
+    mut query: Query<(Entity, &mut Transform, &mut Handle<ColorMaterial>), With<PlacementGhost>>,
+) {
+    let origin = TilePosition::from_position(mouse_position.position());
+
+    let tiles = placement
+        .building
+        .as_ref()
+        .and_then(|building| buildings.get(&buildings.get_handle(building.as_str())))
+        .map_or_else(Vec::new, |building| building.tiles(&origin));
+
+    let ignore: Vec<Entity> = selected_units.units.iter().cloned().collect();
+    let material = if placement_grid.is_free(&tiles, &ignore) {
+        placement_materials.valid.clone()
+    } else {
+        placement_materials.invalid.clone()
+    };
+
+    let translation = |tile: &TilePosition| {
+        let mut translation = *ISO_TO_SCREEN * tile.pos().extend(0.0);
+        translation.z = translation.y / -256.0;
+        translation
+    };
+
+    // the footprint changed, start over
+    if query.iter_mut().count() != tiles.len() {
+        for (entity, _, _) in query.iter_mut() {
+            commands.despawn(entity);
+        }
+
+        for tile in &tiles {
+            commands
+                .spawn(SpriteBundle {
+                    material: material.clone(),
+                    transform: Transform::from_translation(translation(tile)),
+                    ..Default::default()
+                })
+                .with(PlacementGhost);
+        }
+
+        return;
+    }
+
+    for ((_, mut transform, mut ghost_material), tile) in query.iter_mut().zip(tiles.iter()) {
+        transform.translation = translation(tile);
+        *ghost_material = material.clone();
+    }
+}
+
+pub struct PlacementPlugin(pub bool);
+
+impl PlacementPlugin {
+    pub fn server() -> Self {
+        Self(true)
+    }
+
+    pub fn client() -> Self {
+        Self(false)
+    }
+}
+
+impl Plugin for PlacementPlugin {
+    fn build(&self, app_builder: &mut AppBuilder) {
+        app_builder.init_resource::<PlacementGrid>();
+        // rebuilt every tick, so lockstep peers place and walk around the same buildings
+        app_builder.add_system_to_stage(SIMULATION_STAGE, placement_grid_system.system());
+        app_builder.add_system_to_stage(SIMULATION_STAGE, construction_system.system());
+
+        if self.0 {
+            app_builder.add_system(server_construction_site_system.system());
+            app_builder.add_system_to_stage(
+                bevy::app::stage::LAST,
+                server_construction_sync_system.system(),
+            );
+        } else {
+            app_builder.init_resource::<Placement>();
+            app_builder.add_startup_system(client_placement_setup.system());
+            app_builder.add_system(client_placement_grid_system.system());
+            app_builder.add_system(client_construction_system.system());
+            app_builder.add_system(client_placement_input_system.system());
+            app_builder.add_system(client_placement_ghost_system.system());
+        }
+    }
+}
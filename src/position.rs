@@ -1,4 +1,15 @@
 use crate::*;
+use std::collections::{HashMap, VecDeque};
+
+// how many snapshots are kept around to diff against
+const SNAPSHOT_HISTORY_LENGTH: usize = 64;
+// keeps a single snapshot part well below the size of one packet
+const SNAPSHOT_PART_SIZE: usize = 32;
+// how far behind the server clock positions are rendered
+const INTERPOLATION_DELAY: f64 = 0.1;
+// how far past the newest sample positions may be extrapolated
+const MAX_EXTRAPOLATION: f64 = 0.25;
+const POSITION_BUFFER_LENGTH: usize = 32;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Position {
@@ -6,47 +17,410 @@ pub struct Position {
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct PositionMessage {
-    position: Position,
-    network_entity: NetworkEntity,
+pub struct PositionSnapshotMessage {
+    pub tick: u64,
+    pub time: f64,
+    pub baseline: Option<u64>,
+    pub part: u16,
+    pub parts: u16,
+    pub positions: Vec<(NetworkEntity, Vec3)>,
+    // entities in the baseline that no longer exist
+    pub removed: Vec<NetworkEntity>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SnapshotAckMessage {
+    pub tick: u64,
+}
+
+pub type PositionSnapshot = HashMap<NetworkEntity, Vec3>;
+
+#[derive(Default)]
+pub struct SnapshotHistory {
+    tick: u64,
+    // what every connection was sent, which is only what it knows about
+    snapshots: VecDeque<(u64, HashMap<ConnectionHandle, PositionSnapshot>)>,
+    acknowledged: HashMap<ConnectionHandle, u64>,
+}
+
+impl SnapshotHistory {
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    pub fn get(&self, tick: u64, handle: ConnectionHandle) -> Option<&PositionSnapshot> {
+        self.snapshots
+            .iter()
+            .find(|(snapshot_tick, _)| *snapshot_tick == tick)
+            .and_then(|(_, snapshots)| snapshots.get(&handle))
+    }
+
+    pub fn acknowledge(&mut self, handle: ConnectionHandle, tick: u64) {
+        let acknowledged = self.acknowledged.entry(handle).or_insert(tick);
+        *acknowledged = (*acknowledged).max(tick);
+    }
+
+    fn push(&mut self, snapshots: HashMap<ConnectionHandle, PositionSnapshot>) {
+        self.snapshots.push_back((self.tick, snapshots));
+
+        while self.snapshots.len() > SNAPSHOT_HISTORY_LENGTH {
+            self.snapshots.pop_front();
+        }
+    }
+}
+
+struct PartialSnapshot {
+    time: f64,
+    baseline: Option<u64>,
+    parts_received: u16,
+    parts: u16,
+    positions: Vec<(NetworkEntity, Vec3)>,
+    removed: Vec<NetworkEntity>,
+}
+
+#[derive(Default)]
+pub struct SnapshotReceiver {
+    latest_tick: Option<u64>,
+    snapshots: VecDeque<(u64, f64, PositionSnapshot)>,
+    partial: HashMap<u64, PartialSnapshot>,
+}
+
+impl SnapshotReceiver {
+    fn get(&self, tick: u64) -> Option<&PositionSnapshot> {
+        self.get_timed(tick).map(|(_, snapshot)| snapshot)
+    }
+
+    fn get_timed(&self, tick: u64) -> Option<(f64, &PositionSnapshot)> {
+        self.snapshots
+            .iter()
+            .find(|(snapshot_tick, _, _)| *snapshot_tick == tick)
+            .map(|(_, time, snapshot)| (*time, snapshot))
+    }
+
+    /// Collects a part of a snapshot, returns the tick of the snapshot if it was completed.
+    fn receive(&mut self, message: PositionSnapshotMessage) -> Option<u64> {
+        if self.get(message.tick).is_some() {
+            return None;
+        }
+
+        let partial = self
+            .partial
+            .entry(message.tick)
+            .or_insert_with(|| PartialSnapshot {
+                time: message.time,
+                baseline: message.baseline,
+                parts_received: 0,
+                parts: message.parts,
+                positions: Vec::new(),
+                removed: Vec::new(),
+            });
+
+        partial.parts_received += 1;
+        partial.positions.extend(message.positions);
+        partial.removed.extend(message.removed);
+
+        if partial.parts_received < partial.parts {
+            return None;
+        }
+
+        let partial = self.partial.remove(&message.tick).unwrap();
+
+        let mut snapshot = match partial.baseline {
+            Some(baseline) => match self.get(baseline) {
+                Some(baseline) => baseline.clone(),
+                // we no longer know the baseline, wait for the server to catch up
+                None => return None,
+            },
+            None => PositionSnapshot::new(),
+        };
+
+        for network_entity in partial.removed {
+            snapshot.remove(&network_entity);
+        }
+
+        snapshot.extend(partial.positions);
+
+        self.snapshots
+            .push_back((message.tick, partial.time, snapshot));
+
+        while self.snapshots.len() > SNAPSHOT_HISTORY_LENGTH {
+            self.snapshots.pop_front();
+        }
+
+        let (oldest, _, _) = self.snapshots.front().unwrap();
+        let oldest = *oldest;
+        self.partial.retain(|tick, _| *tick > oldest);
+
+        Some(message.tick)
+    }
+}
+
+/// The client's estimate of the current time on the server.
+#[derive(Default)]
+pub struct ServerClock {
+    time: Option<f64>,
+}
+
+impl ServerClock {
+    pub fn time(&self) -> Option<f64> {
+        self.time
+    }
+
+    pub fn render_time(&self) -> Option<f64> {
+        self.time.map(|time| time - INTERPOLATION_DELAY)
+    }
+
+    pub fn advance(&mut self, delta_seconds: f64) {
+        if let Some(time) = &mut self.time {
+            *time += delta_seconds;
+        }
+    }
+
+    pub fn synchronize(&mut self, server_time: f64) {
+        match &mut self.time {
+            // we're behind, late packets only ever make us look further behind
+            Some(time) if server_time > *time => *time = server_time,
+            // drift back slowly, so a single early estimate doesn't stick forever
+            Some(time) => *time += (server_time - *time) * 0.05,
+            None => self.time = Some(server_time),
+        }
+    }
+}
+
+/// Timestamped server positions, rendered a fixed delay behind the server.
+#[derive(Default)]
+pub struct PositionBuffer {
+    samples: VecDeque<(f64, Vec3)>,
+}
+
+impl PositionBuffer {
+    pub fn push(&mut self, time: f64, position: Vec3) {
+        if let Some((last_time, _)) = self.samples.back() {
+            if time <= *last_time {
+                return;
+            }
+        }
+
+        self.samples.push_back((time, position));
+
+        while self.samples.len() > POSITION_BUFFER_LENGTH {
+            self.samples.pop_front();
+        }
+    }
+
+    pub fn sample(&self, time: f64) -> Option<Vec3> {
+        let (first_time, first) = *self.samples.front()?;
+        let (last_time, last) = *self.samples.back()?;
+
+        if time <= first_time {
+            return Some(first);
+        }
+
+        if time >= last_time {
+            if self.samples.len() < 2 {
+                return Some(last);
+            }
+
+            // we're missing packets, keep moving along the last known velocity for a bit
+            let (previous_time, previous) = self.samples[self.samples.len() - 2];
+            let velocity = (last - previous) / (last_time - previous_time) as f32;
+            let extrapolation = (time - last_time).min(MAX_EXTRAPOLATION) as f32;
+
+            return Some(last + velocity * extrapolation);
+        }
+
+        for i in 1..self.samples.len() {
+            let (b_time, b) = self.samples[i];
+
+            if time <= b_time {
+                let (a_time, a) = self.samples[i - 1];
+                let t = ((time - a_time) / (b_time - a_time)) as f32;
+
+                return Some(a + (b - a) * t);
+            }
+        }
+
+        Some(last)
+    }
+}
+
+pub fn server_clock_system(time: Res<Time>, mut server_clock: ResMut<ServerClock>) {
+    server_clock.advance(time.delta_seconds_f64());
 }
 
-pub fn position_system(mut query: Query<(&Position, &mut Transform)>) {
-    for (position, mut transform) in query.iter_mut() {
-        transform.translation = *isometric::ISO_TO_SCREEN * position.position;
+pub fn position_buffer_system(
+    commands: &mut Commands,
+    query: Query<Entity, (With<Position>, With<NetworkEntity>, Without<PositionBuffer>)>,
+) {
+    for entity in query.iter() {
+        commands.insert_one(entity, PositionBuffer::default());
+    }
+}
+
+pub fn position_system(
+    server_clock: Res<ServerClock>,
+    mut query: Query<(&Position, Option<&PositionBuffer>, &mut Transform)>,
+) {
+    let render_time = server_clock.render_time();
+
+    for (position, position_buffer, mut transform) in query.iter_mut() {
+        let position = match (position_buffer, render_time) {
+            (Some(position_buffer), Some(render_time)) => position_buffer
+                .sample(render_time)
+                .unwrap_or(position.position),
+            _ => position.position,
+        };
+
+        transform.translation = *isometric::ISO_TO_SCREEN * position;
         transform.translation.z = transform.translation.y / -256.0;
     }
 }
 
 pub fn server_network_position_system(
-    mut net: ResMut<NetworkResource>,
+    time: Res<Time>,
+    simulation_mode: Res<SimulationMode>,
+    fixed_tick: Res<FixedTick>,
+    mut net: ResMut<Network>,
+    mut rejections: ResMut<Rejections>,
+    mut snapshot_history: ResMut<SnapshotHistory>,
+    vision: Res<Vision>,
     query: Query<(&Position, &NetworkEntity)>,
 ) {
-    for (position, network_entity) in query.iter() {
-        let message = PositionMessage {
-            position: position.clone(),
-            network_entity: network_entity.clone(),
+    // positions are simulated by every peer in lockstep
+    if simulation_mode.is_lockstep() {
+        return;
+    }
+
+    for (handle, ack) in net.recv::<SnapshotAckMessage>() {
+        // acknowledging the future would have us diff against snapshots the client never got
+        if ack.tick > snapshot_history.tick {
+            rejections.reject(&mut net, handle, Rejection::InvalidTick(ack.tick));
+            continue;
+        }
+
+        snapshot_history.acknowledge(handle, ack.tick);
+    }
+
+    // snapshots are numbered by simulation tick, nothing moved if it didn't advance
+    let tick = fixed_tick.tick();
+    if tick == snapshot_history.tick && !snapshot_history.snapshots.is_empty() {
+        return;
+    }
+
+    snapshot_history.tick = tick;
+
+    let handles: Vec<ConnectionHandle> = net.connections().cloned().collect();
+    let mut snapshots = HashMap::new();
+
+    for handle in handles {
+        // nobody gets the positions of what they can't see
+        let snapshot: PositionSnapshot = query
+            .iter()
+            .filter(|(_, network_entity)| vision.knows(handle, **network_entity))
+            .map(|(position, network_entity)| (*network_entity, position.position))
+            .collect();
+
+        // diff against the latest snapshot the client has acknowledged, if we still have it
+        let baseline = snapshot_history.acknowledged.get(&handle).and_then(|tick| {
+            snapshot_history
+                .get(*tick, handle)
+                .map(|snapshot| (*tick, snapshot))
+        });
+
+        let positions: Vec<(NetworkEntity, Vec3)> = snapshot
+            .iter()
+            .filter(|(network_entity, position)| match baseline {
+                Some((_, baseline)) => baseline.get(*network_entity) != Some(*position),
+                None => true,
+            })
+            .map(|(network_entity, position)| (*network_entity, *position))
+            .collect();
+
+        let removed: Vec<NetworkEntity> = match baseline {
+            Some((_, baseline)) => baseline
+                .keys()
+                .filter(|network_entity| !snapshot.contains_key(network_entity))
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        };
+
+        // an empty snapshot is still sent, so the client can acknowledge the tick
+        let chunks: Vec<&[(NetworkEntity, Vec3)]> = if positions.is_empty() {
+            vec![&[][..]]
+        } else {
+            positions.chunks(SNAPSHOT_PART_SIZE).collect()
         };
 
-        net.broadcast_message(message);
+        for (part, chunk) in chunks.iter().enumerate() {
+            let message = PositionSnapshotMessage {
+                tick,
+                time: time.seconds_since_startup(),
+                baseline: baseline.map(|(tick, _)| tick),
+                part: part as u16,
+                parts: chunks.len() as u16,
+                positions: chunk.to_vec(),
+                // removals are small, so they ride along with the first part
+                removed: if part == 0 {
+                    removed.clone()
+                } else {
+                    Vec::new()
+                },
+            };
+
+            net.send_message(handle, message);
+        }
+
+        snapshots.insert(handle, snapshot);
     }
+
+    snapshot_history.push(snapshots);
 }
 
 pub fn client_network_position_system(
-    mut net: ResMut<NetworkResource>,
+    mut net: ResMut<Network>,
+    mut snapshot_receiver: Local<SnapshotReceiver>,
+    mut server_clock: ResMut<ServerClock>,
     network_entity_registry: Res<NetworkEntityRegistry>,
-    mut query: Query<&mut Position>,
+    mut query: Query<(&mut Position, Option<&mut PositionBuffer>)>,
 ) {
-    for (_handle, connection) in net.connections.iter_mut() {
-        let channels = connection.channels().unwrap();
+    let mut completed = Vec::new();
+
+    for (_handle, snapshot_message) in net.recv::<PositionSnapshotMessage>() {
+        if let Some(tick) = snapshot_receiver.receive(snapshot_message) {
+            completed.push(tick);
+        }
+    }
+
+    for tick in completed {
+        net.broadcast_message(SnapshotAckMessage { tick });
+
+        if snapshot_receiver
+            .latest_tick
+            .map_or(false, |latest| latest > tick)
+        {
+            continue;
+        }
+
+        snapshot_receiver.latest_tick = Some(tick);
+
+        let (time, snapshot) = snapshot_receiver.get_timed(tick).unwrap();
+        server_clock.synchronize(time);
+
+        // snapshots are complete states, so every entity gets a sample, even when standing still
+        for (network_entity, position) in snapshot {
+            if let Some(entity) = network_entity_registry.get(network_entity) {
+                if let Ok((mut current, position_buffer)) = query.get_mut(*entity) {
+                    current.position = *position;
 
-        while let Some(position_message) = channels.recv::<PositionMessage>() {
-            if let Some(entity) = network_entity_registry.get(&position_message.network_entity) {
-                *query.get_mut(*entity).unwrap() = position_message.position;
+                    if let Some(mut position_buffer) = position_buffer {
+                        position_buffer.push(time, *position);
+                    }
+                }
             } else {
                 warn!(
-                    "Got position mesage for unregistered network entity: {:?}",
-                    position_message.network_entity
+                    "Got position snapshot for unregistered network entity: {:?}",
+                    network_entity
                 );
             }
         }
@@ -68,8 +442,12 @@ impl PositionPlugin {
 impl Plugin for PositionPlugin {
     fn build(&self, app_builder: &mut AppBuilder) {
         if self.0 {
+            app_builder.init_resource::<SnapshotHistory>();
             app_builder.add_system(server_network_position_system.system());
         } else {
+            app_builder.init_resource::<ServerClock>();
+            app_builder.add_system(server_clock_system.system());
+            app_builder.add_system(position_buffer_system.system());
             app_builder.add_system(client_network_position_system.system());
             app_builder.add_system(position_system.system());
         }
@@ -0,0 +1,767 @@
+use crate::*;
+use std::collections::HashMap;
+
+const REPLAY_SAVE_INTERVAL: f32 = 5.0;
+const REPLAY_STATUS_INTERVAL: f32 = 0.25;
+const REPLAY_SEEK_SECONDS: f32 = 10.0;
+const REPLAY_MIN_SPEED: f32 = 0.25;
+const REPLAY_MAX_SPEED: f32 = 16.0;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RecordedPlayer {
+    pub player_id: PlayerId,
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum RecordedAction {
+    Command(CommandMessage),
+    Production(ProductionMessage),
+    Research(ResearchMessage),
+    /// The player's units are removed, after they abandoned the match.
+    RemovePlayer(PlayerId),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RecordedEvent {
+    /// The simulation tick the action was executed at, counted from the start of the match.
+    pub tick: u64,
+    pub action: RecordedAction,
+}
+
+/// Everything needed to simulate a match again, as written to a replay file.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Recording {
+    pub map: String,
+    /// The gameplay assets have to match for the replay to play out the same.
+    pub content_hash: u64,
+    pub players: Vec<RecordedPlayer>,
+    pub events: Vec<RecordedEvent>,
+    /// How many ticks the match lasted.
+    pub ticks: u64,
+    #[serde(default = "default_tick_rate")]
+    pub tick_rate: u32,
+}
+
+fn default_tick_rate() -> u32 {
+    DEFAULT_TICK_RATE
+}
+
+impl Recording {
+    pub fn load(path: &str) -> Result<Self, anyhow::Error> {
+        let bytes = std::fs::read(path)?;
+        Ok(ron::de::from_bytes(&bytes)?)
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), anyhow::Error> {
+        std::fs::write(path, ron::ser::to_string(self)?)?;
+        Ok(())
+    }
+
+    pub fn player_ids(&self) -> Vec<PlayerId> {
+        let mut player_ids: Vec<PlayerId> =
+            self.players.iter().map(|player| player.player_id).collect();
+
+        player_ids.sort();
+        player_ids
+    }
+
+    pub fn lobby_state(&self, teams: &Teams) -> LobbyState {
+        let mut slots: Vec<LobbySlot> = self
+            .players
+            .iter()
+            .map(|player| LobbySlot {
+                player_id: player.player_id,
+                team: teams.team(player.player_id),
+                player: Some(LobbyPlayer {
+                    name: player.name.clone(),
+                    ready: true,
+                }),
+            })
+            .collect();
+
+        slots.sort_by_key(|slot| slot.player_id.0);
+
+        LobbyState {
+            slots,
+            started: true,
+        }
+    }
+}
+
+/// Records the match on the server, if it was asked to.
+pub struct ReplayRecorder {
+    path: Option<String>,
+    recording: Recording,
+    started: bool,
+    /// The first tick simulated with the match's entities.
+    start_tick: Option<u64>,
+    changed: bool,
+    finished: bool,
+}
+
+impl ReplayRecorder {
+    pub fn new(path: Option<String>, map: String) -> Self {
+        Self {
+            path,
+            recording: Recording {
+                map,
+                ..Default::default()
+            },
+            started: false,
+            start_tick: None,
+            changed: false,
+            finished: false,
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.path.is_some() && self.started
+    }
+
+    /// Starts recording, once the players are known.
+    pub fn start(&mut self, players: Vec<RecordedPlayer>) {
+        if self.path.is_none() {
+            return;
+        }
+
+        self.recording.players = players;
+        self.started = true;
+        self.changed = true;
+    }
+
+    pub fn record(&mut self, tick: u64, action: RecordedAction) {
+        if !self.is_recording() {
+            return;
+        }
+
+        let tick = tick.saturating_sub(self.start_tick.unwrap_or(tick));
+        self.recording.events.push(RecordedEvent { tick, action });
+        self.changed = true;
+    }
+
+    /// Writes out the recording one last time, once the match is decided.
+    pub fn finish(&mut self) {
+        if self.is_recording() {
+            self.finished = true;
+        }
+    }
+}
+
+fn server_replay_record_system(
+    mut timer: Local<Timer>,
+    time: Res<Time>,
+    simulation_mode: Res<SimulationMode>,
+    content_hash: Res<ContentHash>,
+    fixed_tick: Res<FixedTick>,
+    lockstep: Res<Lockstep>,
+    mut replay_recorder: ResMut<ReplayRecorder>,
+) {
+    if !replay_recorder.is_recording() {
+        return;
+    }
+
+    let tick = match *simulation_mode {
+        SimulationMode::Authoritative => fixed_tick.tick(),
+        SimulationMode::Lockstep => lockstep.tick(),
+    };
+
+    // the map is spawned at the end of the frame the match started in,
+    // so the recording starts with the first tick that can see it
+    let start_tick = *replay_recorder.start_tick.get_or_insert(tick);
+
+    timer.set_duration(REPLAY_SAVE_INTERVAL);
+    timer.set_repeating(true);
+    timer.tick(time.delta_seconds());
+
+    let finished = replay_recorder.finished;
+
+    if !timer.just_finished() && !finished {
+        return;
+    }
+
+    let ticks = tick - start_tick;
+
+    if !replay_recorder.changed && replay_recorder.recording.ticks == ticks && !finished {
+        return;
+    }
+
+    replay_recorder.recording.ticks = ticks;
+    replay_recorder.recording.content_hash = content_hash.0;
+    replay_recorder.recording.tick_rate = fixed_tick.tick_rate();
+    replay_recorder.changed = false;
+
+    // the whole file is rewritten, so it's always complete if the server goes down
+    let path = replay_recorder.path.clone().unwrap();
+    if let Err(err) = replay_recorder.recording.save(&path) {
+        error!("Failed to save replay to {}: {}", path, err);
+    }
+
+    // only the first match played on the server is recorded
+    if finished {
+        info!("replay saved to {}", path);
+        replay_recorder.path = None;
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum ReplayControlMessage {
+    SetPaused(bool),
+    SetSpeed(f32),
+    Seek(u64),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ReplayStatusMessage {
+    pub tick: u64,
+    pub ticks: u64,
+    pub speed: f32,
+    pub paused: bool,
+}
+
+#[derive(Clap)]
+pub struct Replay {
+    /// The replay file recorded by a server.
+    file: String,
+    #[clap(short, long, default_value = "35566")]
+    port: u16,
+    /// Simulates the whole replay as fast as possible and exits, instead of waiting for spectators.
+    #[clap(long)]
+    headless: bool,
+    #[clap(long, default_value = "1")]
+    speed: f32,
+}
+
+impl Replay {
+    pub fn run(self) {
+        let transport = if self.headless {
+            Transport::Loopback(LoopbackHub::default().endpoint())
+        } else {
+            Transport::Turbulence
+        };
+
+        self.app(transport).run();
+    }
+
+    /// Builds an app simulating the replay, which spectators can connect to.
+    pub fn app(self, transport: Transport) -> AppBuilder {
+        let listen = transport.is_turbulence();
+        let recording = Recording::load(&self.file)
+            .unwrap_or_else(|err| panic!("Failed to load replay {}: {}", self.file, err));
+        let mut app_builder = App::build();
+
+        app_builder
+            // resources
+            .init_resource::<NetworkEntityRegistry>()
+            .add_resource(SimulationMode::Authoritative)
+            .add_resource(Rejections::new(false))
+            .add_resource(ReplayRecorder::new(None, recording.map.clone()))
+            .add_resource(ReplayPlayback::new(recording, self.headless))
+            .add_resource(self)
+            .add_resource(NetworkSettings::server())
+            .add_resource(bevy::app::ScheduleRunnerSettings::run_loop(
+                std::time::Duration::from_secs_f64(1.0 / 60.0),
+            ))
+            // plugins
+            .add_plugins(MinimalPlugins)
+            .add_plugin(bevy::asset::AssetPlugin)
+            .add_plugin(bevy::transform::TransformPlugin)
+            .add_plugin(bevy::reflect::ReflectPlugin)
+            .add_plugin(bevy::log::LogPlugin)
+            .add_plugin(SimulationPlugin)
+            // the grids are rebuilt before anything in the simulation uses them
+            .add_plugin(PlacementPlugin::server())
+            .add_plugin(NavigationPlugin)
+            .add_plugin(LockstepPlugin::server())
+            // the recorded actions are applied where lockstep applies its commands
+            .add_system_to_stage(SIMULATION_STAGE, replay_action_system.system())
+            .add_plugin(SpawnPlugin::server())
+            .add_plugin(AnimationPlugin::server())
+            .add_plugin(TileMapPlugin::server())
+            .add_plugin(UnitPlugin::server())
+            .add_plugin(ConnectionPlugin::server())
+            .add_plugin(TeamPlugin::server())
+            .add_plugin(EconomyPlugin::server())
+            .add_plugin(BuildingPlugin::server())
+            .add_plugin(UpgradePlugin::server())
+            .add_plugin(PositionPlugin::server())
+            .add_plugin(MapPlugin)
+            .add_plugin(TransportPlugin(transport))
+            .add_plugin(NetworkStatsPlugin::server())
+            .add_plugin(UnitAnimationPlugin)
+            // startup systems
+            .add_startup_system(replay_setup.system())
+            // systems
+            .add_system(replay_connection_system.system())
+            .add_system(replay_control_system.system())
+            .add_system(replay_playback_system.system());
+
+        if listen {
+            app_builder.add_startup_system(replay_listen_system.system());
+        }
+
+        app_builder
+    }
+}
+
+enum PlaybackState {
+    Loading,
+    /// Restarting the replay to seek backwards, the world is cleared first.
+    Despawning(u64),
+    Spawning(u64),
+    Playing,
+}
+
+/// The replay being simulated.
+pub struct ReplayPlayback {
+    recording: Recording,
+    headless: bool,
+    paused: bool,
+    next_event: usize,
+    /// The tick the replay started at, the recorded ticks are relative to it.
+    start_tick: u64,
+    state: PlaybackState,
+}
+
+impl ReplayPlayback {
+    pub fn new(recording: Recording, headless: bool) -> Self {
+        Self {
+            recording,
+            headless,
+            paused: false,
+            next_event: 0,
+            start_tick: 0,
+            state: PlaybackState::Loading,
+        }
+    }
+
+    fn is_playing(&self) -> bool {
+        matches!(self.state, PlaybackState::Playing)
+    }
+
+    fn tick(&self, fixed_tick: &FixedTick) -> u64 {
+        fixed_tick.tick().saturating_sub(self.start_tick)
+    }
+}
+
+fn replay_setup(
+    commands: &mut Commands,
+    replay: Res<Replay>,
+    playback: Res<ReplayPlayback>,
+    content_hash: Res<ContentHash>,
+    asset_server: Res<AssetServer>,
+    mut fixed_tick: ResMut<FixedTick>,
+) {
+    // we store these so they wont be automatically freed
+    let handles = asset_server.load_folder(".").unwrap();
+    commands.insert_resource(handles);
+
+    let map_handle: Handle<Map> = asset_server.load(playback.recording.map.as_str());
+    commands.insert_resource(map_handle);
+
+    if playback.recording.content_hash != content_hash.0 {
+        warn!(
+            "The replay was recorded with different gameplay assets, it might play out differently"
+        );
+    }
+
+    fixed_tick.set_tick_rate(playback.recording.tick_rate);
+    fixed_tick.speed = replay.speed.max(REPLAY_MIN_SPEED).min(REPLAY_MAX_SPEED);
+}
+
+fn replay_listen_system(mut net: ResMut<NetworkResource>, replay: Res<Replay>) {
+    let ip_address = bevy_networking_turbulence::find_my_ip_address().unwrap();
+    let socket_address = std::net::SocketAddr::new(ip_address, replay.port);
+    info!("Playing replay at: {}", socket_address);
+    net.listen(socket_address);
+}
+
+/// Only lets spectators in, there's nothing left to play.
+fn replay_connection_system(
+    mut net: ResMut<Network>,
+    mut reader: Local<EventReader<NetworkEvent>>,
+    mut handshake_reader: Local<EventReader<HandshakeEvent>>,
+    mut players: ResMut<Players>,
+    mut synchronize_events: ResMut<Events<SynchronizeEvent>>,
+    playback: Res<ReplayPlayback>,
+    fixed_tick: Res<FixedTick>,
+    teams: Res<Teams>,
+    events: Res<Events<NetworkEvent>>,
+    handshake_events: Res<Events<HandshakeEvent>>,
+) {
+    for HandshakeEvent {
+        handle, spectator, ..
+    } in handshake_reader.iter(&handshake_events)
+    {
+        if !*spectator {
+            net.send_message(
+                *handle,
+                ConnectionMessage::Rejected(
+                    "this server is playing a replay, join as a spectator instead".to_string(),
+                ),
+            );
+            net.disconnect(*handle);
+            continue;
+        }
+
+        info!("{} is watching the replay", handle);
+
+        players.spectators.insert(*handle);
+        net.send_message(
+            *handle,
+            ConnectionMessage::Spectator(SimulationMode::Authoritative, fixed_tick.tick_rate()),
+        );
+        net.send_message(
+            *handle,
+            LobbyMessage::State(playback.recording.lobby_state(&teams)),
+        );
+
+        if playback.is_playing() {
+            synchronize_events.send(SynchronizeEvent(*handle));
+        }
+    }
+
+    for event in reader.iter(&events) {
+        if let NetworkEvent::Disconnected(handle) = event {
+            players.spectators.remove(handle);
+        }
+    }
+}
+
+fn replay_control_system(
+    mut net: ResMut<Network>,
+    players: Res<Players>,
+    mut playback: ResMut<ReplayPlayback>,
+    mut fixed_tick: ResMut<FixedTick>,
+) {
+    for (handle, control_message) in net.recv::<ReplayControlMessage>() {
+        // rejected peers may still be connected until the disconnect goes through
+        if !players.spectators.contains(&handle) {
+            continue;
+        }
+
+        match control_message {
+            ReplayControlMessage::SetPaused(pause) => playback.paused = pause,
+            ReplayControlMessage::SetSpeed(speed) => {
+                fixed_tick.speed = speed.max(REPLAY_MIN_SPEED).min(REPLAY_MAX_SPEED);
+            }
+            ReplayControlMessage::Seek(tick) if !playback.is_playing() => {
+                warn!("Can't seek to tick {} while restarting", tick);
+            }
+            ReplayControlMessage::Seek(tick) => {
+                let target = tick.min(playback.recording.ticks);
+
+                // the simulation can only go forwards, so going back means starting over
+                if target < playback.tick(&fixed_tick) {
+                    playback.state = PlaybackState::Despawning(target);
+                } else {
+                    fixed_tick.fast_forward = Some(playback.start_tick + target);
+                }
+            }
+        }
+    }
+}
+
+fn replay_playback_system(
+    commands: &mut Commands,
+    mut timer: Local<Timer>,
+    time: Res<Time>,
+    mut net: ResMut<Network>,
+    mut playback: ResMut<ReplayPlayback>,
+    mut fixed_tick: ResMut<FixedTick>,
+    mut paused: ResMut<SimulationPaused>,
+    mut network_entity_registry: ResMut<NetworkEntityRegistry>,
+    mut stockpiles: ResMut<Stockpiles>,
+    mut upgrades: ResMut<Upgrades>,
+    mut app_exit_events: ResMut<Events<bevy::app::AppExit>>,
+    maps: Res<Assets<Map>>,
+    map_handle: Res<Handle<Map>>,
+    spawn_resource: Res<SpawnResource>,
+    entities: Query<Entity, With<NetworkEntity>>,
+    units: Query<(&Owner, &UnitInstance)>,
+) {
+    let ticks = playback.recording.ticks;
+
+    match playback.state {
+        PlaybackState::Loading => {
+            if maps.get(&*map_handle).is_some() {
+                playback.state = PlaybackState::Spawning(0);
+            }
+
+            paused.0 = true;
+        }
+        PlaybackState::Despawning(target) => {
+            for entity in entities.iter() {
+                commands.despawn_recursive(entity);
+            }
+
+            playback.state = PlaybackState::Spawning(target);
+            paused.0 = true;
+        }
+        PlaybackState::Spawning(target) => {
+            let map = maps.get(&*map_handle).unwrap();
+
+            // gives the entities the same network ids as in the recorded match
+            *network_entity_registry = NetworkEntityRegistry::new();
+            map.spawn_players(&playback.recording.player_ids(), &spawn_resource);
+            stockpiles.reset(map.starting_stockpiles(&playback.recording.player_ids()));
+            upgrades.reset(HashMap::new(), HashMap::new());
+
+            // nothing is simulated this frame, the entities are spawned at the end of it
+            playback.start_tick = fixed_tick.tick();
+            playback.next_event = 0;
+
+            if playback.headless {
+                info!("simulating {} ticks", ticks);
+                fixed_tick.fast_forward = Some(playback.start_tick + ticks);
+            } else if target > 0 {
+                fixed_tick.fast_forward = Some(playback.start_tick + target);
+            } else {
+                fixed_tick.fast_forward = None;
+            }
+
+            playback.state = PlaybackState::Playing;
+            paused.0 = true;
+        }
+        PlaybackState::Playing => {
+            if playback.tick(&fixed_tick) >= ticks && fixed_tick.fast_forward.is_none() {
+                if playback.headless {
+                    log_replay_summary(&playback.recording, &units);
+                    app_exit_events.send(bevy::app::AppExit);
+                } else if !playback.paused {
+                    info!("replay finished");
+                    playback.paused = true;
+                }
+            }
+
+            // seeking works while paused as well
+            paused.0 = playback.paused && fixed_tick.fast_forward.is_none();
+        }
+    }
+
+    timer.set_duration(REPLAY_STATUS_INTERVAL);
+    timer.set_repeating(true);
+    timer.tick(time.delta_seconds());
+
+    if timer.just_finished() {
+        net.broadcast_message(ReplayStatusMessage {
+            tick: playback.tick(&fixed_tick),
+            ticks,
+            speed: fixed_tick.speed,
+            paused: playback.paused,
+        });
+    }
+}
+
+fn log_replay_summary(recording: &Recording, units: &Query<(&Owner, &UnitInstance)>) {
+    info!("replay finished after {} ticks", recording.ticks);
+
+    for player in &recording.players {
+        let (count, health) = units
+            .iter()
+            .filter(|(owner, _)| owner.0 == player.player_id)
+            .fold((0, 0.0), |(count, health), (_, unit_instance)| {
+                (count + 1, health + unit_instance.health)
+            });
+
+        info!(
+            "{} ({:?}): {} units left with {} health",
+            player.name, player.player_id, count, health
+        );
+    }
+}
+
+fn replay_action_system(
+    fixed_tick: Res<FixedTick>,
+    mut playback: ResMut<ReplayPlayback>,
+    network_entity_registry: Res<NetworkEntityRegistry>,
+    buildings: Res<Assets<Building>>,
+    upgrade_assets: Res<Assets<Upgrade>>,
+    mut stockpiles: ResMut<Stockpiles>,
+    mut upgrades: ResMut<Upgrades>,
+    mut query: Query<&mut CommandQueue>,
+    mut unit_query: Query<(&Owner, &mut UnitInstance)>,
+    mut building_query: Query<(&Owner, &Handle<Building>, &mut ProductionQueue)>,
+    research_query: Query<(&Owner, &Handle<Building>)>,
+) {
+    let tick = playback.tick(&fixed_tick);
+
+    while let Some(event) = playback.recording.events.get(playback.next_event) {
+        if event.tick > tick {
+            break;
+        }
+
+        match &event.action {
+            RecordedAction::Command(command_message) => {
+                if let Some(entity) = network_entity_registry.get(&command_message.network_entity) {
+                    if let Ok(mut command_queue) = query.get_mut(*entity) {
+                        command_queue.apply(command_message.operation.clone());
+                    }
+                }
+            }
+            RecordedAction::Production(production_message) => {
+                apply_production_message(
+                    production_message,
+                    &network_entity_registry,
+                    &buildings,
+                    &mut stockpiles,
+                    &mut building_query,
+                );
+            }
+            RecordedAction::Research(research_message) => {
+                apply_research_message(
+                    research_message,
+                    &network_entity_registry,
+                    &buildings,
+                    &upgrade_assets,
+                    &mut upgrades,
+                    &mut stockpiles,
+                    &research_query,
+                );
+            }
+            RecordedAction::RemovePlayer(player_id) => {
+                for (owner, mut unit_instance) in unit_query.iter_mut() {
+                    if owner.0 == *player_id {
+                        unit_instance.set_health(0.0);
+                    }
+                }
+            }
+        }
+
+        playback.next_event += 1;
+    }
+}
+
+/// The state of the replay the client is watching, if it is watching one.
+#[derive(Default)]
+pub struct ReplayStatus(pub Option<ReplayStatusMessage>);
+
+pub struct ReplayText;
+
+fn client_replay_setup(commands: &mut Commands, asset_server: Res<AssetServer>) {
+    commands
+        .spawn(TextBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: Rect {
+                    top: Val::Px(16.0),
+                    right: Val::Px(16.0),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            text: Text {
+                value: String::new(),
+                font: asset_server.load("fonts/DejaVuSans.ttf"),
+                style: TextStyle {
+                    font_size: 24.0,
+                    color: Color::WHITE,
+                    ..Default::default()
+                },
+            },
+            ..Default::default()
+        })
+        .with(ReplayText);
+}
+
+fn format_ticks(ticks: u64, fixed_tick: &FixedTick) -> String {
+    let seconds = (ticks as f32 * fixed_tick.tick_seconds()) as u64;
+    format!("{:02}:{:02}", seconds / 60, seconds % 60)
+}
+
+fn client_replay_system(
+    mut net: ResMut<Network>,
+    mut replay_status: ResMut<ReplayStatus>,
+    keyboard_input: Res<Input<KeyCode>>,
+    mouse_input: Res<Input<MouseButton>>,
+    input_config: Res<Assets<InputConfig>>,
+    input_resource: Res<InputResource>,
+    fixed_tick: Res<FixedTick>,
+    mut query: Query<&mut Text, With<ReplayText>>,
+) {
+    for (_handle, status_message) in net.recv::<ReplayStatusMessage>() {
+        replay_status.0 = Some(status_message);
+    }
+
+    let status = match &replay_status.0 {
+        Some(status) => status,
+        None => return,
+    };
+
+    let value = format!(
+        "Replay {} / {}  x{}{}",
+        format_ticks(status.tick, &fixed_tick),
+        format_ticks(status.ticks, &fixed_tick),
+        status.speed,
+        if status.paused { "  (paused)" } else { "" }
+    );
+
+    for mut text in query.iter_mut() {
+        if text.value != value {
+            text.value = value.clone();
+        }
+    }
+
+    let input_config = match input_config.get(&input_resource.0) {
+        Some(input_config) => input_config,
+        None => return,
+    };
+
+    let seek_ticks = (REPLAY_SEEK_SECONDS / fixed_tick.tick_seconds()) as u64;
+
+    let control_message = if input_config
+        .replay_pause
+        .just_pressed(&keyboard_input, &mouse_input)
+    {
+        ReplayControlMessage::SetPaused(!status.paused)
+    } else if input_config
+        .replay_faster
+        .just_pressed(&keyboard_input, &mouse_input)
+    {
+        ReplayControlMessage::SetSpeed(status.speed * 2.0)
+    } else if input_config
+        .replay_slower
+        .just_pressed(&keyboard_input, &mouse_input)
+    {
+        ReplayControlMessage::SetSpeed(status.speed / 2.0)
+    } else if input_config
+        .replay_forward
+        .just_pressed(&keyboard_input, &mouse_input)
+    {
+        ReplayControlMessage::Seek(status.tick + seek_ticks)
+    } else if input_config
+        .replay_backward
+        .just_pressed(&keyboard_input, &mouse_input)
+    {
+        ReplayControlMessage::Seek(status.tick.saturating_sub(seek_ticks))
+    } else {
+        return;
+    };
+
+    net.broadcast_message(control_message);
+}
+
+/// Records matches on the server, and lets clients control a replay they are watching.
+pub struct ReplayPlugin(pub bool);
+
+impl ReplayPlugin {
+    pub fn server() -> Self {
+        Self(true)
+    }
+
+    pub fn client() -> Self {
+        Self(false)
+    }
+}
+
+impl Plugin for ReplayPlugin {
+    fn build(&self, app_builder: &mut AppBuilder) {
+        if self.0 {
+            app_builder.add_system(server_replay_record_system.system());
+        } else {
+            app_builder.init_resource::<ReplayStatus>();
+            app_builder.add_startup_system(client_replay_setup.system());
+            app_builder.add_system(client_replay_system.system());
+        }
+    }
+}
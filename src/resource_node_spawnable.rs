@@ -0,0 +1,42 @@
+use crate::*;
+use bevy::render::pipeline::{RenderPipeline, RenderPipelines};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ResourceNodeSpawnable {
+    pub position: Vec3,
+    pub amount: f32,
+    pub size: f32,
+    pub texture: String,
+}
+
+#[typetag::serde]
+impl Spawnable for ResourceNodeSpawnable {
+    fn spawn(&self, commands: &mut Commands, resources: &Resources) -> Entity {
+        let network_settings = resources.get::<NetworkSettings>().unwrap();
+
+        commands
+            .spawn((ResourceNode {
+                amount: self.amount,
+                size: self.size,
+            },))
+            .with(Position {
+                position: self.position,
+            });
+
+        if network_settings.is_client() {
+            let textures = resources.get::<Assets<Texture>>().unwrap();
+            let mut color_materials = resources.get_mut::<Assets<ColorMaterial>>().unwrap();
+            let texture = textures.get_handle(self.texture.as_str());
+
+            commands.with_bundle(SpriteBundle {
+                material: color_materials.add(texture.into()),
+                render_pipelines: RenderPipelines::from_pipelines(vec![RenderPipeline::new(
+                    SPRITE_PIPELINE_HANDLE.typed(),
+                )]),
+                ..Default::default()
+            });
+        }
+
+        commands.current_entity().unwrap()
+    }
+}
@@ -0,0 +1,303 @@
+use crate::*;
+use std::collections::HashMap;
+
+/// A [`Behaviour`] with its target stored as a network id, so it survives a save.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum SavedBehaviour {
+    Move {
+        target: Vec2,
+        #[serde(default)]
+        flow_field: Option<Vec2>,
+    },
+    Attack {
+        target_position: Vec2,
+        target: NetworkEntity,
+        damage: HashMap<u32, f32>,
+    },
+    Harvest {
+        target_position: Vec2,
+        target: NetworkEntity,
+    },
+    DropOff,
+    Construct {
+        target_position: Vec2,
+        target: NetworkEntity,
+    },
+    Idle,
+}
+
+impl SavedBehaviour {
+    pub fn from_behaviour(behaviour: &Behaviour, network_entities: &Query<&NetworkEntity>) -> Self {
+        match behaviour {
+            Behaviour::Move { target, flow_field } => SavedBehaviour::Move {
+                target: *target,
+                flow_field: *flow_field,
+            },
+            Behaviour::Attack {
+                target_position,
+                target,
+                damage,
+            } => match network_entities.get(*target) {
+                Ok(network_entity) => SavedBehaviour::Attack {
+                    target_position: *target_position,
+                    target: *network_entity,
+                    damage: damage.clone(),
+                },
+                // the command queue picks up a new target anyway
+                Err(_) => SavedBehaviour::Idle,
+            },
+            Behaviour::Harvest {
+                target_position,
+                target,
+            } => match network_entities.get(*target) {
+                Ok(network_entity) => SavedBehaviour::Harvest {
+                    target_position: *target_position,
+                    target: *network_entity,
+                },
+                Err(_) => SavedBehaviour::Idle,
+            },
+            Behaviour::DropOff => SavedBehaviour::DropOff,
+            Behaviour::Construct {
+                target_position,
+                target,
+            } => match network_entities.get(*target) {
+                Ok(network_entity) => SavedBehaviour::Construct {
+                    target_position: *target_position,
+                    target: *network_entity,
+                },
+                Err(_) => SavedBehaviour::Idle,
+            },
+            Behaviour::Idle => SavedBehaviour::Idle,
+        }
+    }
+
+    pub fn to_behaviour(&self, network_entity_registry: &NetworkEntityRegistry) -> Behaviour {
+        match self {
+            SavedBehaviour::Move { target, flow_field } => Behaviour::Move {
+                target: *target,
+                flow_field: *flow_field,
+            },
+            SavedBehaviour::Attack {
+                target_position,
+                target,
+                damage,
+            } => match network_entity_registry.get(target) {
+                Some(entity) => Behaviour::Attack {
+                    target_position: *target_position,
+                    target: *entity,
+                    damage: damage.clone(),
+                },
+                None => Behaviour::Idle,
+            },
+            SavedBehaviour::Harvest {
+                target_position,
+                target,
+            } => match network_entity_registry.get(target) {
+                Some(entity) => Behaviour::Harvest {
+                    target_position: *target_position,
+                    target: *entity,
+                },
+                None => Behaviour::Idle,
+            },
+            SavedBehaviour::DropOff => Behaviour::DropOff,
+            SavedBehaviour::Construct {
+                target_position,
+                target,
+            } => match network_entity_registry.get(target) {
+                Some(entity) => Behaviour::Construct {
+                    target_position: *target_position,
+                    target: *entity,
+                },
+                None => Behaviour::Idle,
+            },
+            SavedBehaviour::Idle => Behaviour::Idle,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SavedPlayer {
+    pub player_id: PlayerId,
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SavedEntity {
+    pub network_entity: NetworkEntity,
+    pub spawnable: Box<dyn Spawnable>,
+    pub state: EntityState,
+}
+
+/// A match in progress, which the server can pick up again with `--load`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SavedMatch {
+    /// The map the match is played on, which decides the slots and teams.
+    pub map: String,
+    pub players: Vec<SavedPlayer>,
+    pub next_entity: NetworkEntity,
+    pub entities: Vec<SavedEntity>,
+    #[serde(default)]
+    pub stockpiles: HashMap<PlayerId, f32>,
+    #[serde(default)]
+    pub researched: HashMap<PlayerId, Vec<String>>,
+    #[serde(default)]
+    pub researching: HashMap<PlayerId, Research>,
+}
+
+impl SavedMatch {
+    pub fn load(path: &str) -> Result<Self, anyhow::Error> {
+        let bytes = std::fs::read(path)?;
+        Ok(ron::de::from_bytes(&bytes)?)
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), anyhow::Error> {
+        let pretty = ron::ser::PrettyConfig::default();
+        std::fs::write(path, ron::ser::to_string_pretty(self, pretty)?)?;
+        Ok(())
+    }
+
+    /// Spawns every saved entity again, instead of spawning the map.
+    pub fn restore(
+        &self,
+        network_entity_registry: &mut NetworkEntityRegistry,
+        spawn_resource: &SpawnResource,
+        stockpiles: &mut Stockpiles,
+        upgrades: &mut Upgrades,
+    ) {
+        for saved_entity in &self.entities {
+            spawn_resource.restore(
+                saved_entity.network_entity,
+                saved_entity.spawnable.clone(),
+                saved_entity.state.clone(),
+            );
+        }
+
+        network_entity_registry.set_next_entity(self.next_entity);
+        stockpiles.reset(self.stockpiles.clone());
+        upgrades.reset(self.researched.clone(), self.researching.clone());
+    }
+}
+
+/// The saved match the server was started with, until the match starts.
+#[derive(Default)]
+pub struct LoadedMatch(pub Option<SavedMatch>);
+
+/// Writes the running match to the given file.
+pub struct SaveMatchEvent(pub String);
+
+fn server_save_system(
+    mut reader: Local<EventReader<SaveMatchEvent>>,
+    events: Res<Events<SaveMatchEvent>>,
+    match_state: Res<MatchState>,
+    server: Res<Server>,
+    lobby: Res<Lobby>,
+    players: Res<Players>,
+    disconnects: Res<Disconnects>,
+    stockpiles: Res<Stockpiles>,
+    upgrades: Res<Upgrades>,
+    network_entity_registry: Res<NetworkEntityRegistry>,
+    network_entities: Query<&NetworkEntity>,
+    query: Query<(
+        &NetworkEntity,
+        &NetworkSpawnable,
+        Option<&Position>,
+        Option<&UnitInstance>,
+        Option<&Animator>,
+        Option<&Owner>,
+        Option<&CommandQueue>,
+        Option<&Behaviour>,
+        Option<&Cargo>,
+        Option<&ResourceNode>,
+        Option<&ProductionQueue>,
+        Option<&Construction>,
+    )>,
+) {
+    for SaveMatchEvent(path) in reader.iter(&events) {
+        if *match_state != MatchState::Running {
+            warn!("Can't save to {}, the match hasn't started", path);
+            continue;
+        }
+
+        // players who dropped still have their slot, whether they come back or not
+        let mut saved_players: Vec<SavedPlayer> = players
+            .player_ids
+            .values()
+            .chain(disconnects.reserved())
+            .map(|player_id| SavedPlayer {
+                player_id: *player_id,
+                name: lobby
+                    .get(*player_id)
+                    .map_or_else(String::new, |player| player.name.clone()),
+            })
+            .collect();
+        saved_players.sort_by_key(|player| player.player_id);
+        saved_players.dedup_by_key(|player| player.player_id);
+
+        let mut entities: Vec<SavedEntity> = query
+            .iter()
+            .map(
+                |(
+                    network_entity,
+                    spawnable,
+                    position,
+                    unit_instance,
+                    animator,
+                    owner,
+                    command_queue,
+                    behaviour,
+                    cargo,
+                    resource_node,
+                    production_queue,
+                    construction,
+                )| SavedEntity {
+                    network_entity: *network_entity,
+                    spawnable: spawnable.0.clone(),
+                    state: EntityState {
+                        position: position.cloned(),
+                        health: unit_instance.map(|unit_instance| unit_instance.health),
+                        animation: animator
+                            .map(|animator| (animator.playing().clone(), animator.current_frame())),
+                        owner: owner.map(|owner| owner.0),
+                        commands: command_queue
+                            .map(|command_queue| command_queue.commands.iter().cloned().collect()),
+                        request_set: command_queue
+                            .and_then(|command_queue| command_queue.request_set.clone()),
+                        behaviour: behaviour.map(|behaviour| {
+                            SavedBehaviour::from_behaviour(behaviour, &network_entities)
+                        }),
+                        cargo: cargo.map(|cargo| cargo.amount),
+                        resource_amount: resource_node.map(|resource_node| resource_node.amount),
+                        production_queue: production_queue.cloned(),
+                        construction: construction.cloned(),
+                    },
+                },
+            )
+            .collect();
+        entities.sort_by_key(|saved_entity| saved_entity.network_entity.0);
+
+        let saved_match = SavedMatch {
+            map: server.map().to_string(),
+            players: saved_players,
+            next_entity: network_entity_registry.next_entity(),
+            entities,
+            stockpiles: stockpiles.amounts().clone(),
+            researched: upgrades.all_researched().clone(),
+            researching: upgrades.all_researching().clone(),
+        };
+
+        match saved_match.save(path) {
+            Ok(()) => info!("saved {} entities to {}", saved_match.entities.len(), path),
+            Err(err) => error!("Failed to save the match to {}: {}", path, err),
+        }
+    }
+}
+
+/// Saves running matches on the server.
+pub struct SavePlugin;
+
+impl Plugin for SavePlugin {
+    fn build(&self, app_builder: &mut AppBuilder) {
+        app_builder.add_event::<SaveMatchEvent>();
+        app_builder.add_system(server_save_system.system());
+    }
+}
@@ -23,12 +23,18 @@ pub fn unit_selection_system(
     keyboard_input: Res<Input<KeyCode>>,
     units: Res<Assets<Unit>>,
     player_id: Res<Option<PlayerId>>,
+    placement: Res<Placement>,
     query: Query<(Entity, &Position, &Handle<Unit>, &Owner, &NetworkEntity)>,
 ) {
     if player_id.is_none() {
         return;
     }
 
+    // clicks place the building instead
+    if placement.is_active() {
+        return;
+    }
+
     let player_id = player_id.unwrap();
 
     let input_config = match input_config.get(&input_resource.0) {
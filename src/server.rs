@@ -1,18 +1,104 @@
 use crate::*;
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MatchState {
+    Waiting,
+    Running,
+    /// The result is in, until the server returns to the lobby or exits.
+    Ended,
+}
+
+impl Default for MatchState {
+    fn default() -> Self {
+        MatchState::Waiting
+    }
+}
+
 #[derive(Clap)]
 pub struct Server {
     #[clap(short, long, default_value = "maps/default_map.map")]
     map: String,
     #[clap(short, long, default_value = "35566")]
     port: u16,
+    /// Either "authoritative" or "lockstep".
+    #[clap(long, default_value = "authoritative")]
+    mode: SimulationMode,
+    /// Simulation ticks per second.
+    #[clap(long, default_value = "60")]
+    tick_rate: u32,
+    /// Seconds a player who dropped during a match has to reconnect.
+    #[clap(long, default_value = "60")]
+    grace_period: f32,
+    /// Pauses the match while waiting for a player to reconnect.
+    #[clap(long)]
+    pause_on_disconnect: bool,
+    /// What happens to a player's units once the grace period is over, either "ai" or "remove".
+    #[clap(long, default_value = "ai")]
+    on_abandon: AbandonAction,
+    /// Tells clients why their messages were rejected, instead of only logging it.
+    #[clap(long)]
+    report_rejections: bool,
+    /// Records the match to a replay file, which can be played with the replay subcommand.
+    #[clap(long)]
+    record: Option<String>,
+    /// Resumes a saved match, instead of starting a new one on the map.
+    #[clap(long)]
+    load: Option<String>,
+    /// What happens once a match is decided, either "lobby" or "exit".
+    #[clap(long, default_value = "lobby")]
+    after_match: AfterMatch,
+    /// Also reads admin commands from connections to this port on localhost.
+    #[clap(long)]
+    admin_port: Option<u16>,
 }
 
 impl Server {
+    pub fn map(&self) -> &str {
+        &self.map
+    }
+
     pub fn run(self) {
-        App::build()
+        self.app(Transport::Turbulence).run();
+    }
+
+    /// Builds the server app, which can be updated manually when using a loopback transport.
+    pub fn app(mut self, transport: Transport) -> AppBuilder {
+        let listen = transport.is_turbulence();
+        let loaded_match = self.load.as_ref().map(|path| {
+            SavedMatch::load(path)
+                .unwrap_or_else(|err| panic!("Failed to load saved match {}: {}", path, err))
+        });
+
+        if let Some(saved_match) = &loaded_match {
+            self.map = saved_match.map.clone();
+
+            for player in &saved_match.players {
+                info!("slot {} was played by {}", player.player_id.0 + 1, player.name);
+            }
+
+            // the replay would start from the map, not from where the match was saved
+            if self.record.take().is_some() {
+                warn!("Can't record a replay of a loaded match");
+            }
+        }
+
+        let admin_port = self.admin_port;
+        let mut app_builder = App::build();
+
+        app_builder
             // resources
             .init_resource::<NetworkEntityRegistry>()
+            .init_resource::<MatchState>()
+            .add_resource(self.mode)
+            .add_resource(self.after_match)
+            .add_resource(Rejections::new(self.report_rejections))
+            .add_resource(ReplayRecorder::new(self.record.clone(), self.map.clone()))
+            .add_resource(LoadedMatch(loaded_match))
+            .add_resource(DisconnectSettings {
+                grace_period: self.grace_period,
+                pause: self.pause_on_disconnect,
+                abandon_action: self.on_abandon,
+            })
             .add_resource(self)
             .add_resource(NetworkSettings::server())
             .add_resource(bevy::app::ScheduleRunnerSettings::run_loop(
@@ -24,29 +110,50 @@ impl Server {
             .add_plugin(bevy::transform::TransformPlugin)
             .add_plugin(bevy::reflect::ReflectPlugin)
             .add_plugin(bevy::log::LogPlugin)
+            .add_plugin(SimulationPlugin)
+            // the grids are rebuilt before anything in the simulation uses them
+            .add_plugin(PlacementPlugin::server())
+            .add_plugin(NavigationPlugin)
+            .add_plugin(LockstepPlugin::server())
+            .add_plugin(ReplayPlugin::server())
             .add_plugin(SpawnPlugin::server())
             .add_plugin(AnimationPlugin::server())
             .add_plugin(TileMapPlugin::server())
             .add_plugin(UnitPlugin::server())
             .add_plugin(ConnectionPlugin::server())
+            .add_plugin(LobbyPlugin::server())
+            .add_plugin(TeamPlugin::server())
+            .add_plugin(EconomyPlugin::server())
+            .add_plugin(BuildingPlugin::server())
+            .add_plugin(UpgradePlugin::server())
+            .add_plugin(DisconnectPlugin::server())
+            .add_plugin(ChatPlugin::server())
+            .add_plugin(VictoryPlugin::server())
+            .add_plugin(SavePlugin)
+            .add_plugin(AdminPlugin(admin_port))
             .add_plugin(PositionPlugin::server())
             .add_plugin(MapPlugin)
-            .add_plugin(NetworkingPlugin)
+            .add_plugin(TransportPlugin(transport))
+            .add_plugin(NetworkStatsPlugin::server())
             .add_plugin(UnitAnimationPlugin)
             // startup systems
-            .add_startup_system(network_setup.system())
             .add_startup_system(setup.system())
             // systems
-            .add_system(handle_connection_system.system())
-            .run();
+            .add_system(handle_connection_system.system());
+
+        if listen {
+            app_builder.add_startup_system(listen_system.system());
+        }
+
+        app_builder
     }
 }
 
 fn setup(
     commands: &mut Commands,
-    mut net: ResMut<NetworkResource>,
     server: Res<Server>,
     asset_server: Res<AssetServer>,
+    mut fixed_tick: ResMut<FixedTick>,
 ) {
     // we store these so they wont be automatically freed
     let handles = asset_server.load_folder(".").unwrap();
@@ -56,6 +163,10 @@ fn setup(
     let map_handle: Handle<Map> = asset_server.load(server.map.as_str());
     commands.insert_resource(map_handle);
 
+    fixed_tick.set_tick_rate(server.tick_rate);
+}
+
+fn listen_system(mut net: ResMut<NetworkResource>, server: Res<Server>) {
     let ip_address = bevy_networking_turbulence::find_my_ip_address().unwrap();
     let socket_address = std::net::SocketAddr::new(ip_address, server.port);
     info!("Starting server at: {}", socket_address);
@@ -63,37 +174,118 @@ fn setup(
 }
 
 fn handle_connection_system(
-    mut net: ResMut<NetworkResource>,
+    mut net: ResMut<Network>,
     mut reader: Local<EventReader<NetworkEvent>>,
+    mut handshake_reader: Local<EventReader<HandshakeEvent>>,
     mut players: ResMut<Players>,
+    mut lobby: ResMut<Lobby>,
+    mut disconnects: ResMut<Disconnects>,
+    disconnect_settings: Res<DisconnectSettings>,
+    match_state: Res<MatchState>,
+    mut synchronize_events: ResMut<Events<SynchronizeEvent>>,
+    simulation_mode: Res<SimulationMode>,
+    fixed_tick: Res<FixedTick>,
     maps: Res<Assets<Map>>,
     map_handle: Res<Handle<Map>>,
-    spawn_resource: Res<SpawnResource>,
     events: Res<Events<NetworkEvent>>,
+    handshake_events: Res<Events<HandshakeEvent>>,
 ) {
+    for HandshakeEvent {
+        handle,
+        session,
+        spectator,
+    } in handshake_reader.iter(&handshake_events)
+    {
+        let map = maps.get(&*map_handle).unwrap();
+
+        if *spectator {
+            info!("{} is spectating", handle);
+
+            players.spectators.insert(*handle);
+            net.send_message(
+                *handle,
+                ConnectionMessage::Spectator(*simulation_mode, fixed_tick.tick_rate()),
+            );
+
+            if *match_state == MatchState::Running {
+                synchronize_events.send(SynchronizeEvent(*handle));
+            }
+        } else if let Some(player_id) = disconnects.reclaim(*session) {
+            info!("{:?} reconnected", player_id);
+
+            players.insert(player_id, *handle);
+            disconnects.connect(*handle, *session);
+            net.send_message(
+                *handle,
+                ConnectionMessage::Server(player_id, *simulation_mode, fixed_tick.tick_rate()),
+            );
+            net.broadcast_message(MatchEventMessage::PlayerReconnected(player_id));
+            synchronize_events.send(SynchronizeEvent(*handle));
+        } else if let Some(player_id) = map.get_unused(&players, &disconnects) {
+            players.insert(player_id, *handle);
+            disconnects.connect(*handle, *session);
+            lobby.join(player_id);
+            net.send_message(
+                *handle,
+                ConnectionMessage::Server(player_id, *simulation_mode, fixed_tick.tick_rate()),
+            );
+
+            // the lobby starts the match once everyone is ready
+            if *match_state == MatchState::Running {
+                info!("{:?} joined a running match", player_id);
+                synchronize_events.send(SynchronizeEvent(*handle));
+            }
+        } else {
+            warn!("not enough players available for map");
+            net.send_message(
+                *handle,
+                ConnectionMessage::Rejected(
+                    "the match is full, join as a spectator instead".to_string(),
+                ),
+            );
+            net.disconnect(*handle);
+        }
+    }
+
     for event in reader.iter(&events) {
         match event {
             NetworkEvent::Connected(handle) => {
                 info!("connection at {}", handle);
+            }
+            NetworkEvent::Disconnected(handle) => {
+                warn!("disconnected at {}", handle);
+
+                let kicked = disconnects.take_kicked(*handle);
 
-                let map = maps.get(&*map_handle).unwrap();
+                if players.spectators.remove(handle) {
+                    continue;
+                }
+
+                let player_id = match players.remove_connection(handle) {
+                    Some(player_id) => player_id,
+                    None => continue,
+                };
 
-                if let Some(player_id) = map.get_unused(&players) {
-                    players.insert(player_id, *handle);
-                    net.send_message(*handle, ConnectionMessage::Server(player_id))
-                        .unwrap();
+                if kicked {
+                    info!("{:?} was kicked", player_id);
+                    lobby.leave(player_id);
+                } else if *match_state == MatchState::Running {
+                    // keeps the slot around for a while, so the player can reclaim it
+                    let grace_period = disconnect_settings.grace_period;
 
-                    if map.all_connected(&players) {
-                        info!("all players connected, spawning map");
-                        map.spawn(&players, &spawn_resource);
+                    if disconnects.disconnect(*handle, player_id, grace_period) {
+                        info!("{:?} disconnected, waiting for them to return", player_id);
+                        net.broadcast_message(MatchEventMessage::PlayerDisconnected {
+                            player_id,
+                            grace_period,
+                        });
                     }
                 } else {
-                    warn!("not enough players available for map");
+                    info!("{:?} left", player_id);
+                    disconnects.forget(*handle);
+                    lobby.leave(player_id);
                 }
             }
-            NetworkEvent::Disconnected(handle) => {
-                warn!("disconnected at {}", handle);
-            }
             _ => {}
         }
     }
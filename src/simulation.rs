@@ -0,0 +1,191 @@
+use crate::*;
+use bevy::ecs::{ShouldRun, SystemStage};
+use std::str::FromStr;
+
+pub const SIMULATION_STAGE: &str = "simulation";
+pub const DEFAULT_TICK_RATE: u32 = 60;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SimulationMode {
+    /// The server simulates everything and replicates the results to the clients.
+    Authoritative,
+    /// Only commands are exchanged, every peer runs the simulation on a fixed timestep.
+    Lockstep,
+}
+
+impl Default for SimulationMode {
+    fn default() -> Self {
+        SimulationMode::Authoritative
+    }
+}
+
+impl FromStr for SimulationMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "authoritative" => Ok(SimulationMode::Authoritative),
+            "lockstep" => Ok(SimulationMode::Lockstep),
+            _ => Err(format!("unknown simulation mode '{}'", s)),
+        }
+    }
+}
+
+impl SimulationMode {
+    pub fn is_authoritative(&self) -> bool {
+        *self == SimulationMode::Authoritative
+    }
+
+    pub fn is_lockstep(&self) -> bool {
+        *self == SimulationMode::Lockstep
+    }
+}
+
+/// The time step of the current run of the simulation stage.
+pub struct SimulationTime {
+    delta_seconds: f32,
+}
+
+impl Default for SimulationTime {
+    fn default() -> Self {
+        Self {
+            delta_seconds: 1.0 / DEFAULT_TICK_RATE as f32,
+        }
+    }
+}
+
+impl SimulationTime {
+    pub fn delta_seconds(&self) -> f32 {
+        self.delta_seconds
+    }
+}
+
+// keeps a slow frame from queueing up more ticks than we can ever catch up on
+const FIXED_TICK_MAX_BACKLOG: f32 = 0.25;
+
+/// Runs the simulation on a fixed tick instead of once per frame,
+/// which makes it reproducible given the same commands at the same ticks.
+pub struct FixedTick {
+    /// How many seconds of simulation pass per second.
+    pub speed: f32,
+    /// Simulates as fast as possible until this tick is reached.
+    pub fast_forward: Option<u64>,
+    tick_rate: u32,
+    tick: u64,
+    accumulator: f32,
+    simulating: bool,
+}
+
+impl Default for FixedTick {
+    fn default() -> Self {
+        Self {
+            speed: 1.0,
+            fast_forward: None,
+            tick_rate: DEFAULT_TICK_RATE,
+            tick: 0,
+            accumulator: 0.0,
+            simulating: false,
+        }
+    }
+}
+
+impl FixedTick {
+    /// The tick being simulated, or the next one to be outside of the simulation stage.
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    /// Ticks per second.
+    pub fn tick_rate(&self) -> u32 {
+        self.tick_rate
+    }
+
+    pub fn set_tick_rate(&mut self, tick_rate: u32) {
+        self.tick_rate = tick_rate.max(1);
+    }
+
+    pub fn tick_seconds(&self) -> f32 {
+        1.0 / self.tick_rate as f32
+    }
+
+    /// Finishes the previous tick, and decides whether the next one can be simulated.
+    pub fn next_tick(&mut self, delta_seconds: f32) -> ShouldRun {
+        if self.simulating {
+            self.simulating = false;
+            self.tick += 1;
+        } else {
+            // the first time we're asked this frame
+            self.accumulator = (self.accumulator + delta_seconds * self.speed)
+                .min(FIXED_TICK_MAX_BACKLOG * self.speed.max(1.0));
+        }
+
+        let tick_seconds = self.tick_seconds();
+
+        match self.fast_forward {
+            // the time spent fast forwarding doesn't count
+            Some(target) if self.tick < target => self.accumulator = 0.0,
+            _ => {
+                self.fast_forward = None;
+
+                if self.accumulator < tick_seconds {
+                    return ShouldRun::No;
+                }
+
+                self.accumulator -= tick_seconds;
+            }
+        }
+
+        // always loops, so the tick is finished before the next frame
+        self.simulating = true;
+        ShouldRun::YesAndLoop
+    }
+}
+
+/// Stops the simulation, for example while waiting for a player to reconnect.
+#[derive(Default)]
+pub struct SimulationPaused(pub bool);
+
+fn simulation_run_criteria(
+    time: Res<Time>,
+    network_settings: Res<NetworkSettings>,
+    simulation_mode: Res<SimulationMode>,
+    paused: Res<SimulationPaused>,
+    mut fixed_tick: ResMut<FixedTick>,
+    mut lockstep: ResMut<Lockstep>,
+    network_entity_registry: Res<NetworkEntityRegistry>,
+) -> ShouldRun {
+    match *simulation_mode {
+        SimulationMode::Authoritative if paused.0 => ShouldRun::No,
+        SimulationMode::Authoritative if network_settings.is_server() => {
+            fixed_tick.next_tick(time.delta_seconds())
+        }
+        // the client only receives the results of the simulation
+        SimulationMode::Authoritative => ShouldRun::No,
+        SimulationMode::Lockstep => lockstep.next_tick(&network_entity_registry),
+    }
+}
+
+fn simulation_time_system(fixed_tick: Res<FixedTick>, mut simulation_time: ResMut<SimulationTime>) {
+    // lockstep counts its own ticks, but steps them at the same rate
+    simulation_time.delta_seconds = fixed_tick.tick_seconds();
+}
+
+/// Adds the simulation stage, must be added before any plugin adding systems to it.
+pub struct SimulationPlugin;
+
+impl Plugin for SimulationPlugin {
+    fn build(&self, app_builder: &mut AppBuilder) {
+        app_builder.init_resource::<SimulationTime>();
+        app_builder.init_resource::<Lockstep>();
+        app_builder.init_resource::<SimulationPaused>();
+        app_builder.init_resource::<FixedTick>();
+
+        app_builder.add_stage_after(
+            bevy::app::stage::UPDATE,
+            SIMULATION_STAGE,
+            SystemStage::parallel().with_run_criteria(simulation_run_criteria.system()),
+        );
+
+        app_builder.add_system_to_stage(SIMULATION_STAGE, simulation_time_system.system());
+    }
+}
@@ -5,6 +5,7 @@ use bevy::{
     reflect::TypeUuid,
     utils::BoxedFuture,
 };
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::{Arc, Mutex};
 
 pub struct SpawnableLoader;
@@ -32,8 +33,43 @@ impl AssetLoader for SpawnableLoader {
 pub struct SpawnMessage {
     spawnable: Box<dyn Spawnable>,
     entity: NetworkEntity,
+    state: Option<EntityState>,
 }
 
+/// The state of an entity that has diverged from what its spawnable spawns,
+/// sent to clients that join after the entity was spawned, or loaded from a saved match.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default)]
+pub struct EntityState {
+    pub position: Option<Position>,
+    pub health: Option<f32>,
+    pub animation: Option<(String, u32)>,
+    #[serde(default)]
+    pub owner: Option<PlayerId>,
+    /// The contents of the command queue, from the front to the back.
+    #[serde(default)]
+    pub commands: Option<Vec<Box<dyn Command>>>,
+    #[serde(default)]
+    pub request_set: Option<Box<dyn Command>>,
+    #[serde(default)]
+    pub behaviour: Option<SavedBehaviour>,
+    #[serde(default)]
+    pub cargo: Option<f32>,
+    /// What's left of a resource node.
+    #[serde(default)]
+    pub resource_amount: Option<f32>,
+    #[serde(default)]
+    pub production_queue: Option<ProductionQueue>,
+    /// Left out once a placed building is finished.
+    #[serde(default)]
+    pub construction: Option<Construction>,
+}
+
+/// The spawnable a networked entity was spawned from.
+pub struct NetworkSpawnable(pub Box<dyn Spawnable>);
+
+/// Requests a full world snapshot to be sent to a connection.
+pub struct SynchronizeEvent(pub ConnectionHandle);
+
 pub trait SpawnableClone {
     fn box_clone(&self) -> Box<dyn Spawnable>;
 }
@@ -53,6 +89,15 @@ impl Clone for Box<dyn Spawnable> {
 #[typetag::serde]
 impl Spawnable for SpawnMessage {
     fn spawn(&self, commands: &mut Commands, resources: &Resources) -> Entity {
+        // a reconnecting client might already know the entity
+        if let Some(old_entity) = resources
+            .get::<NetworkEntityRegistry>()
+            .unwrap()
+            .get(&self.entity)
+        {
+            commands.despawn_recursive(*old_entity);
+        }
+
         let entity = self.spawnable.spawn(commands, resources);
 
         resources
@@ -61,6 +106,11 @@ impl Spawnable for SpawnMessage {
             .insert(self.entity, entity);
 
         commands.insert_one(entity, self.entity);
+
+        if let Some(state) = &self.state {
+            commands.insert_one(entity, state.clone());
+        }
+
         entity
     }
 }
@@ -95,16 +145,37 @@ pub trait Spawnable: SpawnableClone + std::fmt::Debug + Send + Sync + 'static {
     fn spawn(&self, commands: &mut Commands, resources: &Resources) -> Entity;
 }
 
+struct QueuedSpawn {
+    spawnable: Box<dyn Spawnable>,
+    restore: Option<(NetworkEntity, EntityState)>,
+}
+
 pub struct SpawnResource {
-    spawnables: Arc<Mutex<Vec<Box<dyn Spawnable>>>>,
+    spawnables: Arc<Mutex<Vec<QueuedSpawn>>>,
 }
 
 impl SpawnResource {
     pub fn spawn<T: Spawnable>(&self, spawnable: T) {
-        self.spawnables.lock().unwrap().push(Box::new(spawnable));
+        self.spawnables.lock().unwrap().push(QueuedSpawn {
+            spawnable: Box::new(spawnable),
+            restore: None,
+        });
+    }
+
+    /// Spawns an entity with the network id and state it had before, when loading a saved match.
+    pub fn restore(
+        &self,
+        network_entity: NetworkEntity,
+        spawnable: Box<dyn Spawnable>,
+        state: EntityState,
+    ) {
+        self.spawnables.lock().unwrap().push(QueuedSpawn {
+            spawnable,
+            restore: Some((network_entity, state)),
+        });
     }
 
-    pub fn clear(&self) -> Vec<Box<dyn Spawnable>> {
+    fn clear(&self) -> Vec<QueuedSpawn> {
         std::mem::replace(&mut *self.spawnables.lock().unwrap(), Vec::new())
     }
 }
@@ -116,24 +187,42 @@ pub fn spawn_system(world: &mut World, resources: &mut Resources) {
     {
         let spawn_resource = resources.get::<SpawnResource>().unwrap();
         let network_settings = resources.get::<NetworkSettings>().unwrap();
-        let mut net = resources.get_mut::<NetworkResource>().unwrap();
-
-        for spawnable in spawn_resource.clear() {
-            let network_entity = {
-                let mut network_entity_registry =
-                    resources.get_mut::<NetworkEntityRegistry>().unwrap();
-                network_entity_registry.generate_entity()
+        let mut net = resources.get_mut::<Network>().unwrap();
+
+        for queued_spawn in spawn_resource.clear() {
+            let (network_entity, state) = match queued_spawn.restore {
+                Some((network_entity, state)) => (network_entity, Some(state)),
+                None => {
+                    let mut network_entity_registry =
+                        resources.get_mut::<NetworkEntityRegistry>().unwrap();
+                    (network_entity_registry.generate_entity(), None)
+                }
             };
 
             let message = SpawnMessage {
-                spawnable,
+                spawnable: queued_spawn.spawnable,
                 entity: network_entity,
+                state,
             };
 
             let entity = message.spawn(&mut commands, resources);
 
             if network_settings.is_server {
-                net.broadcast_message(message);
+                commands.insert_one(entity, NetworkSpawnable(message.spawnable.clone()));
+
+                let players = resources.get::<Players>().unwrap();
+                let teams = resources.get::<Teams>().unwrap();
+                let simulation_mode = resources.get::<SimulationMode>().unwrap();
+                let handles: Vec<ConnectionHandle> = net.connections().cloned().collect();
+
+                // the vision system spawns it for players once their team can see it
+                for handle in handles {
+                    let view = View::of(handle, &players, &teams, &simulation_mode);
+
+                    if view == Some(View::Everything) {
+                        net.send_message(handle, message.clone());
+                    }
+                }
             }
 
             let mut network_entity_registry = resources.get_mut::<NetworkEntityRegistry>().unwrap();
@@ -144,13 +233,274 @@ pub fn spawn_system(world: &mut World, resources: &mut Resources) {
     commands.apply(world, resources);
 }
 
-pub fn network_spawn_system(mut net: ResMut<NetworkResource>, spawn_resource: Res<SpawnResource>) {
-    for (_handle, connection) in net.connections.iter_mut() {
-        let channels = connection.channels().unwrap();
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DespawnMessage {
+    pub entity: NetworkEntity,
+}
+
+/// Finds every registered entity which no longer exists and tells the clients about it.
+///
+/// Entities can be despawned from anywhere on the server, they only have to be despawned.
+pub fn server_despawn_system(
+    simulation_mode: Res<SimulationMode>,
+    mut net: ResMut<Network>,
+    mut network_entity_registry: ResMut<NetworkEntityRegistry>,
+    query: Query<&NetworkEntity>,
+) {
+    let despawned: Vec<NetworkEntity> = network_entity_registry
+        .iter()
+        .filter(|(_, entity)| query.get(**entity).is_err())
+        .map(|(network_entity, _)| *network_entity)
+        .collect();
+
+    for network_entity in despawned {
+        network_entity_registry.remove(&network_entity);
+
+        // every peer despawns on its own in lockstep
+        if simulation_mode.is_authoritative() {
+            net.broadcast_message(DespawnMessage {
+                entity: network_entity,
+            });
+        }
+    }
+}
+
+pub fn client_despawn_system(
+    commands: &mut Commands,
+    mut net: ResMut<Network>,
+    mut selected_units: ResMut<SelectedUnits>,
+    mut network_entity_registry: ResMut<NetworkEntityRegistry>,
+) {
+    for (_handle, despawn_message) in net.recv::<DespawnMessage>() {
+        let network_entity = despawn_message.entity;
+
+        // might have despawned it already, if it died on our side as well
+        if let Some(entity) = network_entity_registry.get(&network_entity).cloned() {
+            network_entity_registry.remove(&network_entity);
+            selected_units.units.remove(&entity);
+            selected_units.network_entities.remove(&network_entity);
+            commands.despawn_recursive(entity);
+        }
+    }
+}
+
+pub fn network_spawn_system(mut net: ResMut<Network>, spawn_resource: Res<SpawnResource>) {
+    for (_handle, spawn_message) in net.recv::<SpawnMessage>() {
+        spawn_resource.spawn(spawn_message);
+    }
+}
+
+/// What goes into the spawn message of an entity that already exists.
+pub type SynchronizeQuery<'a> = Query<
+    'a,
+    (
+        &'a NetworkSpawnable,
+        Option<&'a Position>,
+        Option<&'a UnitInstance>,
+        Option<&'a Animator>,
+        Option<&'a ResourceNode>,
+        Option<&'a ProductionQueue>,
+        Option<&'a Construction>,
+    ),
+>;
+
+/// Spawns the entity as it is now, for a connection that didn't see it being spawned.
+pub fn synchronize_message(
+    query: &SynchronizeQuery,
+    entity: Entity,
+    network_entity: NetworkEntity,
+) -> Option<SpawnMessage> {
+    let (
+        spawnable,
+        position,
+        unit_instance,
+        animator,
+        resource_node,
+        production_queue,
+        construction,
+    ) = query.get(entity).ok()?;
+
+    Some(SpawnMessage {
+        spawnable: spawnable.0.clone(),
+        entity: network_entity,
+        state: Some(EntityState {
+            position: position.cloned(),
+            health: unit_instance.map(|unit_instance| unit_instance.health),
+            animation: animator
+                .map(|animator| (animator.playing().clone(), animator.current_frame())),
+            resource_amount: resource_node.map(|resource_node| resource_node.amount),
+            production_queue: production_queue.cloned(),
+            construction: construction.cloned(),
+            ..Default::default()
+        }),
+    })
+}
+
+// a world snapshot is trickled out, the reliable send buffer only holds so many messages
+const SYNCHRONIZE_SPAWNS_PER_FRAME: usize = 16;
+
+pub fn server_synchronize_system(
+    mut net: ResMut<Network>,
+    mut reader: Local<EventReader<SynchronizeEvent>>,
+    mut pending: Local<HashMap<ConnectionHandle, VecDeque<NetworkEntity>>>,
+    events: Res<Events<SynchronizeEvent>>,
+    mut vision: ResMut<Vision>,
+    simulation_mode: Res<SimulationMode>,
+    players: Res<Players>,
+    teams: Res<Teams>,
+    network_entity_registry: Res<NetworkEntityRegistry>,
+    query: SynchronizeQuery,
+) {
+    for SynchronizeEvent(handle) in reader.iter(&events) {
+        // players only get to see what their team can, the vision system spawns that for them
+        if View::of(*handle, &players, &teams, &simulation_mode) != Some(View::Everything) {
+            continue;
+        }
+
+        info!("sending world snapshot to {}", handle);
+
+        let mut network_entities: Vec<NetworkEntity> = network_entity_registry
+            .iter()
+            .map(|(network_entity, _)| *network_entity)
+            .collect();
+        network_entities.sort_by_key(|network_entity| network_entity.0);
+        pending.insert(*handle, network_entities.into_iter().collect());
+    }
+
+    let connections: HashSet<ConnectionHandle> = net.connections().cloned().collect();
+    pending.retain(|handle, _| connections.contains(handle));
+
+    // spawns that didn't fit go out again, and the snapshot waits until they're through
+    let mut congested = HashSet::new();
+
+    for (handle, message) in net.undelivered::<SpawnMessage>() {
+        if !connections.contains(&handle) || network_entity_registry.get(&message.entity).is_none()
+        {
+            continue;
+        }
+
+        if vision.is_revealed(handle, message.entity) {
+            // revealed again once it fits, as it is by then
+            vision.forget(handle, message.entity);
+        } else {
+            congested.insert(handle);
+            net.send_message(handle, message);
+        }
+    }
+
+    for (handle, network_entities) in pending.iter_mut() {
+        if congested.contains(handle) {
+            continue;
+        }
+
+        let mut sent = 0;
+
+        while sent < SYNCHRONIZE_SPAWNS_PER_FRAME {
+            let network_entity = match network_entities.pop_front() {
+                Some(network_entity) => network_entity,
+                None => break,
+            };
+
+            // it might have been despawned since the snapshot was requested
+            let message = network_entity_registry
+                .get(&network_entity)
+                .and_then(|entity| synchronize_message(&query, *entity, network_entity));
+
+            if let Some(message) = message {
+                net.send_message(*handle, message);
+                sent += 1;
+            }
+        }
+    }
+
+    pending.retain(|_, network_entities| !network_entities.is_empty());
+}
+
+pub fn entity_state_system(
+    commands: &mut Commands,
+    network_entity_registry: Res<NetworkEntityRegistry>,
+    buildings: Res<Assets<Building>>,
+    mut constructions: Query<(&Handle<Building>, &mut Construction)>,
+    mut query: Query<(
+        Entity,
+        &EntityState,
+        Option<&mut Position>,
+        Option<&mut UnitInstance>,
+        Option<&mut Animator>,
+        Option<&mut Owner>,
+        Option<&mut CommandQueue>,
+        Option<&mut Behaviour>,
+        Option<&mut Cargo>,
+        Option<&mut ResourceNode>,
+        Option<&mut ProductionQueue>,
+    )>,
+) {
+    for (
+        entity,
+        state,
+        position,
+        unit_instance,
+        animator,
+        owner,
+        command_queue,
+        behaviour,
+        cargo,
+        resource_node,
+        production_queue,
+    ) in query.iter_mut()
+    {
+        if let (Some(mut position), Some(state_position)) = (position, &state.position) {
+            *position = state_position.clone();
+        }
+
+        if let (Some(mut unit_instance), Some(health)) = (unit_instance, state.health) {
+            unit_instance.apply_operation(UnitInstanceOperation::SetHealth(health));
+        }
+
+        if let (Some(mut animator), Some((animation, frame))) = (animator, &state.animation) {
+            animator.apply(AnimatorOperation::SetPlaying(animation.clone(), *frame));
+        }
+
+        if let (Some(mut owner), Some(state_owner)) = (owner, state.owner) {
+            owner.0 = state_owner;
+        }
+
+        if let (Some(mut command_queue), Some(state_commands)) = (command_queue, &state.commands) {
+            command_queue.commands = state_commands.iter().cloned().collect();
+            command_queue.request_set = state.request_set.clone();
+        }
+
+        if let (Some(mut behaviour), Some(state_behaviour)) = (behaviour, &state.behaviour) {
+            *behaviour = state_behaviour.to_behaviour(&network_entity_registry);
+        }
+
+        if let (Some(mut cargo), Some(amount)) = (cargo, state.cargo) {
+            cargo.amount = amount;
+        }
+
+        if let (Some(mut resource_node), Some(amount)) = (resource_node, state.resource_amount) {
+            resource_node.amount = amount;
+        }
 
-        while let Some(spawn_message) = channels.recv::<SpawnMessage>() {
-            spawn_resource.spawn(spawn_message);
+        if let (Some(mut production_queue), Some(state_production_queue)) =
+            (production_queue, &state.production_queue)
+        {
+            *production_queue = state_production_queue.clone();
         }
+
+        if let Ok((building_handle, mut construction)) = constructions.get_mut(entity) {
+            match &state.construction {
+                Some(state_construction) => *construction = state_construction.clone(),
+                // it was finished before we knew about it
+                None => {
+                    if let Some(building) = buildings.get(building_handle) {
+                        finish_construction(commands, entity, building);
+                    }
+                }
+            }
+        }
+
+        commands.remove_one::<EntityState>(entity);
     }
 }
 
@@ -173,10 +523,16 @@ impl Plugin for SpawnPlugin {
         });
 
         app_builder.add_system_to_stage(bevy::app::stage::POST_UPDATE, spawn_system.system());
-
-        if !self.0 {
-            app_builder
-                .add_system_to_stage(bevy::app::stage::PRE_UPDATE, network_spawn_system.system());
+        app_builder.add_system(entity_state_system.system());
+
+        if self.0 {
+            app_builder.add_event::<SynchronizeEvent>();
+            app_builder.add_system(server_synchronize_system.system());
+            // runs after every other stage had the chance to despawn something
+            app_builder.add_system_to_stage(bevy::app::stage::LAST, server_despawn_system.system());
+        } else {
+            app_builder.add_system(network_spawn_system.system());
+            app_builder.add_system(client_despawn_system.system());
         }
     }
 }
@@ -0,0 +1,130 @@
+use crate::*;
+
+/// The player a spectator's camera follows, if any.
+#[derive(Default)]
+pub struct Spectating {
+    pub following: Option<PlayerId>,
+}
+
+pub struct SpectatorText;
+
+fn spectator_setup(commands: &mut Commands, asset_server: Res<AssetServer>) {
+    commands
+        .spawn(TextBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: Rect {
+                    bottom: Val::Px(16.0),
+                    left: Val::Px(16.0),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            text: Text {
+                value: String::new(),
+                font: asset_server.load("fonts/DejaVuSans.ttf"),
+                style: TextStyle {
+                    font_size: 24.0,
+                    color: Color::WHITE,
+                    ..Default::default()
+                },
+            },
+            ..Default::default()
+        })
+        .with(SpectatorText);
+}
+
+fn spectator_follow_system(
+    connection_status: Res<ConnectionStatus>,
+    lobby_state: Res<LobbyState>,
+    keyboard_input: Res<Input<KeyCode>>,
+    mouse_input: Res<Input<MouseButton>>,
+    input_config: Res<Assets<InputConfig>>,
+    input_resource: Res<InputResource>,
+    mouse_position: Res<MousePosition>,
+    mut spectating: ResMut<Spectating>,
+    units: Query<(&Position, &Owner)>,
+    mut cameras: Query<&mut Transform>,
+    mut text_query: Query<&mut Text, With<SpectatorText>>,
+) {
+    if !matches!(*connection_status, ConnectionStatus::Spectating) {
+        return;
+    }
+
+    let input_config = match input_config.get(&input_resource.0) {
+        Some(input_config) => input_config,
+        None => return,
+    };
+
+    if input_config
+        .follow_next_player
+        .just_pressed(&keyboard_input, &mouse_input)
+    {
+        let player_ids: Vec<PlayerId> = lobby_state
+            .slots
+            .iter()
+            .map(|slot| slot.player_id)
+            .collect();
+
+        // cycles through every player, and then back to a free camera
+        spectating.following = match spectating.following {
+            Some(following) => player_ids
+                .iter()
+                .skip_while(|player_id| **player_id != following)
+                .nth(1)
+                .cloned(),
+            None => player_ids.first().cloned(),
+        };
+    }
+
+    let value = match spectating.following {
+        Some(following) => {
+            let name = lobby_state
+                .slot(following)
+                .and_then(|slot| slot.player.as_ref())
+                .map_or("nobody", |player| player.name.as_str());
+
+            format!("Spectating, following {}", name)
+        }
+        None => "Spectating".to_string(),
+    };
+
+    for mut text in text_query.iter_mut() {
+        if text.value != value {
+            text.value = value.clone();
+        }
+    }
+
+    let following = match spectating.following {
+        Some(following) => following,
+        None => return,
+    };
+
+    let (sum, count) = units
+        .iter()
+        .filter(|(_, owner)| owner.0 == following)
+        .fold((Vec3::zero(), 0), |(sum, count), (position, _)| {
+            (sum + position.position, count + 1)
+        });
+
+    if count == 0 {
+        return;
+    }
+
+    let center = *isometric::ISO_TO_SCREEN * (sum / count as f32);
+
+    if let Ok(mut transform) = cameras.get_mut(mouse_position.camera()) {
+        transform.translation.x = center.x;
+        transform.translation.y = center.y;
+    }
+}
+
+pub struct SpectatorPlugin;
+
+impl Plugin for SpectatorPlugin {
+    fn build(&self, app_builder: &mut AppBuilder) {
+        app_builder.init_resource::<Spectating>();
+        app_builder.add_startup_system(spectator_setup.system());
+        app_builder.add_system(spectator_follow_system.system());
+    }
+}
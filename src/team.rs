@@ -0,0 +1,304 @@
+use crate::*;
+use std::collections::{HashMap, HashSet};
+
+// the reliable send buffer only holds so many spawns
+const REVEALS_PER_FRAME: usize = 16;
+
+/// Which team every player is on, known to the server and every client.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Teams {
+    teams: HashMap<PlayerId, u32>,
+}
+
+impl Teams {
+    pub fn from_map(map: &Map) -> Self {
+        Self {
+            teams: map.teams.clone(),
+        }
+    }
+
+    pub fn insert(&mut self, player_id: PlayerId, team: u32) {
+        self.teams.insert(player_id, team);
+    }
+
+    pub fn team(&self, player_id: PlayerId) -> u32 {
+        match self.teams.get(&player_id) {
+            Some(team) => *team,
+            // players without a team are on their own, clear of the configured teams
+            None => u32::MAX - player_id.0 as u32,
+        }
+    }
+
+    pub fn is_allied(&self, a: PlayerId, b: PlayerId) -> bool {
+        a == b || self.team(a) == self.team(b)
+    }
+
+    pub fn is_hostile(&self, a: PlayerId, b: PlayerId) -> bool {
+        !self.is_allied(a, b)
+    }
+}
+
+/// What a connection gets to know about on the server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum View {
+    /// Only what the team can see.
+    Team(u32),
+    Everything,
+}
+
+impl View {
+    /// The view of a connection, if it has joined the match.
+    pub fn of(
+        handle: ConnectionHandle,
+        players: &Players,
+        teams: &Teams,
+        simulation_mode: &SimulationMode,
+    ) -> Option<View> {
+        if players.spectators.contains(&handle) {
+            return Some(View::Everything);
+        }
+
+        let player_id = players.player_ids.get(&handle)?;
+
+        // every peer simulates the whole world in lockstep
+        if simulation_mode.is_lockstep() {
+            Some(View::Everything)
+        } else {
+            Some(View::Team(teams.team(*player_id)))
+        }
+    }
+}
+
+/// Which entities every connection has been told about, players only learn about what their team sees.
+#[derive(Default)]
+pub struct Vision {
+    views: HashMap<ConnectionHandle, View>,
+    revealed: HashMap<ConnectionHandle, HashSet<NetworkEntity>>,
+}
+
+impl Vision {
+    /// Whether the connection knows about the entity, and should hear about it.
+    pub fn knows(&self, handle: ConnectionHandle, network_entity: NetworkEntity) -> bool {
+        match self.views.get(&handle) {
+            Some(View::Everything) => true,
+            Some(View::Team(_)) => self.is_revealed(handle, network_entity),
+            None => false,
+        }
+    }
+
+    pub fn is_revealed(&self, handle: ConnectionHandle, network_entity: NetworkEntity) -> bool {
+        self.revealed
+            .get(&handle)
+            .map_or(false, |revealed| revealed.contains(&network_entity))
+    }
+
+    /// Reveals the entity to the connection again, when it can still see it.
+    pub fn forget(&mut self, handle: ConnectionHandle, network_entity: NetworkEntity) {
+        if let Some(revealed) = self.revealed.get_mut(&handle) {
+            revealed.remove(&network_entity);
+        }
+    }
+
+    /// Sends a message about the entity to everyone who knows about it.
+    pub fn send_message<M: NetworkMessage>(
+        &self,
+        net: &mut Network,
+        network_entity: NetworkEntity,
+        message: M,
+    ) {
+        for handle in self.views.keys() {
+            if self.knows(*handle, network_entity) {
+                net.send_message(*handle, message.clone());
+            }
+        }
+    }
+}
+
+fn server_teams_system(
+    mut loaded: Local<bool>,
+    maps: Res<Assets<Map>>,
+    map_handle: Res<Handle<Map>>,
+    mut teams: ResMut<Teams>,
+) {
+    if *loaded {
+        return;
+    }
+
+    if let Some(map) = maps.get(&*map_handle) {
+        *teams = Teams::from_map(map);
+        *loaded = true;
+    }
+}
+
+/// Spawns units for the players whose team can see them, and despawns them once out of sight.
+fn server_vision_system(
+    simulation_mode: Res<SimulationMode>,
+    teams: Res<Teams>,
+    players: Res<Players>,
+    network_entity_registry: Res<NetworkEntityRegistry>,
+    mut net: ResMut<Network>,
+    mut vision: ResMut<Vision>,
+    spawnables: SynchronizeQuery,
+    query: Query<(&NetworkEntity, &Position, &Owner, &UnitStats)>,
+) {
+    let views: HashMap<ConnectionHandle, View> = net
+        .connections()
+        .filter_map(|handle| {
+            View::of(*handle, &players, &teams, &simulation_mode).map(|view| (*handle, view))
+        })
+        .collect();
+
+    let sight: Vec<(u32, Vec2, f32)> = query
+        .iter()
+        .map(|(_, position, owner, unit_stats)| {
+            (
+                teams.team(owner.0),
+                position.position.truncate(),
+                unit_stats.sight_range,
+            )
+        })
+        .collect();
+
+    // only units can be out of sight, everything else is known to everyone
+    let mut hidden: HashMap<u32, HashSet<NetworkEntity>> = HashMap::new();
+
+    for view in views.values() {
+        let team = match view {
+            View::Team(team) => *team,
+            View::Everything => continue,
+        };
+
+        if hidden.contains_key(&team) {
+            continue;
+        }
+
+        let team_hidden = query
+            .iter()
+            .filter(|(_, position, owner, _)| {
+                teams.team(owner.0) != team
+                    && !sight
+                        .iter()
+                        .any(|(sight_team, sight_position, sight_range)| {
+                            *sight_team == team
+                                && (position.position.truncate() - *sight_position).length()
+                                    <= *sight_range
+                        })
+            })
+            .map(|(network_entity, ..)| *network_entity)
+            .collect();
+
+        hidden.insert(team, team_hidden);
+    }
+
+    // the oldest first, so the tile map comes before whatever stands on it
+    let mut network_entities: Vec<(NetworkEntity, Entity)> = network_entity_registry
+        .iter()
+        .map(|(network_entity, entity)| (*network_entity, *entity))
+        .collect();
+    network_entities.sort_by_key(|(network_entity, _)| network_entity.0);
+
+    vision
+        .revealed
+        .retain(|handle, _| matches!(views.get(handle), Some(View::Team(_))));
+
+    for (handle, view) in &views {
+        let team_hidden = match view {
+            View::Team(team) => &hidden[team],
+            View::Everything => continue,
+        };
+
+        let revealed = vision.revealed.entry(*handle).or_insert_with(HashSet::new);
+        // the despawn system already told everyone about these
+        revealed.retain(|network_entity| network_entity_registry.get(network_entity).is_some());
+
+        let mut sent = 0;
+
+        for (network_entity, entity) in &network_entities {
+            let visible = !team_hidden.contains(network_entity);
+
+            if visible && !revealed.contains(network_entity) && sent < REVEALS_PER_FRAME {
+                // the spawn carries a building's production queue and construction as they are
+                // now, the sync systems only send changes to those who already know it
+                if let Some(message) = synchronize_message(&spawnables, *entity, *network_entity) {
+                    net.send_message(*handle, message);
+                    revealed.insert(*network_entity);
+                    sent += 1;
+                }
+            } else if !visible && revealed.remove(network_entity) {
+                net.send_message(
+                    *handle,
+                    DespawnMessage {
+                        entity: *network_entity,
+                    },
+                );
+            }
+        }
+    }
+
+    vision.views = views;
+}
+
+/// Hides enemy units no allied unit can see, and the health bars of everyone but allies.
+fn client_vision_system(
+    teams: Res<Teams>,
+    player_id: Res<Option<PlayerId>>,
+    query: Query<(Entity, &Position, &Owner, &UnitStats)>,
+    mut unit_query: Query<(&mut Visible, &HealthBar, &Children)>,
+    mut visible_query: Query<&mut Visible, Without<HealthBar>>,
+) {
+    // spectators see everything
+    let player_id = match *player_id {
+        Some(player_id) => player_id,
+        None => return,
+    };
+
+    let sight: Vec<(Vec2, f32)> = query
+        .iter()
+        .filter(|(_, _, owner, _)| teams.is_allied(owner.0, player_id))
+        .map(|(_, position, _, unit_stats)| (position.position.truncate(), unit_stats.sight_range))
+        .collect();
+
+    for (entity, position, owner, _) in query.iter() {
+        let visible = teams.is_allied(owner.0, player_id)
+            || sight.iter().any(|(sight_position, sight_range)| {
+                (position.position.truncate() - *sight_position).length() <= *sight_range
+            });
+
+        if let Ok((mut unit_visible, health_bar, children)) = unit_query.get_mut(entity) {
+            if unit_visible.is_visible != visible {
+                unit_visible.is_visible = visible;
+            }
+
+            if let Ok(mut bar_visible) = visible_query.get_mut(children[health_bar.0]) {
+                bar_visible.is_visible = visible && teams.is_allied(owner.0, player_id);
+            }
+        }
+    }
+}
+
+pub struct TeamPlugin(pub bool);
+
+impl TeamPlugin {
+    pub fn server() -> Self {
+        Self(true)
+    }
+
+    pub fn client() -> Self {
+        Self(false)
+    }
+}
+
+impl Plugin for TeamPlugin {
+    fn build(&self, app_builder: &mut AppBuilder) {
+        app_builder.init_resource::<Teams>();
+
+        if self.0 {
+            app_builder.init_resource::<Vision>();
+            app_builder.add_system(server_teams_system.system());
+            // after the spawn system, so new entities go out the frame they are spawned
+            app_builder.add_system_to_stage(bevy::app::stage::LAST, server_vision_system.system());
+        } else {
+            app_builder.add_system(client_vision_system.system());
+        }
+    }
+}
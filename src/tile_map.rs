@@ -12,7 +12,7 @@ use bevy::{
 };
 use std::collections::HashMap;
 
-#[derive(Clone, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
 pub struct TilePosition {
     pub x: i32,
     pub y: i32,
@@ -22,11 +22,22 @@ impl TilePosition {
     pub fn pos(&self) -> Vec2 {
         Vec2::new(self.x as f32, self.y as f32)
     }
+
+    /// The tile a position is on, tiles are centered on their position.
+    pub fn from_position(position: Vec2) -> Self {
+        Self {
+            x: position.x.round() as i32,
+            y: position.y.round() as i32,
+        }
+    }
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct Tile {
     pub index: u32,
+    /// Nothing can be built on it.
+    #[serde(default)]
+    pub impassable: bool,
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
@@ -53,6 +64,24 @@ pub struct TileSet {
 }
 
 impl TileMap {
+    /// Every tile on the map, and whether it's passable on all layers.
+    pub fn passable_tiles(&self, tile_set: &TileSet) -> HashMap<TilePosition, bool> {
+        let mut tiles = HashMap::new();
+
+        for layer in self.layers.values() {
+            for (position, tile) in &layer.tile_set {
+                let impassable = tile_set
+                    .tiles
+                    .get(*tile)
+                    .map_or(false, |tile| tile.impassable);
+
+                *tiles.entry(*position).or_insert(true) &= !impassable;
+            }
+        }
+
+        tiles
+    }
+
     pub fn generate_mesh(&self, tile_set: &TileSet) -> Mesh {
         let mut mesh = Mesh::new(Default::default());
 
@@ -0,0 +1,465 @@
+use crate::*;
+use bevy::ecs::SystemStage;
+use std::any::{Any, TypeId};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+
+pub const NETWORK_RECEIVE_STAGE: &str = "network_receive";
+
+pub trait NetworkMessage:
+    Serialize + serde::de::DeserializeOwned + Clone + std::fmt::Debug + Send + Sync + 'static
+{
+}
+
+impl<T> NetworkMessage for T where
+    T: Serialize + serde::de::DeserializeOwned + Clone + std::fmt::Debug + Send + Sync + 'static
+{
+}
+
+type AnyMessage = Box<dyn Any + Send + Sync>;
+
+struct MessageType {
+    name: &'static str,
+    clone: fn(&AnyMessage) -> AnyMessage,
+    size: fn(&AnyMessage) -> u64,
+    turbulence_send: fn(&mut NetworkResource, ConnectionHandle, AnyMessage) -> SendResult,
+    turbulence_recv: fn(&mut NetworkResource, &mut Network, &mut NetworkStats),
+}
+
+fn message_name<M: NetworkMessage>() -> &'static str {
+    let name = std::any::type_name::<M>();
+    name.rsplit("::").next().unwrap_or(name)
+}
+
+fn clone_message<M: NetworkMessage>(message: &AnyMessage) -> AnyMessage {
+    Box::new(message.downcast_ref::<M>().unwrap().clone())
+}
+
+fn message_size<M: NetworkMessage>(message: &AnyMessage) -> u64 {
+    bincode::serialized_size(message.downcast_ref::<M>().unwrap()).unwrap_or(0)
+}
+
+enum SendResult {
+    Sent,
+    // the message is handed back, so it can be sent again later
+    BufferFull(AnyMessage),
+    Failed,
+}
+
+fn turbulence_send<M: NetworkMessage>(
+    net: &mut NetworkResource,
+    handle: ConnectionHandle,
+    message: AnyMessage,
+) -> SendResult {
+    let message = *message.downcast::<M>().unwrap();
+
+    match net.send_message(handle, message) {
+        Ok(Some(message)) => {
+            warn!("Send buffer full, dropped {:?}", message);
+            SendResult::BufferFull(Box::new(message))
+        }
+        Err(err) => {
+            error!("Failed to send message to {}: {}", handle, err);
+            SendResult::Failed
+        }
+        Ok(None) => SendResult::Sent,
+    }
+}
+
+fn turbulence_recv<M: NetworkMessage>(
+    net: &mut NetworkResource,
+    network: &mut Network,
+    network_stats: &mut NetworkStats,
+) {
+    for (handle, connection) in net.connections.iter_mut() {
+        if let Some(channels) = connection.channels() {
+            while let Some(message) = channels.recv::<M>() {
+                let size = bincode::serialized_size(&message).unwrap_or(0);
+                network_stats.record_received(*handle, message_name::<M>(), size);
+                network.receive(*handle, message);
+            }
+        }
+    }
+}
+
+struct Envelope {
+    from: ConnectionHandle,
+    type_id: TypeId,
+    message: AnyMessage,
+}
+
+#[derive(Default)]
+struct LoopbackHubInner {
+    next_endpoint: ConnectionHandle,
+    links: HashMap<ConnectionHandle, HashSet<ConnectionHandle>>,
+    inboxes: HashMap<ConnectionHandle, VecDeque<Envelope>>,
+    events: HashMap<ConnectionHandle, Vec<NetworkEvent>>,
+}
+
+/// Connects apps running in the same process through memory channels.
+///
+/// Every endpoint is identified by a [`ConnectionHandle`], which is also the handle
+/// its peers see it as.
+#[derive(Clone, Default)]
+pub struct LoopbackHub {
+    inner: Arc<Mutex<LoopbackHubInner>>,
+}
+
+impl LoopbackHub {
+    pub fn endpoint(&self) -> LoopbackEndpoint {
+        let mut inner = self.inner.lock().unwrap();
+
+        let handle = inner.next_endpoint;
+        inner.next_endpoint += 1;
+        inner.links.insert(handle, HashSet::new());
+        inner.inboxes.insert(handle, VecDeque::new());
+        inner.events.insert(handle, Vec::new());
+
+        LoopbackEndpoint {
+            handle,
+            hub: self.clone(),
+        }
+    }
+
+    pub fn connect(&self, a: &LoopbackEndpoint, b: &LoopbackEndpoint) {
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.links.get_mut(&a.handle).unwrap().insert(b.handle);
+        inner.links.get_mut(&b.handle).unwrap().insert(a.handle);
+
+        let events = inner.events.get_mut(&a.handle).unwrap();
+        events.push(NetworkEvent::Connected(b.handle));
+        let events = inner.events.get_mut(&b.handle).unwrap();
+        events.push(NetworkEvent::Connected(a.handle));
+    }
+
+    pub fn disconnect(&self, a: &LoopbackEndpoint, b: &LoopbackEndpoint) {
+        self.inner.lock().unwrap().disconnect(a.handle, b.handle);
+    }
+}
+
+impl LoopbackHubInner {
+    fn disconnect(&mut self, a: ConnectionHandle, b: ConnectionHandle) {
+        self.links.get_mut(&a).unwrap().remove(&b);
+        self.links.get_mut(&b).unwrap().remove(&a);
+
+        let events = self.events.get_mut(&a).unwrap();
+        events.push(NetworkEvent::Disconnected(b));
+        let events = self.events.get_mut(&b).unwrap();
+        events.push(NetworkEvent::Disconnected(a));
+    }
+}
+
+#[derive(Clone)]
+pub struct LoopbackEndpoint {
+    handle: ConnectionHandle,
+    hub: LoopbackHub,
+}
+
+impl LoopbackEndpoint {
+    pub fn handle(&self) -> ConnectionHandle {
+        self.handle
+    }
+}
+
+#[derive(Clone)]
+pub enum Transport {
+    Turbulence,
+    Loopback(LoopbackEndpoint),
+}
+
+impl Transport {
+    pub fn is_turbulence(&self) -> bool {
+        match self {
+            Transport::Turbulence => true,
+            Transport::Loopback(_) => false,
+        }
+    }
+}
+
+/// Sends and receives messages over whichever [`Transport`] the app was built with.
+pub struct Network {
+    transport: Transport,
+    message_types: HashMap<TypeId, MessageType>,
+    connections: HashSet<ConnectionHandle>,
+    incoming: HashMap<TypeId, VecDeque<(ConnectionHandle, AnyMessage)>>,
+    outgoing: Vec<(Option<ConnectionHandle>, TypeId, AnyMessage)>,
+    undelivered: HashMap<TypeId, Vec<(ConnectionHandle, AnyMessage)>>,
+    disconnecting: Vec<ConnectionHandle>,
+}
+
+impl Network {
+    pub fn new(transport: Transport) -> Self {
+        Self {
+            transport,
+            message_types: HashMap::new(),
+            connections: HashSet::new(),
+            incoming: HashMap::new(),
+            outgoing: Vec::new(),
+            undelivered: HashMap::new(),
+            disconnecting: Vec::new(),
+        }
+    }
+
+    pub fn register<M: NetworkMessage>(&mut self) {
+        self.message_types.insert(
+            TypeId::of::<M>(),
+            MessageType {
+                name: message_name::<M>(),
+                clone: clone_message::<M>,
+                size: message_size::<M>,
+                turbulence_send: turbulence_send::<M>,
+                turbulence_recv: turbulence_recv::<M>,
+            },
+        );
+    }
+
+    pub fn connections(&self) -> impl Iterator<Item = &ConnectionHandle> {
+        self.connections.iter()
+    }
+
+    pub fn send_message<M: NetworkMessage>(&mut self, handle: ConnectionHandle, message: M) {
+        self.outgoing
+            .push((Some(handle), TypeId::of::<M>(), Box::new(message)));
+    }
+
+    pub fn broadcast_message<M: NetworkMessage>(&mut self, message: M) {
+        self.outgoing
+            .push((None, TypeId::of::<M>(), Box::new(message)));
+    }
+
+    /// Drops the connection once the messages already sent to it are out.
+    pub fn disconnect(&mut self, handle: ConnectionHandle) {
+        self.disconnecting.push(handle);
+    }
+
+    /// Takes every message of type `M` received this frame.
+    pub fn recv<M: NetworkMessage>(&mut self) -> Vec<(ConnectionHandle, M)> {
+        match self.incoming.get_mut(&TypeId::of::<M>()) {
+            Some(incoming) => incoming
+                .drain(..)
+                .map(|(handle, message)| (handle, *message.downcast::<M>().unwrap()))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Takes every message of type `M` that didn't fit into the send buffer last frame.
+    pub fn undelivered<M: NetworkMessage>(&mut self) -> Vec<(ConnectionHandle, M)> {
+        match self.undelivered.remove(&TypeId::of::<M>()) {
+            Some(undelivered) => undelivered
+                .into_iter()
+                .map(|(handle, message)| (handle, *message.downcast::<M>().unwrap()))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    fn receive<M: NetworkMessage>(&mut self, handle: ConnectionHandle, message: M) {
+        self.incoming
+            .entry(TypeId::of::<M>())
+            .or_insert_with(VecDeque::new)
+            .push_back((handle, Box::new(message)));
+    }
+}
+
+fn clear_incoming_system(mut network: ResMut<Network>) {
+    for (_, incoming) in network.incoming.iter_mut() {
+        incoming.clear();
+    }
+}
+
+fn turbulence_receive_system(
+    mut net: ResMut<NetworkResource>,
+    mut network: ResMut<Network>,
+    mut network_stats: ResMut<NetworkStats>,
+    mut events: ResMut<Events<NetworkEvent>>,
+) {
+    // a frame late, so whatever was sent to the connection before has been flushed
+    for handle in network.disconnecting.drain(..) {
+        if net.connections.remove(&handle).is_some() {
+            events.send(NetworkEvent::Disconnected(handle));
+        }
+    }
+
+    network.connections = net.connections.keys().cloned().collect();
+
+    let recvs: Vec<_> = network
+        .message_types
+        .values()
+        .map(|message_type| message_type.turbulence_recv)
+        .collect();
+
+    for recv in recvs {
+        recv(&mut *net, &mut *network, &mut *network_stats);
+    }
+}
+
+fn turbulence_send_system(
+    mut net: ResMut<NetworkResource>,
+    mut network: ResMut<Network>,
+    mut network_stats: ResMut<NetworkStats>,
+) {
+    let outgoing = std::mem::replace(&mut network.outgoing, Vec::new());
+    // whatever wasn't picked up from last frame is lost for good
+    network.undelivered.clear();
+
+    for (handle, type_id, message) in outgoing {
+        let (name, size, clone, send) = match network.message_types.get(&type_id) {
+            Some(message_type) => (
+                message_type.name,
+                (message_type.size)(&message),
+                message_type.clone,
+                message_type.turbulence_send,
+            ),
+            None => {
+                error!("Tried to send unregistered message type");
+                continue;
+            }
+        };
+
+        let targets: Vec<ConnectionHandle> = match handle {
+            Some(handle) => vec![handle],
+            None => net.connections.keys().cloned().collect(),
+        };
+
+        for target in targets {
+            match send(&mut *net, target, clone(&message)) {
+                SendResult::Sent => network_stats.record_sent(target, name, size),
+                SendResult::BufferFull(message) => {
+                    network_stats.record_dropped(target, name, size);
+                    network
+                        .undelivered
+                        .entry(type_id)
+                        .or_insert_with(Vec::new)
+                        .push((target, message));
+                }
+                SendResult::Failed => network_stats.record_dropped(target, name, size),
+            }
+        }
+    }
+}
+
+fn loopback_receive_system(
+    mut network: ResMut<Network>,
+    mut network_stats: ResMut<NetworkStats>,
+    mut events: ResMut<Events<NetworkEvent>>,
+) {
+    let endpoint = match &network.transport {
+        Transport::Loopback(endpoint) => endpoint.clone(),
+        Transport::Turbulence => return,
+    };
+
+    let mut inner = endpoint.hub.inner.lock().unwrap();
+
+    for event in inner.events.get_mut(&endpoint.handle).unwrap().drain(..) {
+        match &event {
+            NetworkEvent::Connected(handle) => {
+                network.connections.insert(*handle);
+            }
+            NetworkEvent::Disconnected(handle) => {
+                network.connections.remove(handle);
+            }
+            _ => {}
+        }
+
+        events.send(event);
+    }
+
+    for envelope in inner.inboxes.get_mut(&endpoint.handle).unwrap().drain(..) {
+        if let Some(message_type) = network.message_types.get(&envelope.type_id) {
+            let size = (message_type.size)(&envelope.message);
+            network_stats.record_received(envelope.from, message_type.name, size);
+        }
+
+        network
+            .incoming
+            .entry(envelope.type_id)
+            .or_insert_with(VecDeque::new)
+            .push_back((envelope.from, envelope.message));
+    }
+}
+
+fn loopback_send_system(mut network: ResMut<Network>, mut network_stats: ResMut<NetworkStats>) {
+    let endpoint = match &network.transport {
+        Transport::Loopback(endpoint) => endpoint.clone(),
+        Transport::Turbulence => return,
+    };
+
+    let outgoing = std::mem::replace(&mut network.outgoing, Vec::new());
+    let mut inner = endpoint.hub.inner.lock().unwrap();
+    let links = inner.links[&endpoint.handle].clone();
+
+    for (handle, type_id, message) in outgoing {
+        let message_type = match network.message_types.get(&type_id) {
+            Some(message_type) => message_type,
+            None => {
+                error!("Tried to send unregistered message type");
+                continue;
+            }
+        };
+
+        let size = (message_type.size)(&message);
+        let targets: Vec<ConnectionHandle> = match handle {
+            Some(handle) if links.contains(&handle) => vec![handle],
+            Some(handle) => {
+                error!("Failed to send message to {}: not connected", handle);
+                network_stats.record_dropped(handle, message_type.name, size);
+                continue;
+            }
+            None => links.iter().cloned().collect(),
+        };
+
+        for target in targets {
+            network_stats.record_sent(target, message_type.name, size);
+            inner.inboxes.get_mut(&target).unwrap().push_back(Envelope {
+                from: endpoint.handle,
+                type_id,
+                message: (message_type.clone)(&message),
+            });
+        }
+    }
+
+    // the messages are already in the inboxes, so nothing sent before is lost
+    for handle in network.disconnecting.drain(..) {
+        if links.contains(&handle) {
+            inner.disconnect(endpoint.handle, handle);
+        }
+    }
+}
+
+/// Adds the [`Network`] resource and the systems moving messages over the transport.
+pub struct TransportPlugin(pub Transport);
+
+impl Plugin for TransportPlugin {
+    fn build(&self, app_builder: &mut AppBuilder) {
+        let transport = self.0.clone();
+
+        app_builder.init_resource::<NetworkStats>();
+
+        app_builder.add_stage_after(
+            bevy::app::stage::PRE_UPDATE,
+            NETWORK_RECEIVE_STAGE,
+            SystemStage::parallel(),
+        );
+        app_builder.add_system_to_stage(NETWORK_RECEIVE_STAGE, clear_incoming_system.system());
+
+        if transport.is_turbulence() {
+            app_builder.add_plugin(NetworkingPlugin);
+            app_builder.add_startup_system(network_setup.system());
+            app_builder
+                .add_system_to_stage(NETWORK_RECEIVE_STAGE, turbulence_receive_system.system());
+            app_builder
+                .add_system_to_stage(bevy::app::stage::LAST, turbulence_send_system.system());
+        } else {
+            app_builder.add_event::<NetworkEvent>();
+            app_builder
+                .add_system_to_stage(NETWORK_RECEIVE_STAGE, loopback_receive_system.system());
+            app_builder.add_system_to_stage(bevy::app::stage::LAST, loopback_send_system.system());
+        }
+
+        let mut network = Network::new(transport);
+        register_messages(&mut network);
+        app_builder.add_resource(network);
+    }
+}
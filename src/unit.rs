@@ -6,13 +6,16 @@ use bevy::{
     utils::BoxedFuture,
 };
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum CommandTarget {
     Position(Vec2),
     Ally(NetworkEntity),
     Enemy(NetworkEntity),
+    ResourceNode(NetworkEntity),
+    /// One of our own drop-offs.
+    DropOff(NetworkEntity),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -22,10 +25,28 @@ pub enum CommandQueueOperation {
     ClearCommands,
 }
 
+impl CommandQueueOperation {
+    pub fn command(&self) -> Option<&Box<dyn Command>> {
+        match self {
+            CommandQueueOperation::AddCommand(command) => Some(command),
+            CommandQueueOperation::SetCommand(command) => Some(command),
+            CommandQueueOperation::ClearCommands => None,
+        }
+    }
+
+    pub fn command_mut(&mut self) -> Option<&mut Box<dyn Command>> {
+        match self {
+            CommandQueueOperation::AddCommand(command) => Some(command),
+            CommandQueueOperation::SetCommand(command) => Some(command),
+            CommandQueueOperation::ClearCommands => None,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CommandMessage {
-    operation: CommandQueueOperation,
-    network_entity: NetworkEntity,
+    pub operation: CommandQueueOperation,
+    pub network_entity: NetworkEntity,
 }
 
 pub struct CommandQueue {
@@ -72,6 +93,19 @@ pub struct Unit {
     pub movement_speed: MovementSpeed,
     pub attack_damage_frames: HashMap<u32, f32>,
     pub max_health: f32,
+    /// How far the unit reveals enemies to its team.
+    #[serde(default = "default_sight_range")]
+    pub sight_range: f32,
+    /// Units without one can't gather resources.
+    #[serde(default)]
+    pub harvester: Option<Harvester>,
+    /// Units without one can't place buildings.
+    #[serde(default)]
+    pub builder: Option<Builder>,
+}
+
+fn default_sight_range() -> f32 {
+    10.0
 }
 
 impl Unit {
@@ -89,10 +123,13 @@ pub struct HealthBar(pub usize);
 pub enum UnitInstanceOperation {
     SetHealth(f32),
     SubtractHealth(f32),
+    AddHealth(f32),
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct UnitInstanceMessage {
+    /// The simulation tick the operation was applied at.
+    pub tick: u64,
     pub operation: UnitInstanceOperation,
     pub target: NetworkEntity,
 }
@@ -115,6 +152,7 @@ impl UnitInstance {
         match operation {
             UnitInstanceOperation::SetHealth(new_health) => self.health = new_health,
             UnitInstanceOperation::SubtractHealth(damage) => self.health -= damage,
+            UnitInstanceOperation::AddHealth(amount) => self.health += amount,
         }
     }
 
@@ -129,32 +167,58 @@ impl UnitInstance {
     pub fn subtract_health(&mut self, damage: f32) {
         self.operation(UnitInstanceOperation::SubtractHealth(damage));
     }
+
+    pub fn add_health(&mut self, amount: f32) {
+        self.operation(UnitInstanceOperation::AddHealth(amount));
+    }
 }
 
 fn client_unit_instance_system(
-    mut net: ResMut<NetworkResource>,
+    mut net: ResMut<Network>,
     network_entity_registry: Res<NetworkEntityRegistry>,
     mut query: Query<&mut UnitInstance>,
 ) {
-    for (_handle, connection) in net.connections.iter_mut() {
-        let channels = connection.channels().unwrap();
-
-        while let Some(unit_instance_message) = channels.recv::<UnitInstanceMessage>() {
-            let entity = network_entity_registry.get(&unit_instance_message.target).unwrap();
+    for (_handle, unit_instance_message) in net.recv::<UnitInstanceMessage>() {
+        info!("{:?}", unit_instance_message);
 
-            info!("{:?}", unit_instance_message);
+        let unit_instance = network_entity_registry
+            .get(&unit_instance_message.target)
+            .and_then(|entity| query.get_mut(*entity).ok());
 
-            let mut unit_instance = query.get_mut(*entity).unwrap();
-
-            unit_instance.apply_operation(unit_instance_message.operation);
+        match unit_instance {
+            Some(mut unit_instance) => {
+                unit_instance.apply_operation(unit_instance_message.operation)
+            }
+            None => warn!(
+                "Recieved unit instance message for unknown entity {:?}",
+                unit_instance_message.target
+            ),
         }
     }
 }
 
+fn unit_instance_operation_system(mut query: Query<&mut UnitInstance>) {
+    for mut unit_instance in query.iter_mut() {
+        unit_instance.apply_operations();
+    }
+}
+
 fn server_unit_instance_system(
-    mut net: ResMut<NetworkResource>,
+    simulation_mode: Res<SimulationMode>,
+    fixed_tick: Res<FixedTick>,
+    vision: Res<Vision>,
+    mut net: ResMut<Network>,
     mut query: Query<(&mut UnitInstance, &NetworkEntity)>,
 ) {
+    if simulation_mode.is_lockstep() {
+        // every peer applies the operations on their own
+        for (mut unit_instance, _) in query.iter_mut() {
+            unit_instance.apply_operations();
+        }
+
+        return;
+    }
+
     for (mut unit_instance, network_entity) in query.iter_mut() {
         let operations = std::mem::replace(&mut unit_instance.operations, Vec::new());
 
@@ -162,31 +226,67 @@ fn server_unit_instance_system(
             unit_instance.apply_operation(operation.clone());
 
             let message = UnitInstanceMessage {
+                tick: fixed_tick.tick(),
                 operation,
                 target: *network_entity,
             };
     
-            net.broadcast_message(message);
+            vision.send_message(&mut net, *network_entity, message);
         }
     }
 }
 
 fn unit_health_bar_system(
     mut bar_query: Query<&mut Bar>,
-    query: Query<(&UnitInstance, &HealthBar, &Children)>,
+    query: Query<(&UnitInstance, &UnitStats, &HealthBar, &Children)>,
 ) {
-    for (unit_instance, health_bar, children) in query.iter() {
+    for (unit_instance, unit_stats, health_bar, children) in query.iter() {
         if let Ok(mut bar) = bar_query.get_mut(children[health_bar.0]) {
+            bar.max_value = unit_stats.max_health;
             bar.current_value = unit_instance.health;
         }
     }
 }
 
+// in lockstep units die during the simulation, otherwise whenever the server says so
+fn lockstep_unit_health_system(
+    commands: &mut Commands,
+    mut selected_units: ResMut<SelectedUnits>,
+    mut network_entity_registry: ResMut<NetworkEntityRegistry>,
+    query: Query<(Entity, &UnitInstance, &NetworkEntity)>,
+) {
+    despawn_dead_units(
+        commands,
+        &mut selected_units,
+        &mut network_entity_registry,
+        &query,
+    );
+}
+
 fn client_unit_health_system(
     commands: &mut Commands,
+    simulation_mode: Res<SimulationMode>,
     mut selected_units: ResMut<SelectedUnits>,
     mut network_entity_registry: ResMut<NetworkEntityRegistry>,
     query: Query<(Entity, &UnitInstance, &NetworkEntity)>,
+) {
+    if simulation_mode.is_lockstep() {
+        return;
+    }
+
+    despawn_dead_units(
+        commands,
+        &mut selected_units,
+        &mut network_entity_registry,
+        &query,
+    );
+}
+
+fn despawn_dead_units(
+    commands: &mut Commands,
+    selected_units: &mut SelectedUnits,
+    network_entity_registry: &mut NetworkEntityRegistry,
+    query: &Query<(Entity, &UnitInstance, &NetworkEntity)>,
 ) {
     for (entity, unit_instance, network_entity) in query.iter() {
         if unit_instance.health <= 0.0 {
@@ -198,14 +298,18 @@ fn client_unit_health_system(
     }
 }
 
+// the registry is cleaned up by the despawn system, which also tells the clients
 fn server_unit_health_system(
     commands: &mut Commands,
-    mut network_entity_registry: ResMut<NetworkEntityRegistry>,
-    query: Query<(Entity, &UnitInstance, &NetworkEntity)>,
+    mut match_stats: ResMut<MatchStats>,
+    query: Query<(Entity, &UnitInstance, Option<&Owner>)>,
 ) {
-    for (entity, unit_instance, network_entity) in query.iter() {
+    for (entity, unit_instance, owner) in query.iter() {
         if unit_instance.health <= 0.0 {
-            network_entity_registry.remove(&network_entity);
+            if let Some(owner) = owner {
+                match_stats.record_loss(owner.0);
+            }
+
             commands.despawn_recursive(entity);
         }
     }
@@ -227,8 +331,17 @@ pub fn unit_command_system(
     selected_units: Res<SelectedUnits>,
     units: Res<Assets<Unit>>,
     player_id: Res<Option<PlayerId>>,
-    mut net: ResMut<NetworkResource>,
-    query: Query<(&Position, &Handle<Unit>, &NetworkEntity, &Owner)>,
+    teams: Res<Teams>,
+    mut net: ResMut<Network>,
+    query: Query<(
+        &Position,
+        &Handle<Unit>,
+        &NetworkEntity,
+        &Owner,
+        Option<&Visible>,
+    )>,
+    resource_nodes: Query<(&Position, &NetworkEntity, &ResourceNode)>,
+    drop_offs: Query<(&Position, &NetworkEntity, &Owner, &DropOff)>,
 ) {
     let input_config = match input_config.get(&input_resource.0) {
         Some(i) => i,
@@ -243,12 +356,32 @@ pub fn unit_command_system(
 
     let mut target = CommandTarget::Position(mouse_position.position());
 
-    for (position, unit_handle, network_entity, owner) in query.iter() {
+    for (position, network_entity, resource_node) in resource_nodes.iter() {
+        if (mouse_position.position() - position.position.truncate()).length() < resource_node.size
+        {
+            target = CommandTarget::ResourceNode(*network_entity);
+        }
+    }
+
+    for (position, network_entity, owner, drop_off) in drop_offs.iter() {
+        if owner.0 == *player_id
+            && (mouse_position.position() - position.position.truncate()).length() < drop_off.size
+        {
+            target = CommandTarget::DropOff(*network_entity);
+        }
+    }
+
+    for (position, unit_handle, network_entity, owner, visible) in query.iter() {
+        // can't target what we can't see
+        if visible.map_or(false, |visible| !visible.is_visible) {
+            continue;
+        }
+
         let unit = units.get(&*unit_handle).unwrap();
 
         if (mouse_position.position() - position.position.truncate()).length() < unit.selection_size
         {
-            if owner.0 == *player_id {
+            if teams.is_allied(owner.0, *player_id) {
                 target = CommandTarget::Ally(*network_entity);
             } else {
                 target = CommandTarget::Enemy(*network_entity);
@@ -256,6 +389,17 @@ pub fn unit_command_system(
         }
     }
 
+    let harvester_command: Option<Box<dyn Command>> = match target {
+        CommandTarget::ResourceNode(target) => Some(Box::new(HarvestCommand {
+            target,
+            returning: false,
+        })),
+        CommandTarget::DropOff(target) => Some(Box::new(ReturnCargoCommand {
+            target: Some(target),
+        })),
+        _ => None,
+    };
+
     match () {
         _ if input_config
             .move_command
@@ -288,7 +432,38 @@ pub fn unit_command_system(
                         net.broadcast_message(message);
                     }
                 }
+                CommandTarget::ResourceNode(_) | CommandTarget::DropOff(_) => {
+                    for entity in &selected_units.units {
+                        let (_, unit_handle, network_entity, _, _) = query.get(*entity).unwrap();
+                        let unit = units.get(&*unit_handle).unwrap();
+
+                        // everyone else just walks over
+                        let command: Box<dyn Command> = match (&harvester_command, &unit.harvester)
+                        {
+                            (Some(command), Some(_)) => command.clone(),
+                            _ => Box::new(MovePositionCommand {
+                                target: mouse_position.position(),
+                                precise: false,
+                                group_target: None,
+                            }),
+                        };
+
+                        let message = CommandMessage {
+                            operation: operation(command, &keyboard_input),
+                            network_entity: *network_entity,
+                        };
+
+                        net.broadcast_message(message);
+                    }
+                }
                 CommandTarget::Position(target_position) => {
+                    // large groups share a flow field instead of searching a path each
+                    let group_target = if selected_units.units.len() >= FLOW_FIELD_GROUP_SIZE {
+                        Some(target_position)
+                    } else {
+                        None
+                    };
+
                     let mut diameter = 0.0;
                     let mut center = Vec2::zero();
                     let mut center_of_mass = Vec2::zero();
@@ -296,7 +471,7 @@ pub fn unit_command_system(
 
                     // calculate MEC
                     for a in &selected_units.units {
-                        let (a_position, a_unit_handle, _, _) = query.get(*a).unwrap();
+                        let (a_position, a_unit_handle, _, _, _) = query.get(*a).unwrap();
                         let a_unit = units.get(&*a_unit_handle).unwrap();
 
                         area += a_unit.size.powi(2) * std::f32::consts::PI;
@@ -307,7 +482,7 @@ pub fn unit_command_system(
                                 continue;
                             }
 
-                            let (b_position, b_unit_handle, _, _) = query.get(*b).unwrap();
+                            let (b_position, b_unit_handle, _, _, _) = query.get(*b).unwrap();
                             let b_unit = units.get(&*b_unit_handle).unwrap();
 
                             let diff =
@@ -330,13 +505,14 @@ pub fn unit_command_system(
 
                     if area > (diameter / 2.0).powi(2) * std::f32::consts::PI * 0.4 {
                         for entity in &selected_units.units {
-                            let (position, _, network_entity, _) = query.get(*entity).unwrap();
+                            let (position, _, network_entity, _, _) = query.get(*entity).unwrap();
 
                             let relative_position = position.position.truncate() - center_of_mass;
 
                             let command = MovePositionCommand {
                                 target: target_position + relative_position,
                                 precise: true,
+                                group_target,
                             };
 
                             let message = CommandMessage {
@@ -353,6 +529,7 @@ pub fn unit_command_system(
                                     Box::new(MovePositionCommand {
                                         target: target_position,
                                         precise: false,
+                                        group_target,
                                     }),
                                     &keyboard_input,
                                 ),
@@ -369,29 +546,216 @@ pub fn unit_command_system(
     }
 }
 
+/// The commands a player sent while one of their group orders was coming in.
+#[derive(Default)]
+pub struct HeldCommands {
+    frames: u32,
+    commands: Vec<(Entity, CommandMessage)>,
+}
+
 pub fn network_unit_action_system(
-    mut net: ResMut<NetworkResource>,
+    mut held: Local<HashMap<PlayerId, HeldCommands>>,
+    simulation_mode: Res<SimulationMode>,
+    mut net: ResMut<Network>,
+    mut lockstep: ResMut<Lockstep>,
     network_entity_registry: Res<NetworkEntityRegistry>,
     players: Res<Players>,
+    teams: Res<Teams>,
+    mut rejections: ResMut<Rejections>,
+    mut replay_recorder: ResMut<ReplayRecorder>,
+    fixed_tick: Res<FixedTick>,
+    buildings: Res<Assets<Building>>,
+    placement_grid: Res<PlacementGrid>,
+    target_query: Query<(&Position, &Animator)>,
+    economy_query: EconomyQuery,
+    owners: Query<&Owner>,
     mut query: Query<(&mut CommandQueue, &Owner)>,
 ) {
-    for (handle, connection) in net.connections.iter_mut() {
-        let channels = connection.channels().unwrap();
+    let mut accepted = Vec::new();
+
+    for (handle, action_message) in net.recv::<CommandMessage>() {
+        let network_entity = action_message.network_entity;
+
+        // spectators and connections still in the handshake don't own anything
+        let player = match players.player_ids.get(&handle) {
+            Some(player) => player,
+            None => {
+                rejections.reject(&mut net, handle, Rejection::NotAPlayer);
+                continue;
+            }
+        };
+
+        let entity = match network_entity_registry.get(&network_entity) {
+            Some(entity) => *entity,
+            None => {
+                rejections.reject(&mut net, handle, Rejection::UnknownEntity(network_entity));
+                continue;
+            }
+        };
 
-        while let Some(action_message) = channels.recv::<CommandMessage>() {
-            let entity = network_entity_registry
-                .get(&action_message.network_entity)
-                .unwrap();
-            let (mut action_queue, owner) = query.get_mut(*entity).unwrap();
+        let (_, owner) = match query.get_mut(entity) {
+            Ok(components) => components,
+            Err(_) => {
+                rejections.reject(&mut net, handle, Rejection::NotCommandable(network_entity));
+                continue;
+            }
+        };
 
-            let player = players.player_ids.get(handle).unwrap();
+        if *player != owner.0 {
+            rejections.reject(&mut net, handle, Rejection::NotOwner(network_entity));
+            continue;
+        }
 
-            if *player != owner.0 {
-                warn!("Recieved action from wrong owner {:?}", player);
+        if let Some(command) = action_message.operation.command() {
+            if let Err(rejection) =
+                command.validate(&network_entity_registry, &target_query, &economy_query)
+            {
+                rejections.reject(&mut net, handle, rejection);
                 continue;
             }
 
+            let target_owner = command.attack_target().and_then(|target| {
+                network_entity_registry
+                    .get(&target)
+                    .and_then(|entity| owners.get(*entity).ok())
+                    .map(|target_owner| (target, target_owner.0))
+            });
+
+            if let Some((target, target_owner)) = target_owner {
+                if teams.is_allied(*player, target_owner) {
+                    rejections.reject(&mut net, handle, Rejection::FriendlyTarget(target));
+                    continue;
+                }
+            }
+
+            if let Some((building, position)) = command.placement() {
+                if let Err(rejection) =
+                    placement_grid.check(building, position, *player, entity, &buildings)
+                {
+                    rejections.reject(&mut net, handle, rejection);
+                    continue;
+                }
+            }
+        }
+
+        accepted.push((entity, *player, action_message));
+    }
+
+    // a flow field searches the whole map, so only groups that were really ordered there together
+    // get one, whatever the client says. a player's commands are held back while one of their
+    // group orders comes in, it may take a few frames when it doesn't fit in one packet
+    let mut ready = Vec::new();
+
+    for (entity, player, action_message) in accepted {
+        let is_group = action_message
+            .operation
+            .command()
+            .map_or(false, |command| command.group_target().is_some());
+
+        if is_group || held.contains_key(&player) {
+            held.entry(player)
+                .or_default()
+                .commands
+                .push((entity, action_message));
+        } else {
+            ready.push((entity, action_message));
+        }
+    }
+
+    let mut released = Vec::new();
+
+    for (player, held_commands) in held.iter_mut() {
+        held_commands.frames += 1;
+
+        let mut groups: HashMap<(u32, u32), HashSet<NetworkEntity>> = HashMap::new();
+
+        for (_, action_message) in &held_commands.commands {
+            if let Some(group_target) = action_message
+                .operation
+                .command()
+                .and_then(|command| command.group_target())
+            {
+                groups
+                    .entry(group_key(group_target))
+                    .or_default()
+                    .insert(action_message.network_entity);
+            }
+        }
+
+        if held_commands.frames >= FLOW_FIELD_GROUP_FRAMES
+            || groups
+                .values()
+                .all(|group| group.len() >= FLOW_FIELD_GROUP_SIZE)
+        {
+            released.push((*player, groups));
+        }
+    }
+
+    for (player, groups) in released {
+        for (entity, mut action_message) in held.remove(&player).unwrap().commands {
+            if let Some(command) = action_message.operation.command_mut() {
+                if let Some(group_target) = command.group_target() {
+                    if groups[&group_key(group_target)].len() < FLOW_FIELD_GROUP_SIZE {
+                        command.leave_group();
+                    }
+                }
+            }
+
+            ready.push((entity, action_message));
+        }
+    }
+
+    for (entity, action_message) in ready {
+        if simulation_mode.is_lockstep() {
+            lockstep.queue(action_message);
+        } else if let Ok((mut action_queue, _)) = query.get_mut(entity) {
             action_queue.apply(action_message.operation.clone());
+            replay_recorder.record(fixed_tick.tick(), RecordedAction::Command(action_message));
+        }
+    }
+}
+
+fn group_key(group_target: Vec2) -> (u32, u32) {
+    (group_target.x.to_bits(), group_target.y.to_bits())
+}
+
+// idle units go after the closest enemy they can see
+fn unit_auto_attack_system(
+    teams: Res<Teams>,
+    units: Res<Assets<Unit>>,
+    targets: Query<(&NetworkEntity, &Position, &Owner), With<UnitInstance>>,
+    mut query: Query<(&Position, &Owner, &Handle<Unit>, &UnitStats, &mut CommandQueue)>,
+) {
+    for (position, owner, unit_handle, unit_stats, mut command_queue) in query.iter_mut() {
+        if !command_queue.commands.is_empty() {
+            continue;
+        }
+
+        match units.get(unit_handle) {
+            Some(unit) if !unit.attack_damage_frames.is_empty() => {}
+            _ => continue,
+        }
+
+        let target = targets
+            .iter()
+            .filter(|(_, _, target_owner)| teams.is_hostile(owner.0, target_owner.0))
+            .map(|(target, target_position, _)| {
+                let diff = target_position.position.truncate() - position.position.truncate();
+                (target, diff.length())
+            })
+            .filter(|(_, distance)| *distance <= unit_stats.sight_range)
+            // ties go to the oldest entity, so every lockstep peer picks the same one
+            .min_by(|(a, a_distance), (b, b_distance)| {
+                a_distance
+                    .partial_cmp(b_distance)
+                    .unwrap()
+                    .then(a.0.cmp(&b.0))
+            });
+
+        if let Some((target, _)) = target {
+            command_queue.apply(CommandQueueOperation::SetCommand(Box::new(
+                AttackUnitCommand { target: *target },
+            )));
         }
     }
 }
@@ -399,7 +763,9 @@ pub fn network_unit_action_system(
 pub fn unit_command_execution_system(
     units: Res<Assets<Unit>>,
     network_entity_registry: Res<NetworkEntityRegistry>,
+    placement_grid: Res<PlacementGrid>,
     command_query: Query<(&Position, &Animator)>,
+    economy_query: EconomyQuery,
     mut query: Query<(Entity, &mut CommandQueue, &mut Behaviour, &Handle<Unit>)>,
 ) {
     for (entity, mut command_queue, mut behaviour, unit_handle) in query.iter_mut() {
@@ -411,7 +777,15 @@ pub fn unit_command_execution_system(
 
         let request_set = command_queue.request_set.is_some();
         if let Some(command) = command_queue.commands.back_mut() {
-            match command.execute(entity, &unit, request_set, &network_entity_registry, &command_query) {
+            match command.execute(
+                entity,
+                &unit,
+                request_set,
+                &network_entity_registry,
+                &command_query,
+                &economy_query,
+                &placement_grid,
+            ) {
                 CommandControlFlow::Wait => {
                     // bib bob, do nothing
                 }
@@ -453,15 +827,33 @@ impl Plugin for UnitPlugin {
     fn build(&self, app_builder: &mut AppBuilder) {
         app_builder.add_asset_loader(UnitLoader);
         app_builder.add_asset::<Unit>();
+        app_builder.init_resource::<MatchStats>();
         
         if self.0 {
-            app_builder.add_system(unit_command_execution_system.system());
-            app_builder.add_system(unit_command_behaviour_system.system());
-            app_builder.add_system(unit_collision_system.system());
+            app_builder.add_system_to_stage(SIMULATION_STAGE, unit_auto_attack_system.system());
+            app_builder
+                .add_system_to_stage(SIMULATION_STAGE, unit_command_execution_system.system());
+            app_builder.add_system_to_stage(SIMULATION_STAGE, unit_pathfinding_system.system());
+            app_builder
+                .add_system_to_stage(SIMULATION_STAGE, unit_command_behaviour_system.system());
+            app_builder.add_system_to_stage(SIMULATION_STAGE, unit_collision_system.system());
+            app_builder.add_system_to_stage(SIMULATION_STAGE, server_unit_instance_system.system());
+            app_builder.add_system_to_stage(SIMULATION_STAGE, server_unit_health_system.system());
             app_builder.add_system(network_unit_action_system.system());
-            app_builder.add_system(server_unit_instance_system.system());
-            app_builder.add_system(server_unit_health_system.system());
         } else {
+            // only runs in lockstep, where the client simulates everything itself
+            app_builder.add_system_to_stage(SIMULATION_STAGE, unit_auto_attack_system.system());
+            app_builder
+                .add_system_to_stage(SIMULATION_STAGE, unit_command_execution_system.system());
+            app_builder.add_system_to_stage(SIMULATION_STAGE, unit_pathfinding_system.system());
+            app_builder
+                .add_system_to_stage(SIMULATION_STAGE, unit_command_behaviour_system.system());
+            app_builder.add_system_to_stage(SIMULATION_STAGE, unit_collision_system.system());
+            app_builder
+                .add_system_to_stage(SIMULATION_STAGE, unit_instance_operation_system.system());
+            app_builder
+                .add_system_to_stage(SIMULATION_STAGE, lockstep_unit_health_system.system());
+
             app_builder.add_system(unit_command_system.system());
             app_builder.add_system(unit_selection_system.system());
             app_builder.add_system(unit_selection_ring_system.system());
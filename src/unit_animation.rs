@@ -155,6 +155,6 @@ impl Plugin for UnitAnimationPlugin {
     fn build(&self, app_builder: &mut AppBuilder) {
         app_builder.add_asset::<UnitAnimationSet>();
         app_builder.add_asset_loader(UnitAnimationSetLoader);
-        app_builder.add_system(unit_animation_system.system());
+        app_builder.add_system_to_stage(SIMULATION_STAGE, unit_animation_system.system());
     }
 }
@@ -15,6 +15,7 @@ pub struct UnitSpawnable {
 impl Spawnable for UnitSpawnable {
     fn spawn(&self, commands: &mut Commands, resources: &Resources) -> Entity {
         let network_settings = resources.get::<NetworkSettings>().unwrap();
+        let simulation_mode = resources.get::<SimulationMode>().unwrap();
 
         let units = resources.get::<Assets<Unit>>().unwrap();
         let animation_sets = resources.get::<Assets<AnimationSet>>().unwrap();
@@ -26,6 +27,20 @@ impl Spawnable for UnitSpawnable {
 
         let animator = Animator::new(animation_set_handle, "idle_up");
 
+        // units trained after an upgrade start out with it
+        let unit_stats = resources
+            .get::<Upgrades>()
+            .unwrap()
+            .unit_stats(
+                self.owner,
+                &unit_handle,
+                &units,
+                &resources.get::<Assets<Upgrade>>().unwrap(),
+            )
+            .unwrap();
+        let mut unit_instance = unit.instance();
+        unit_instance.health = unit_stats.max_health;
+
         commands
             .spawn((CommandQueue {
                 commands: VecDeque::new(),
@@ -34,14 +49,21 @@ impl Spawnable for UnitSpawnable {
             .with(Behaviour::Idle)
             .with(animator)
             .with(unit_handle)
-            .with(unit.instance())
+            .with(unit_instance)
             .with(Position {
                 position: self.position,
             })
             .with(UnitDirection::Down)
-            .with(Owner(self.owner));
+            .with(Owner(self.owner))
+            .with(unit_stats.clone())
+            .with(Path::default());
+
+        if unit.harvester.is_some() {
+            commands.with(Cargo::default());
+        }
 
-        if network_settings.is_server {
+        // the unit animations are driven by the simulation
+        if network_settings.is_server || simulation_mode.is_lockstep() {
             let unit_animation_sets = resources.get::<Assets<UnitAnimationSet>>().unwrap();
             let unit_animation_set =
                 unit_animation_sets.get_handle(self.unit_animation_set.as_str());
@@ -49,7 +71,9 @@ impl Spawnable for UnitSpawnable {
             commands
                 .with(unit_animation_set)
                 .with(UnitAnimator::new("idle".into()));
-        } else {
+        }
+
+        if network_settings.is_client() {
             let textures = resources.get::<Assets<Texture>>().unwrap();
             let mut texture_atlases = resources.get_mut::<Assets<TextureAtlas>>().unwrap();
             let mut color_materials = resources.get_mut::<Assets<ColorMaterial>>().unwrap();
@@ -92,8 +116,8 @@ impl Spawnable for UnitSpawnable {
                         .spawn(BarBundle {
                             bar: Bar {
                                 size: Vec2::new(unit.width, 6.0),
-                                max_value: unit.max_health,
-                                current_value: unit.max_health,
+                                max_value: unit_stats.max_health,
+                                current_value: unit_stats.max_health,
                                 ..Default::default()
                             },
                             transform: Transform::from_translation(Vec3::new(
@@ -0,0 +1,555 @@
+use crate::*;
+use bevy::reflect::TypeUuid;
+use std::collections::HashMap;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Stat {
+    MaxHealth,
+    /// The damage of every attack frame.
+    AttackDamage,
+    MovementSpeed,
+    SightRange,
+}
+
+/// Changes a stat by a flat amount and a percentage of the unit's base value.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StatModifier {
+    pub stat: Stat,
+    #[serde(default)]
+    pub add: f32,
+    #[serde(default)]
+    pub percent: f32,
+}
+
+#[derive(TypeUuid, Serialize, Deserialize)]
+#[uuid = "3f0c9a52-6d1e-4b8f-a7c4-1e95d2b8f604"]
+pub struct Upgrade {
+    pub name: String,
+    pub cost: f32,
+    /// Seconds it takes to research.
+    pub research_time: f32,
+    pub modifiers: Vec<StatModifier>,
+    /// The units it applies to, all of them if empty.
+    #[serde(default)]
+    pub units: Vec<String>,
+}
+
+/// Every modifier researched for a stat, added together.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct Scale {
+    pub add: f32,
+    pub percent: f32,
+}
+
+impl Scale {
+    pub fn apply(&self, value: f32) -> f32 {
+        value * (1.0 + self.percent / 100.0) + self.add
+    }
+}
+
+/// A unit's stats with its owner's upgrades applied.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnitStats {
+    pub max_health: f32,
+    pub sight_range: f32,
+    pub attack_damage: Scale,
+    pub movement_speed: Scale,
+}
+
+/// The upgrade a player is researching.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Research {
+    pub upgrade: String,
+    /// Seconds spent researching it.
+    pub progress: f32,
+}
+
+impl Research {
+    /// How far along it is, from 0 to 1.
+    pub fn fraction(&self, upgrade: &Upgrade) -> f32 {
+        (self.progress / upgrade.research_time).min(1.0)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ResearchMessage {
+    pub building: NetworkEntity,
+    /// The index of the upgrade in the building's list.
+    pub upgrade: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UpgradeMessage {
+    pub researched: HashMap<PlayerId, Vec<String>>,
+    pub researching: HashMap<PlayerId, Research>,
+}
+
+/// The upgrades every player has researched, and the one they're researching.
+#[derive(Default)]
+pub struct Upgrades {
+    researched: HashMap<PlayerId, Vec<String>>,
+    researching: HashMap<PlayerId, Research>,
+    /// Bumped whenever an upgrade is researched, so unit stats know to update.
+    generation: u64,
+    changed: bool,
+    reset: bool,
+}
+
+impl Upgrades {
+    pub fn researched(&self, player_id: PlayerId) -> &[String] {
+        self.researched
+            .get(&player_id)
+            .map_or(&[], |researched| researched.as_slice())
+    }
+
+    pub fn researching(&self, player_id: PlayerId) -> Option<&Research> {
+        self.researching.get(&player_id)
+    }
+
+    pub fn validate(
+        &self,
+        player_id: PlayerId,
+        upgrade_path: &str,
+        upgrade: &Upgrade,
+        stockpiles: &Stockpiles,
+    ) -> Result<(), Rejection> {
+        if self
+            .researched(player_id)
+            .iter()
+            .any(|path| path == upgrade_path)
+        {
+            Err(Rejection::AlreadyResearched(upgrade_path.to_string()))
+        } else if self.researching.contains_key(&player_id) {
+            Err(Rejection::AlreadyResearching)
+        } else if stockpiles.get(player_id) < upgrade.cost {
+            Err(Rejection::NotEnoughResources)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Checked again when applied, as the stockpile might have been spent in the meantime.
+    pub fn start(
+        &mut self,
+        player_id: PlayerId,
+        upgrade_path: &str,
+        upgrade: &Upgrade,
+        stockpiles: &mut Stockpiles,
+    ) {
+        if self
+            .validate(player_id, upgrade_path, upgrade, stockpiles)
+            .is_err()
+        {
+            return;
+        }
+
+        stockpiles.spend(player_id, upgrade.cost);
+        self.researching.insert(
+            player_id,
+            Research {
+                upgrade: upgrade_path.to_string(),
+                progress: 0.0,
+            },
+        );
+        self.changed = true;
+    }
+
+    /// Replaces every player's upgrades, at the start of a match or when one is loaded.
+    pub fn reset(
+        &mut self,
+        researched: HashMap<PlayerId, Vec<String>>,
+        researching: HashMap<PlayerId, Research>,
+    ) {
+        self.researched = researched;
+        self.researching = researching;
+        self.generation += 1;
+        self.changed = true;
+        self.reset = true;
+    }
+
+    pub fn all_researched(&self) -> &HashMap<PlayerId, Vec<String>> {
+        &self.researched
+    }
+
+    pub fn all_researching(&self) -> &HashMap<PlayerId, Research> {
+        &self.researching
+    }
+
+    /// The stats of a player's unit, with everything they've researched for it.
+    pub fn unit_stats(
+        &self,
+        player_id: PlayerId,
+        unit_handle: &Handle<Unit>,
+        units: &Assets<Unit>,
+        upgrades: &Assets<Upgrade>,
+    ) -> Option<UnitStats> {
+        let unit = units.get(unit_handle)?;
+        let mut scales: HashMap<Stat, Scale> = HashMap::new();
+
+        let researched = self
+            .researched(player_id)
+            .iter()
+            .filter_map(|path| upgrades.get(&upgrades.get_handle(path.as_str())))
+            .filter(|upgrade| {
+                upgrade.units.is_empty()
+                    || upgrade
+                        .units
+                        .iter()
+                        .any(|path| units.get_handle(path.as_str()) == *unit_handle)
+            });
+
+        for upgrade in researched {
+            for modifier in &upgrade.modifiers {
+                let scale = scales.entry(modifier.stat).or_default();
+                scale.add += modifier.add;
+                scale.percent += modifier.percent;
+            }
+        }
+
+        let scale = |stat: Stat| scales.get(&stat).cloned().unwrap_or_default();
+
+        Some(UnitStats {
+            max_health: scale(Stat::MaxHealth).apply(unit.max_health),
+            sight_range: scale(Stat::SightRange).apply(unit.sight_range),
+            attack_damage: scale(Stat::AttackDamage),
+            movement_speed: scale(Stat::MovementSpeed),
+        })
+    }
+
+    fn message(&self) -> UpgradeMessage {
+        UpgradeMessage {
+            researched: self.researched.clone(),
+            researching: self.researching.clone(),
+        }
+    }
+}
+
+/// Starts researching the upgrade a research message is for, if the building still exists.
+pub fn apply_research_message(
+    research_message: &ResearchMessage,
+    network_entity_registry: &NetworkEntityRegistry,
+    buildings: &Assets<Building>,
+    upgrade_assets: &Assets<Upgrade>,
+    upgrades: &mut Upgrades,
+    stockpiles: &mut Stockpiles,
+    query: &Query<(&Owner, &Handle<Building>)>,
+) {
+    let entity = match network_entity_registry.get(&research_message.building) {
+        Some(entity) => *entity,
+        None => return,
+    };
+
+    let (owner, building_handle) = match query.get(entity) {
+        Ok(components) => components,
+        Err(_) => return,
+    };
+
+    let upgrade_path = match buildings
+        .get(building_handle)
+        .and_then(|building| building.upgrades.get(research_message.upgrade))
+    {
+        Some(upgrade_path) => upgrade_path,
+        None => return,
+    };
+
+    if let Some(upgrade) = upgrade_assets.get(&upgrade_assets.get_handle(upgrade_path.as_str())) {
+        upgrades.start(owner.0, upgrade_path, upgrade, stockpiles);
+    }
+}
+
+fn server_research_message_system(
+    simulation_mode: Res<SimulationMode>,
+    fixed_tick: Res<FixedTick>,
+    players: Res<Players>,
+    buildings: Res<Assets<Building>>,
+    upgrade_assets: Res<Assets<Upgrade>>,
+    network_entity_registry: Res<NetworkEntityRegistry>,
+    mut net: ResMut<Network>,
+    mut rejections: ResMut<Rejections>,
+    mut stockpiles: ResMut<Stockpiles>,
+    mut upgrades: ResMut<Upgrades>,
+    mut lockstep: ResMut<Lockstep>,
+    mut replay_recorder: ResMut<ReplayRecorder>,
+    constructions: Query<&Construction>,
+    query: Query<(&Owner, &Handle<Building>)>,
+) {
+    for (handle, research_message) in net.recv::<ResearchMessage>() {
+        let network_entity = research_message.building;
+
+        let player = match players.player_ids.get(&handle) {
+            Some(player) => *player,
+            None => {
+                rejections.reject(&mut net, handle, Rejection::NotAPlayer);
+                continue;
+            }
+        };
+
+        let entity = match network_entity_registry.get(&network_entity) {
+            Some(entity) => *entity,
+            None => {
+                rejections.reject(&mut net, handle, Rejection::UnknownEntity(network_entity));
+                continue;
+            }
+        };
+
+        let (owner, building_handle) = match query.get(entity) {
+            Ok(components) => components,
+            Err(_) => {
+                rejections.reject(&mut net, handle, Rejection::NotCommandable(network_entity));
+                continue;
+            }
+        };
+
+        if owner.0 != player {
+            rejections.reject(&mut net, handle, Rejection::NotOwner(network_entity));
+            continue;
+        }
+
+        if constructions.get(entity).is_ok() {
+            rejections.reject(&mut net, handle, Rejection::NotConstructed(network_entity));
+            continue;
+        }
+
+        let upgrade = buildings
+            .get(building_handle)
+            .and_then(|building| building.upgrades.get(research_message.upgrade))
+            .and_then(|upgrade_path| {
+                let upgrade =
+                    upgrade_assets.get(&upgrade_assets.get_handle(upgrade_path.as_str()))?;
+                Some((upgrade_path, upgrade))
+            });
+
+        let (upgrade_path, upgrade) = match upgrade {
+            Some(upgrade) => upgrade,
+            None => {
+                let rejection = Rejection::InvalidUpgrade(research_message.upgrade);
+                rejections.reject(&mut net, handle, rejection);
+                continue;
+            }
+        };
+
+        if let Err(rejection) = upgrades.validate(player, upgrade_path, upgrade, &stockpiles) {
+            rejections.reject(&mut net, handle, rejection);
+            continue;
+        }
+
+        if simulation_mode.is_lockstep() {
+            lockstep.queue_research(research_message);
+        } else {
+            apply_research_message(
+                &research_message,
+                &network_entity_registry,
+                &buildings,
+                &upgrade_assets,
+                &mut upgrades,
+                &mut stockpiles,
+                &query,
+            );
+            replay_recorder.record(
+                fixed_tick.tick(),
+                RecordedAction::Research(research_message),
+            );
+        }
+    }
+}
+
+fn research_system(
+    simulation_time: Res<SimulationTime>,
+    upgrade_assets: Res<Assets<Upgrade>>,
+    mut upgrades: ResMut<Upgrades>,
+) {
+    let mut finished = Vec::new();
+
+    for (player_id, research) in upgrades.researching.iter_mut() {
+        let upgrade =
+            match upgrade_assets.get(&upgrade_assets.get_handle(research.upgrade.as_str())) {
+                Some(upgrade) => upgrade,
+                None => continue,
+            };
+
+        research.progress += simulation_time.delta_seconds();
+
+        if research.progress >= upgrade.research_time {
+            finished.push(*player_id);
+        }
+    }
+
+    for player_id in finished {
+        if let Some(research) = upgrades.researching.remove(&player_id) {
+            upgrades
+                .researched
+                .entry(player_id)
+                .or_insert_with(Vec::new)
+                .push(research.upgrade);
+            upgrades.generation += 1;
+            upgrades.changed = true;
+        }
+    }
+}
+
+/// Keeps the units' stats in line with their owner's upgrades, healing them by any health gained.
+fn unit_stats_system(
+    mut generation: Local<u64>,
+    units: Res<Assets<Unit>>,
+    upgrade_assets: Res<Assets<Upgrade>>,
+    upgrades: Res<Upgrades>,
+    mut query: Query<(&Owner, &Handle<Unit>, &mut UnitStats, &mut UnitInstance)>,
+) {
+    if *generation == upgrades.generation {
+        return;
+    }
+    *generation = upgrades.generation;
+
+    for (owner, unit_handle, mut unit_stats, mut unit_instance) in query.iter_mut() {
+        let new_stats = match upgrades.unit_stats(owner.0, unit_handle, &units, &upgrade_assets) {
+            Some(new_stats) => new_stats,
+            None => continue,
+        };
+
+        if new_stats.max_health != unit_stats.max_health {
+            unit_instance.add_health(new_stats.max_health - unit_stats.max_health);
+        }
+
+        if *unit_stats != new_stats {
+            *unit_stats = new_stats;
+        }
+    }
+}
+
+// the server sends the health, this is only for what the client shows
+fn client_unit_stats_system(
+    mut generation: Local<u64>,
+    simulation_mode: Res<SimulationMode>,
+    units: Res<Assets<Unit>>,
+    upgrade_assets: Res<Assets<Upgrade>>,
+    upgrades: Res<Upgrades>,
+    mut query: Query<(&Owner, &Handle<Unit>, &mut UnitStats)>,
+) {
+    if simulation_mode.is_lockstep() || *generation == upgrades.generation {
+        return;
+    }
+    *generation = upgrades.generation;
+
+    for (owner, unit_handle, mut unit_stats) in query.iter_mut() {
+        if let Some(new_stats) = upgrades.unit_stats(owner.0, unit_handle, &units, &upgrade_assets)
+        {
+            if *unit_stats != new_stats {
+                *unit_stats = new_stats;
+            }
+        }
+    }
+}
+
+fn server_upgrade_system(
+    mut throttle: Local<SyncThrottle>,
+    mut reader: Local<EventReader<SynchronizeEvent>>,
+    events: Res<Events<SynchronizeEvent>>,
+    time: Res<Time>,
+    simulation_mode: Res<SimulationMode>,
+    mut upgrades: ResMut<Upgrades>,
+    mut net: ResMut<Network>,
+) {
+    for SynchronizeEvent(handle) in reader.iter(&events) {
+        net.send_message(*handle, upgrades.message());
+    }
+
+    throttle.tick(&time);
+
+    // lockstep peers research on their own, they only need to know where they start
+    let send = upgrades.reset
+        || (!simulation_mode.is_lockstep()
+            && (upgrades.changed || (throttle.is_due() && !upgrades.researching.is_empty())));
+
+    if send {
+        net.broadcast_message(upgrades.message());
+    }
+
+    upgrades.changed = false;
+    upgrades.reset = false;
+}
+
+fn client_upgrade_system(mut net: ResMut<Network>, mut upgrades: ResMut<Upgrades>) {
+    for (_handle, upgrade_message) in net.recv::<UpgradeMessage>() {
+        if upgrades.researched != upgrade_message.researched {
+            upgrades.generation += 1;
+        }
+
+        upgrades.researched = upgrade_message.researched;
+        upgrades.researching = upgrade_message.researching;
+    }
+}
+
+fn client_research_input_system(
+    selected_building: Res<SelectedBuilding>,
+    input_config: Res<Assets<InputConfig>>,
+    input_resource: Res<InputResource>,
+    chat_input: Res<ChatInput>,
+    mouse_input: Res<Input<MouseButton>>,
+    keyboard_input: Res<Input<KeyCode>>,
+    mut net: ResMut<Network>,
+    query: Query<&NetworkEntity>,
+) {
+    // typing in the chat shouldn't research anything
+    if chat_input.scope.is_some() {
+        return;
+    }
+
+    let input_config = match input_config.get(&input_resource.0) {
+        Some(input_config) => input_config,
+        None => return,
+    };
+
+    let network_entity = match selected_building
+        .0
+        .and_then(|entity| query.get(entity).ok())
+    {
+        Some(network_entity) => *network_entity,
+        None => return,
+    };
+
+    for (index, input) in input_config.research.iter().enumerate() {
+        if input.just_pressed(&keyboard_input, &mouse_input) {
+            net.broadcast_message(ResearchMessage {
+                building: network_entity,
+                upgrade: index,
+            });
+        }
+    }
+}
+
+pub struct UpgradeLoader;
+
+ron_loader!(UpgradeLoader, "upgrade" => Upgrade);
+
+pub struct UpgradePlugin(pub bool);
+
+impl UpgradePlugin {
+    pub fn server() -> Self {
+        Self(true)
+    }
+
+    pub fn client() -> Self {
+        Self(false)
+    }
+}
+
+impl Plugin for UpgradePlugin {
+    fn build(&self, app_builder: &mut AppBuilder) {
+        app_builder.add_asset_loader(UpgradeLoader);
+        app_builder.add_asset::<Upgrade>();
+        app_builder.init_resource::<Upgrades>();
+
+        // only runs in lockstep on clients, where they simulate everything themselves
+        app_builder.add_system_to_stage(SIMULATION_STAGE, research_system.system());
+        app_builder.add_system_to_stage(SIMULATION_STAGE, unit_stats_system.system());
+
+        if self.0 {
+            app_builder.add_system(server_research_message_system.system());
+            app_builder.add_system_to_stage(bevy::app::stage::LAST, server_upgrade_system.system());
+        } else {
+            app_builder.add_system(client_upgrade_system.system());
+            app_builder.add_system(client_unit_stats_system.system());
+            app_builder.add_system(client_research_input_system.system());
+        }
+    }
+}
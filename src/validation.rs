@@ -0,0 +1,116 @@
+use crate::*;
+use std::collections::HashMap;
+use std::fmt;
+
+/// Why the server refused a message from a client.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Rejection {
+    NotAPlayer,
+    UnknownEntity(NetworkEntity),
+    NotCommandable(NetworkEntity),
+    NotOwner(NetworkEntity),
+    InvalidTarget(NetworkEntity),
+    FriendlyTarget(NetworkEntity),
+    InvalidPosition,
+    InvalidSlot(PlayerId),
+    MatchRunning,
+    InvalidTick(u64),
+    InvalidProduction(usize),
+    ProductionQueueFull,
+    NotEnoughResources,
+    NotConstructed(NetworkEntity),
+    UnknownBuilding(String),
+    PlacementBlocked(TilePosition),
+    InvalidUpgrade(usize),
+    AlreadyResearched(String),
+    AlreadyResearching,
+    UnexpectedMessage(String),
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Rejection::NotAPlayer => write!(f, "connection is not a player"),
+            Rejection::UnknownEntity(entity) => write!(f, "{:?} does not exist", entity),
+            Rejection::NotCommandable(entity) => write!(f, "{:?} can't be commanded", entity),
+            Rejection::NotOwner(entity) => write!(f, "{:?} is owned by someone else", entity),
+            Rejection::InvalidTarget(entity) => write!(f, "{:?} is not a valid target", entity),
+            Rejection::FriendlyTarget(entity) => write!(f, "{:?} is on your side", entity),
+            Rejection::InvalidPosition => write!(f, "position is not a valid target"),
+            Rejection::InvalidSlot(player_id) => write!(f, "{:?} is not a free slot", player_id),
+            Rejection::MatchRunning => write!(f, "the match is already running"),
+            Rejection::InvalidTick(tick) => write!(f, "tick {} was never sent", tick),
+            Rejection::InvalidProduction(index) => {
+                write!(f, "the building can't train production {}", index)
+            }
+            Rejection::ProductionQueueFull => write!(f, "the production queue is full"),
+            Rejection::NotEnoughResources => write!(f, "not enough resources"),
+            Rejection::NotConstructed(entity) => write!(f, "{:?} isn't built yet", entity),
+            Rejection::UnknownBuilding(building) => write!(f, "{} is not a building", building),
+            Rejection::PlacementBlocked(position) => {
+                write!(f, "can't build at {:?}, something is in the way", position)
+            }
+            Rejection::InvalidUpgrade(index) => {
+                write!(f, "the building can't research upgrade {}", index)
+            }
+            Rejection::AlreadyResearched(upgrade) => write!(f, "{} is already researched", upgrade),
+            Rejection::AlreadyResearching => write!(f, "something is already being researched"),
+            Rejection::UnexpectedMessage(message) => {
+                write!(f, "clients aren't supposed to send {}", message)
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RejectionMessage {
+    pub rejection: Rejection,
+}
+
+/// Logs every rejected message, and tells the sender about it if enabled.
+pub struct Rejections {
+    report: bool,
+    counts: HashMap<ConnectionHandle, u64>,
+}
+
+impl Rejections {
+    pub fn new(report: bool) -> Self {
+        Self {
+            report,
+            counts: HashMap::new(),
+        }
+    }
+
+    pub fn reject(&mut self, net: &mut Network, handle: ConnectionHandle, rejection: Rejection) {
+        let count = self.counts.entry(handle).or_insert(0);
+        *count += 1;
+
+        warn!(
+            "Rejected message from {} ({} so far): {}",
+            handle, count, rejection
+        );
+
+        if self.report {
+            net.send_message(handle, RejectionMessage { rejection });
+        }
+    }
+
+    pub fn count(&self, handle: ConnectionHandle) -> u64 {
+        self.counts.get(&handle).cloned().unwrap_or(0)
+    }
+}
+
+fn client_rejection_system(mut net: ResMut<Network>) {
+    for (_handle, rejection_message) in net.recv::<RejectionMessage>() {
+        warn!("Server rejected: {}", rejection_message.rejection);
+    }
+}
+
+/// Shows the client why the server rejected its messages.
+pub struct ValidationPlugin;
+
+impl Plugin for ValidationPlugin {
+    fn build(&self, app_builder: &mut AppBuilder) {
+        app_builder.add_system(client_rejection_system.system());
+    }
+}
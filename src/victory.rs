@@ -0,0 +1,492 @@
+use crate::*;
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+
+// how long the result is shown before the server moves on
+const MATCH_END_DELAY: f32 = 10.0;
+
+/// How a map's match can be won, checked in the order given by the map.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum WinCondition {
+    /// The last team with units left wins.
+    Annihilation,
+    /// Once the time is up, the team with the highest score wins.
+    TimeLimit { seconds: f32 },
+    /// The first team to hold the area on its own for long enough wins.
+    Objective {
+        position: Vec2,
+        radius: f32,
+        seconds: f32,
+    },
+}
+
+pub fn default_win_conditions() -> Vec<WinCondition> {
+    vec![WinCondition::Annihilation]
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AfterMatch {
+    /// Everyone goes back to the lobby for another match.
+    Lobby,
+    Exit,
+}
+
+impl FromStr for AfterMatch {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lobby" => Ok(AfterMatch::Lobby),
+            "exit" => Ok(AfterMatch::Exit),
+            _ => Err(format!("unknown after match action '{}'", s)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum MatchEndReason {
+    Annihilation,
+    TimeLimit,
+    /// The index of the objective in the map's win conditions.
+    Objective(usize),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PlayerStats {
+    pub player_id: PlayerId,
+    pub name: String,
+    pub team: u32,
+    pub units_left: u32,
+    pub units_lost: u32,
+    pub damage_dealt: f32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MatchResultMessage {
+    /// Nobody wins in a draw.
+    pub winners: Vec<PlayerId>,
+    pub reason: MatchEndReason,
+    pub duration: f32,
+    pub stats: Vec<PlayerStats>,
+}
+
+/// What every player did over the match, kept by the simulation.
+#[derive(Default)]
+pub struct MatchStats {
+    damage_dealt: HashMap<PlayerId, f32>,
+    units_lost: HashMap<PlayerId, u32>,
+}
+
+impl MatchStats {
+    pub fn record_damage(&mut self, player_id: PlayerId, damage: f32) {
+        *self.damage_dealt.entry(player_id).or_insert(0.0) += damage;
+    }
+
+    pub fn record_loss(&mut self, player_id: PlayerId) {
+        *self.units_lost.entry(player_id).or_insert(0) += 1;
+    }
+
+    /// The score deciding a match at its time limit.
+    pub fn score(&self, player_id: PlayerId) -> f32 {
+        self.damage_dealt.get(&player_id).cloned().unwrap_or(0.0)
+    }
+
+    pub fn units_lost(&self, player_id: PlayerId) -> u32 {
+        self.units_lost.get(&player_id).cloned().unwrap_or(0)
+    }
+}
+
+/// Tracks the win conditions of the running match on the server.
+#[derive(Default)]
+pub struct MatchOutcome {
+    elapsed: f32,
+    /// The map is only spawned at the end of the frame the match starts in.
+    spawned: bool,
+    /// The team holding each objective, and for how long.
+    objectives: HashMap<usize, (u32, f32)>,
+    remaining: f32,
+}
+
+fn server_victory_system(
+    simulation_time: Res<SimulationTime>,
+    maps: Res<Assets<Map>>,
+    map_handle: Res<Handle<Map>>,
+    teams: Res<Teams>,
+    lobby: Res<Lobby>,
+    match_stats: Res<MatchStats>,
+    mut match_state: ResMut<MatchState>,
+    mut match_outcome: ResMut<MatchOutcome>,
+    mut replay_recorder: ResMut<ReplayRecorder>,
+    mut net: ResMut<Network>,
+    units: Query<(&Owner, &UnitInstance, &Position)>,
+) {
+    if *match_state != MatchState::Running {
+        return;
+    }
+
+    let map = match maps.get(&*map_handle) {
+        Some(map) => map,
+        None => return,
+    };
+
+    let mut player_ids: Vec<PlayerId> = lobby
+        .player_ids()
+        .filter(|player_id| map.players.contains(player_id))
+        .cloned()
+        .collect();
+    player_ids.sort();
+
+    let mut units_left: HashMap<PlayerId, u32> = HashMap::new();
+    for (owner, unit_instance, _) in units.iter() {
+        if unit_instance.health > 0.0 {
+            *units_left.entry(owner.0).or_insert(0) += 1;
+        }
+    }
+
+    if !match_outcome.spawned {
+        if units_left.is_empty() {
+            return;
+        }
+
+        match_outcome.spawned = true;
+    }
+
+    match_outcome.elapsed += simulation_time.delta_seconds();
+
+    // ordered, so every draw and tie plays out the same
+    let mut team_members: BTreeMap<u32, Vec<PlayerId>> = BTreeMap::new();
+    for player_id in &player_ids {
+        team_members
+            .entry(teams.team(*player_id))
+            .or_default()
+            .push(*player_id);
+    }
+
+    let mut result = None;
+
+    for (index, win_condition) in map.win_conditions.iter().enumerate() {
+        result = match win_condition {
+            WinCondition::Annihilation => {
+                let alive: Vec<u32> = team_members
+                    .iter()
+                    .filter(|(_, members)| {
+                        members
+                            .iter()
+                            .any(|player_id| units_left.contains_key(player_id))
+                    })
+                    .map(|(team, _)| *team)
+                    .collect();
+
+                match alive.as_slice() {
+                    [] => Some((None, MatchEndReason::Annihilation)),
+                    // a map with a single team is played until another condition ends it
+                    [team] if team_members.len() > 1 => {
+                        Some((Some(*team), MatchEndReason::Annihilation))
+                    }
+                    _ => None,
+                }
+            }
+            WinCondition::TimeLimit { seconds } if match_outcome.elapsed >= *seconds => {
+                let scores: Vec<(u32, f32)> = team_members
+                    .iter()
+                    .map(|(team, members)| {
+                        let score = members
+                            .iter()
+                            .map(|player_id| match_stats.score(*player_id))
+                            .sum();
+                        (*team, score)
+                    })
+                    .collect();
+
+                let best = scores.iter().map(|(_, score)| *score).fold(0.0, f32::max);
+                let best_teams: Vec<u32> = scores
+                    .iter()
+                    .filter(|(_, score)| *score == best)
+                    .map(|(team, _)| *team)
+                    .collect();
+
+                match best_teams.as_slice() {
+                    [team] => Some((Some(*team), MatchEndReason::TimeLimit)),
+                    _ => Some((None, MatchEndReason::TimeLimit)),
+                }
+            }
+            WinCondition::TimeLimit { .. } => None,
+            WinCondition::Objective {
+                position,
+                radius,
+                seconds,
+            } => {
+                let mut inside: Vec<u32> = units
+                    .iter()
+                    .filter(|(_, unit_instance, unit_position)| {
+                        unit_instance.health > 0.0
+                            && (unit_position.position.truncate() - *position).length() <= *radius
+                    })
+                    .map(|(owner, _, _)| teams.team(owner.0))
+                    .collect();
+                inside.sort();
+                inside.dedup();
+
+                // contested or empty objectives keep their progress
+                if let [team] = inside.as_slice() {
+                    let held = match_outcome
+                        .objectives
+                        .entry(index)
+                        .or_insert((*team, 0.0));
+
+                    if held.0 != *team {
+                        *held = (*team, 0.0);
+                    }
+
+                    held.1 += simulation_time.delta_seconds();
+
+                    if held.1 >= *seconds {
+                        Some((Some(*team), MatchEndReason::Objective(index)))
+                    } else {
+                        None
+                    }
+                } else {
+                    None
+                }
+            }
+        };
+
+        if result.is_some() {
+            break;
+        }
+    }
+
+    let (winning_team, reason) = match result {
+        Some(result) => result,
+        None => return,
+    };
+
+    let winners = winning_team
+        .and_then(|team| team_members.get(&team).cloned())
+        .unwrap_or_default();
+
+    let stats = player_ids
+        .iter()
+        .map(|player_id| PlayerStats {
+            player_id: *player_id,
+            name: lobby
+                .get(*player_id)
+                .map_or_else(String::new, |player| player.name.clone()),
+            team: teams.team(*player_id),
+            units_left: units_left.get(player_id).cloned().unwrap_or(0),
+            units_lost: match_stats.units_lost(*player_id),
+            damage_dealt: match_stats.score(*player_id),
+        })
+        .collect();
+
+    let result_message = MatchResultMessage {
+        winners,
+        reason,
+        duration: match_outcome.elapsed,
+        stats,
+    };
+
+    info!("match over: {:?}", result_message);
+
+    net.broadcast_message(result_message);
+    replay_recorder.finish();
+    match_outcome.remaining = MATCH_END_DELAY;
+    *match_state = MatchState::Ended;
+}
+
+fn server_match_end_system(
+    commands: &mut Commands,
+    time: Res<Time>,
+    after_match: Res<AfterMatch>,
+    simulation_mode: Res<SimulationMode>,
+    players: Res<Players>,
+    mut match_state: ResMut<MatchState>,
+    mut match_outcome: ResMut<MatchOutcome>,
+    mut match_stats: ResMut<MatchStats>,
+    mut stockpiles: ResMut<Stockpiles>,
+    mut upgrades: ResMut<Upgrades>,
+    mut lobby: ResMut<Lobby>,
+    mut disconnects: ResMut<Disconnects>,
+    mut net: ResMut<Network>,
+    mut app_exit_events: ResMut<Events<bevy::app::AppExit>>,
+    query: Query<(Entity, &NetworkEntity)>,
+) {
+    if *match_state != MatchState::Ended {
+        return;
+    }
+
+    match_outcome.remaining -= time.delta_seconds();
+
+    if match_outcome.remaining > 0.0 {
+        return;
+    }
+
+    match *after_match {
+        AfterMatch::Exit => {
+            info!("match over, shutting down");
+            app_exit_events.send(bevy::app::AppExit);
+        }
+        AfterMatch::Lobby => {
+            info!("match over, returning to the lobby");
+
+            for (entity, network_entity) in query.iter() {
+                commands.despawn_recursive(entity);
+
+                // lockstep peers only despawn what dies in their simulation
+                if simulation_mode.is_lockstep() {
+                    net.broadcast_message(DespawnMessage {
+                        entity: *network_entity,
+                    });
+                }
+            }
+
+            lobby.reset(&players);
+            disconnects.reset();
+            *match_stats = MatchStats::default();
+            stockpiles.reset(HashMap::new());
+            upgrades.reset(HashMap::new(), HashMap::new());
+            *match_outcome = MatchOutcome::default();
+            *match_state = MatchState::Waiting;
+        }
+    }
+}
+
+/// The result of the match the client is in, once it's over.
+#[derive(Default)]
+pub struct MatchResult(pub Option<MatchResultMessage>);
+
+pub struct MatchResultText;
+
+fn client_match_result_setup(commands: &mut Commands, asset_server: Res<AssetServer>) {
+    commands
+        .spawn(TextBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: Rect {
+                    top: Val::Percent(30.0),
+                    left: Val::Percent(30.0),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            text: Text {
+                value: String::new(),
+                font: asset_server.load("fonts/DejaVuSans.ttf"),
+                style: TextStyle {
+                    font_size: 28.0,
+                    color: Color::WHITE,
+                    ..Default::default()
+                },
+            },
+            ..Default::default()
+        })
+        .with(MatchResultText);
+}
+
+fn format_result(result: &MatchResultMessage, player_id: Option<PlayerId>) -> String {
+    let title = match player_id {
+        _ if result.winners.is_empty() => "Draw".to_string(),
+        Some(player_id) if result.winners.contains(&player_id) => "Victory".to_string(),
+        Some(player_id)
+            if result
+                .stats
+                .iter()
+                .any(|stats| stats.player_id == player_id) =>
+        {
+            "Defeat".to_string()
+        }
+        // spectators only see who won
+        _ => {
+            let names: Vec<&str> = result
+                .stats
+                .iter()
+                .filter(|stats| result.winners.contains(&stats.player_id))
+                .map(|stats| stats.name.as_str())
+                .collect();
+
+            format!("{} won", names.join(", "))
+        }
+    };
+
+    let reason = match result.reason {
+        MatchEndReason::Annihilation => "by annihilation",
+        MatchEndReason::TimeLimit => "on score at the time limit",
+        MatchEndReason::Objective(_) => "by holding the objective",
+    };
+
+    let seconds = result.duration as u64;
+    let mut lines = vec![
+        title,
+        format!(
+            "Decided {} after {:02}:{:02}",
+            reason,
+            seconds / 60,
+            seconds % 60
+        ),
+        String::new(),
+    ];
+
+    for stats in &result.stats {
+        lines.push(format!(
+            "{} (team {}): {} units left, {} lost, {:.0} damage dealt",
+            stats.name, stats.team, stats.units_left, stats.units_lost, stats.damage_dealt
+        ));
+    }
+
+    lines.join("\n")
+}
+
+fn client_match_result_system(
+    mut net: ResMut<Network>,
+    mut match_result: ResMut<MatchResult>,
+    lobby_state: Res<LobbyState>,
+    player_id: Res<Option<PlayerId>>,
+    mut query: Query<&mut Text, With<MatchResultText>>,
+) {
+    for (_handle, result_message) in net.recv::<MatchResultMessage>() {
+        info!("{:?}", result_message);
+        match_result.0 = Some(result_message);
+    }
+
+    // back in the lobby
+    if !lobby_state.started {
+        match_result.0 = None;
+    }
+
+    let value = match &match_result.0 {
+        Some(result) => format_result(result, *player_id),
+        None => String::new(),
+    };
+
+    for mut text in query.iter_mut() {
+        if text.value != value {
+            text.value = value.clone();
+        }
+    }
+}
+
+pub struct VictoryPlugin(pub bool);
+
+impl VictoryPlugin {
+    pub fn server() -> Self {
+        Self(true)
+    }
+
+    pub fn client() -> Self {
+        Self(false)
+    }
+}
+
+impl Plugin for VictoryPlugin {
+    fn build(&self, app_builder: &mut AppBuilder) {
+        if self.0 {
+            app_builder.init_resource::<MatchOutcome>();
+            app_builder.add_system_to_stage(SIMULATION_STAGE, server_victory_system.system());
+            app_builder.add_system(server_match_end_system.system());
+        } else {
+            app_builder.init_resource::<MatchResult>();
+            app_builder.add_startup_system(client_match_result_setup.system());
+            app_builder.add_system(client_match_result_system.system());
+        }
+    }
+}
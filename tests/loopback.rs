@@ -0,0 +1,123 @@
+use clap::Clap;
+use robots_or_smth::*;
+use std::time::{Duration, Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+struct Match {
+    server: App,
+    // the handle the server knows the client by, with the client
+    clients: Vec<(ConnectionHandle, App)>,
+}
+
+impl Match {
+    // the default map has two slots, so it takes two clients to start a match
+    fn new() -> Self {
+        let hub = LoopbackHub::default();
+        let server_endpoint = hub.endpoint();
+
+        let server = Server::parse_from(&["server"])
+            .app(Transport::Loopback(server_endpoint.clone()))
+            .app;
+
+        let clients = (0..2)
+            .map(|i| {
+                let client_endpoint = hub.endpoint();
+                hub.connect(&server_endpoint, &client_endpoint);
+
+                let handle = client_endpoint.handle();
+                let name = format!("Client {}", i);
+                let client = Client::parse_from(&["client", "--name", name.as_str()])
+                    .headless_app(Transport::Loopback(client_endpoint))
+                    .app;
+
+                (handle, client)
+            })
+            .collect();
+
+        Self { server, clients }
+    }
+
+    fn update(&mut self) {
+        self.server.update();
+
+        for (_, client) in &mut self.clients {
+            client.update();
+        }
+
+        // assets are loaded in the background and the handshake is retried on a timer
+        std::thread::sleep(Duration::from_millis(5));
+    }
+
+    fn update_until(&mut self, mut done: impl FnMut(&Self) -> bool) {
+        let start = Instant::now();
+
+        while !done(self) {
+            assert!(start.elapsed() < TIMEOUT, "timed out");
+            self.update();
+        }
+    }
+}
+
+fn connection_status(app: &App) -> ConnectionStatus {
+    app.resources.get::<ConnectionStatus>().unwrap().clone()
+}
+
+fn network_entities(app: &App) -> Vec<NetworkEntity> {
+    let mut network_entities: Vec<NetworkEntity> = app
+        .resources
+        .get::<NetworkEntityRegistry>()
+        .unwrap()
+        .iter()
+        .map(|(network_entity, _)| *network_entity)
+        .collect();
+    network_entities.sort_by_key(|network_entity| network_entity.0);
+    network_entities
+}
+
+// one test, since the server installs the global log subscriber
+#[test]
+fn clients_join_and_receive_the_map_over_loopback() {
+    let mut game = Match::new();
+
+    game.update_until(|game| {
+        game.clients
+            .iter()
+            .all(|(_, client)| matches!(connection_status(client), ConnectionStatus::Connected(_)))
+    });
+
+    let mut player_ids: Vec<PlayerId> = game
+        .clients
+        .iter()
+        .map(|(_, client)| client.resources.get::<Option<PlayerId>>().unwrap().unwrap())
+        .collect();
+    player_ids.sort();
+
+    assert_eq!(player_ids, vec![PlayerId(0), PlayerId(1)]);
+
+    for (_, client) in &mut game.clients {
+        let mut net = client.resources.get_mut::<Network>().unwrap();
+        net.broadcast_message(LobbyMessage::SetReady(true));
+    }
+
+    game.update_until(|game| {
+        *game.server.resources.get::<MatchState>().unwrap() == MatchState::Running
+    });
+    game.update_until(|game| !network_entities(&game.server).is_empty());
+
+    // enemy units out of sight are never sent
+    game.update_until(|game| {
+        let vision = game.server.resources.get::<Vision>().unwrap();
+        let spawned = network_entities(&game.server);
+
+        game.clients.iter().all(|(handle, client)| {
+            let known: Vec<NetworkEntity> = spawned
+                .iter()
+                .filter(|network_entity| vision.knows(*handle, **network_entity))
+                .cloned()
+                .collect();
+
+            !known.is_empty() && network_entities(client) == known
+        })
+    });
+}